@@ -16,7 +16,11 @@ const GATEWAY_URL: &str = "/gateway/index";
 const GATEWAY_DATA_KEY: &str = "url";
 
 const MESSAGE_CREATE_URL: &str = "/message/create";
+#[allow(dead_code)]
 const MESSAGE_TYPE_KMAEKDOWN: usize = 9;
+pub const MESSAGE_TYPE_FILE: usize = 4;
+
+const ASSET_CREATE_URL: &str = "/asset/create";
 
 const USER_ME_URL: &str = "/user/me";
 
@@ -105,6 +109,11 @@ pub async fn message_create(
         .json(&req)
         .send()
         .await?;
+    // 限流和服务端出错过一会儿再试就好, 不算 `ApiError`
+    let status = res.status();
+    if status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+        bail!("kook api responded {}", status)
+    }
 
     let kres = res.json::<KookResponse>().await?;
     is_http_ok(&kres)?;
@@ -112,6 +121,40 @@ pub async fn message_create(
     Ok(())
 }
 
+/// 上传文件到 Kook 的服务器, 发文件消息之前要先上传
+///
+/// Arguments:
+///
+/// * `name`: 文件名
+/// * `bytes`: 文件内容
+///
+/// Returns:
+///
+/// 文件的地址, 作为文件消息的内容发出去
+pub async fn asset_create(name: String, bytes: Vec<u8>) -> Result<String, anyhow::Error> {
+    req_slow_down().await;
+
+    #[derive(Debug, Deserialize)]
+    struct Asset {
+        url: String,
+    }
+
+    let part = reqwest::multipart::Part::bytes(bytes).file_name(name);
+    let form = reqwest::multipart::Form::new().part("file", part);
+
+    let res = CLIENT
+        .get()
+        .expect("CLIENT not initialized")
+        .post(prefix_url(ASSET_CREATE_URL))
+        .multipart(form)
+        .send()
+        .await?;
+
+    let kres = res.json::<KookResponse<Asset>>().await?;
+    is_http_ok(&kres)?;
+    Ok(kres.data.url)
+}
+
 pub async fn user_me() -> Result<UserMe, anyhow::Error> {
     req_slow_down().await;
 
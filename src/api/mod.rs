@@ -1,10 +1,12 @@
-use anyhow::bail;
 use serde::Deserialize;
 use serde_json::Value;
+use thiserror::Error;
 pub mod http;
 
 const KOOK_BASE_API: &str = "https://www.kookapp.cn/api/v3";
 const KOOK_OK_CODE: usize = 0;
+// 参数错误 (频道不存在, 消息内容被拒绝或者太长), 没有权限, 资源不存在
+const KOOK_PERMANENT_CODES: [usize; 3] = [40000, 40300, 40400];
 
 #[derive(Debug, Deserialize)]
 struct KookResponse<T = Value> {
@@ -17,13 +19,33 @@ fn prefix_url(url: &str) -> String {
     format!("{}{}", KOOK_BASE_API, url)
 }
 
+#[allow(dead_code)]
 fn not_compress(url: &str) -> String {
     format!("{}?compress=0", url)
 }
 
+/// Kook 接口返回的错误码不是 0
+#[derive(Debug, Error)]
+#[error("{message}")]
+pub struct ApiError {
+    pub code: usize,
+    pub message: String,
+}
+
+impl ApiError {
+    /// 原样重试也不会成功的错误 (没有权限, 频道被删除, 内容被拒绝或者太长), 其他错误过一会儿再试
+    pub fn is_permanent(&self) -> bool {
+        KOOK_PERMANENT_CODES.contains(&self.code)
+    }
+}
+
 fn is_http_ok<T>(kres: &KookResponse<T>) -> Result<(), anyhow::Error> {
     if kres.code != KOOK_OK_CODE {
-        bail!(kres.message.to_owned())
+        return Err(ApiError {
+            code: kres.code,
+            message: kres.message.to_owned(),
+        }
+        .into());
     }
     Ok(())
 }
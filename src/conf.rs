@@ -1,30 +1,196 @@
-use std::path::Path;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use anyhow::{bail, Ok};
 use tini::Ini;
 
+use crate::fetch::Network;
+
 const MAIN_SECTION: &str = "Main";
 const MAIN_NAME_FIELD: &str = "Name";
 const MAIN_TOKEN_FIELD: &str = "Token";
+const MAIN_ADMINS_FIELD: &str = "Admins";
+
+const FEED_SECTION: &str = "Feed";
+const FEED_MAX_POST_AGE_FIELD: &str = "MaxPostAge";
+const FEED_SEEN_RETENTION_FIELD: &str = "SeenRetention";
+const FEED_MIN_INTERVAL_FIELD: &str = "MinInterval";
+const FEED_MAX_INTERVAL_FIELD: &str = "MaxInterval";
+const FEED_FAILURE_NOTIFY_FIELD: &str = "FailureNotify";
+const FEED_SUSPEND_AFTER_FIELD: &str = "SuspendAfter";
+
+const FETCH_SECTION: &str = "Fetch";
+const FETCH_PROXY_FIELD: &str = "Proxy";
+const FETCH_CA_FILE_FIELD: &str = "CaFile";
+const FETCH_ALLOWED_NETWORKS_FIELD: &str = "AllowedNetworks";
+const FETCH_MAX_CONCURRENT_FIELD: &str = "MaxConcurrent";
+const FETCH_PER_HOST_CONCURRENT_FIELD: &str = "PerHostConcurrent";
+const FETCH_PER_HOST_DELAY_FIELD: &str = "PerHostDelay";
+
+const RSSHUB_SECTION: &str = "RSSHub";
+const RSSHUB_BASE_URL_FIELD: &str = "BaseUrl";
+const RSSHUB_ACCESS_KEY_FIELD: &str = "AccessKey";
+
+const SOURCES_SECTION: &str = "Sources";
+const SOURCES_FILE_ROOTS_FIELD: &str = "FileRoots";
+// 这一节里每一项都是一个命令: 名称 = 命令行
+const COMMANDS_SECTION: &str = "Commands";
+
+const DIGEST_SECTION: &str = "Digest";
+const DIGEST_UTC_OFFSET_FIELD: &str = "UtcOffset";
+
+const WEBSUB_SECTION: &str = "WebSub";
+const WEBSUB_CALLBACK_FIELD: &str = "Callback";
+const WEBSUB_LISTEN_FIELD: &str = "Listen";
+
+const DEFAULT_WEBSUB_LISTEN: &str = "0.0.0.0:8787";
+
+const DEFAULT_RSSHUB_BASE_URL: &str = "https://rsshub.app";
+
+// 同时最多拉取几个订阅源
+const DEFAULT_MAX_CONCURRENT: usize = 8;
+// 同一个主机同时最多几个请求
+const DEFAULT_PER_HOST_CONCURRENT: usize = 2;
+// 同一个主机两次请求之间至少隔多久, 单位毫秒
+const DEFAULT_PER_HOST_DELAY_MS: u64 = 1000;
+
+// 同一个订阅源两次拉取的最短和最长间隔, 单位分钟
+const DEFAULT_MIN_INTERVAL_MINUTES: u64 = 3;
+const DEFAULT_MAX_INTERVAL_MINUTES: u64 = 60 * 6;
+
+// 连续失败几次之后通知订阅的频道
+const DEFAULT_FAILURE_NOTIFY: u32 = 5;
+// 多久没有拉取成功就暂停拉取, 单位小时
+const DEFAULT_SUSPEND_AFTER_HOURS: u64 = 24 * 7;
+
+// 默认只推送一周以内的文章, 单位小时
+const DEFAULT_MAX_POST_AGE_HOURS: u64 = 24 * 7;
+// 文章离开订阅源之后, 推送记录默认保留 90 天, 单位天
+const DEFAULT_SEEN_RETENTION_DAYS: u64 = 90;
 
 pub const BOT_STORE_FILE_PATH: &str = "__bot.json";
 #[derive(Debug, Default)]
 pub struct Config {
+    #[allow(dead_code)]
     pub name: String,
     pub token: String,
+    // 管理员的用户 ID, 可以处理推送失败的消息
+    pub admins: Vec<String>,
+    // 超过多少小时的文章不再推送, 0 表示不限制
+    pub max_post_age: Option<u64>,
+    // 文章离开订阅源多少天之后删除推送记录
+    pub seen_retention: Option<u64>,
+    // 拉取间隔的上下限, 分钟
+    pub min_interval: Option<u64>,
+    pub max_interval: Option<u64>,
+    // 连续失败几次之后通知频道, 0 表示不通知
+    pub failure_notify: Option<u32>,
+    // 多少小时没有拉取成功就暂停拉取, 0 表示不暂停
+    pub suspend_after: Option<u64>,
+    // 摘要默认的 UTC 偏移, 秒
+    pub digest_utc_offset: Option<i32>,
+    // WebSub 回调的公网地址, 不设置就不启用 WebSub
+    pub websub_callback: Option<String>,
+    pub websub_listen: Option<SocketAddr>,
+    // 拉取订阅源的全局代理: http://, socks5://
+    pub fetch_proxy: Option<String>,
+    // 额外信任的 CA 证书, PEM 格式
+    pub fetch_ca_file: Option<String>,
+    // 允许订阅的内网网段, 默认内网地址都不能订阅
+    pub fetch_allowed_networks: Vec<Network>,
+    pub fetch_max_concurrent: Option<usize>,
+    pub fetch_per_host_concurrent: Option<usize>,
+    // 毫秒
+    pub fetch_per_host_delay: Option<u64>,
+    // `rsshub:` 路由展开用的 RSSHub 实例, 默认是公共实例
+    pub rsshub_base_url: Option<String>,
+    pub rsshub_access_key: Option<String>,
+    // 允许订阅的本地文件目录, 不设置就不能订阅本地文件
+    pub source_file_roots: Vec<PathBuf>,
+    // 允许订阅的命令, 名称 => 命令行
+    pub source_commands: HashMap<String, Vec<String>>,
 }
 
 impl Config {
     pub fn bot_conf(&self) -> BotConfig {
         BotConfig::new(self.token.to_owned(), BOT_STORE_FILE_PATH.to_owned())
     }
+
+    pub fn fetch_conf(&self) -> FetchConfig {
+        FetchConfig {
+            max_feed_size: None,
+            proxy: self.fetch_proxy.to_owned(),
+            ca_file: self.fetch_ca_file.to_owned(),
+            allowed_networks: self.fetch_allowed_networks.to_owned(),
+            limits: FetchLimits {
+                max_concurrent: self.fetch_max_concurrent.unwrap_or(DEFAULT_MAX_CONCURRENT),
+                per_host_concurrent: self
+                    .fetch_per_host_concurrent
+                    .unwrap_or(DEFAULT_PER_HOST_CONCURRENT),
+                per_host_delay: Duration::from_millis(
+                    self.fetch_per_host_delay
+                        .unwrap_or(DEFAULT_PER_HOST_DELAY_MS),
+                ),
+            },
+            rsshub: RssHubConfig {
+                base_url: self
+                    .rsshub_base_url
+                    .to_owned()
+                    .unwrap_or_else(|| DEFAULT_RSSHUB_BASE_URL.to_owned()),
+                access_key: self.rsshub_access_key.to_owned(),
+            },
+            sources: SourceConfig {
+                file_roots: self.source_file_roots.to_owned(),
+                commands: self.source_commands.to_owned(),
+            },
+        }
+    }
+
+    pub fn runtime_conf(&self) -> RuntimeConfig {
+        let max_post_age = match self.max_post_age.unwrap_or(DEFAULT_MAX_POST_AGE_HOURS) {
+            0 => None,
+            hours => Some(hours * 60 * 60),
+        };
+
+        let seen_retention =
+            self.seen_retention.unwrap_or(DEFAULT_SEEN_RETENTION_DAYS) * 60 * 60 * 24;
+
+        let websub = self.websub_callback.as_ref().map(|callback| WebSubConfig {
+            callback: callback.trim_end_matches('/').to_owned(),
+            listen: self
+                .websub_listen
+                .unwrap_or_else(|| DEFAULT_WEBSUB_LISTEN.parse().unwrap()),
+        });
+
+        let min_interval = self.min_interval.unwrap_or(DEFAULT_MIN_INTERVAL_MINUTES) * 60;
+        let max_interval = self.max_interval.unwrap_or(DEFAULT_MAX_INTERVAL_MINUTES) * 60;
+
+        RuntimeConfig {
+            admins: self.admins.to_owned(),
+            digest_utc_offset: self.digest_utc_offset.unwrap_or_default(),
+            max_post_age,
+            seen_retention,
+            schedule: ScheduleConfig {
+                min_interval,
+                max_interval: max_interval.max(min_interval),
+                failure_notify: self.failure_notify.unwrap_or(DEFAULT_FAILURE_NOTIFY),
+                suspend_after: match self.suspend_after.unwrap_or(DEFAULT_SUSPEND_AFTER_HOURS) {
+                    0 => None,
+                    hours => Some(hours * 60 * 60),
+                },
+            },
+            websub,
+        }
+    }
 }
 
 impl TryFrom<&Path> for Config {
     type Error = anyhow::Error;
 
     fn try_from(path: &Path) -> Result<Self, Self::Error> {
-        if path.exists() == false {
+        if !path.exists() {
             bail!("config file does not exist")
         }
 
@@ -40,7 +206,131 @@ impl TryFrom<&Path> for Config {
             None => bail!("error config file."),
         };
 
-        Ok(Config { name, token })
+        // 逗号分隔的用户 ID
+        let admins = match ini_conf.get::<String>(MAIN_SECTION, MAIN_ADMINS_FIELD) {
+            Some(admins) => admins
+                .split(',')
+                .map(|a| a.trim())
+                .filter(|a| !a.is_empty())
+                .map(|a| a.to_owned())
+                .collect(),
+            None => vec![],
+        };
+
+        let max_post_age = ini_conf.get(FEED_SECTION, FEED_MAX_POST_AGE_FIELD);
+        let seen_retention = ini_conf.get(FEED_SECTION, FEED_SEEN_RETENTION_FIELD);
+        let min_interval = ini_conf.get(FEED_SECTION, FEED_MIN_INTERVAL_FIELD);
+        let max_interval = ini_conf.get(FEED_SECTION, FEED_MAX_INTERVAL_FIELD);
+        if min_interval == Some(0) {
+            bail!("MinInterval must be at least 1 minute")
+        }
+        if let (Some(min), Some(max)) = (min_interval, max_interval) {
+            if max < min {
+                bail!("MaxInterval must not be less than MinInterval")
+            }
+        }
+        let failure_notify = ini_conf.get(FEED_SECTION, FEED_FAILURE_NOTIFY_FIELD);
+        let suspend_after = ini_conf.get(FEED_SECTION, FEED_SUSPEND_AFTER_FIELD);
+
+        let fetch_proxy = ini_conf.get::<String>(FETCH_SECTION, FETCH_PROXY_FIELD);
+        if let Some(ref proxy) = fetch_proxy {
+            reqwest::Proxy::all(proxy)?;
+        }
+        let fetch_ca_file = ini_conf.get::<String>(FETCH_SECTION, FETCH_CA_FILE_FIELD);
+        if let Some(ref path) = fetch_ca_file {
+            crate::fetch::load_ca_file(path)?;
+        }
+        // 逗号分隔: 10.1.0.0/16, 192.168.1.10
+        let fetch_allowed_networks =
+            match ini_conf.get::<String>(FETCH_SECTION, FETCH_ALLOWED_NETWORKS_FIELD) {
+                Some(networks) => networks
+                    .split(',')
+                    .filter(|n| !n.trim().is_empty())
+                    .map(|n| n.parse())
+                    .collect::<Result<Vec<Network>, _>>()?,
+                None => vec![],
+            };
+
+        let fetch_max_concurrent = ini_conf.get(FETCH_SECTION, FETCH_MAX_CONCURRENT_FIELD);
+        let fetch_per_host_concurrent =
+            ini_conf.get(FETCH_SECTION, FETCH_PER_HOST_CONCURRENT_FIELD);
+        let fetch_per_host_delay = ini_conf.get(FETCH_SECTION, FETCH_PER_HOST_DELAY_FIELD);
+        if fetch_max_concurrent == Some(0) || fetch_per_host_concurrent == Some(0) {
+            bail!("MaxConcurrent and PerHostConcurrent must be at least 1")
+        }
+
+        let rsshub_base_url = ini_conf.get::<String>(RSSHUB_SECTION, RSSHUB_BASE_URL_FIELD);
+        if let Some(ref base_url) = rsshub_base_url {
+            let url = url::Url::parse(base_url)?;
+            if !matches!(url.scheme(), "http" | "https") {
+                bail!("RSSHub BaseUrl must be http or https: {}", base_url)
+            }
+        }
+        let rsshub_access_key = ini_conf.get(RSSHUB_SECTION, RSSHUB_ACCESS_KEY_FIELD);
+
+        // 逗号分隔: /srv/feeds, /var/lib/ksbot/feeds
+        let source_file_roots =
+            match ini_conf.get::<String>(SOURCES_SECTION, SOURCES_FILE_ROOTS_FIELD) {
+                Some(roots) => roots
+                    .split(',')
+                    .map(|r| r.trim())
+                    .filter(|r| !r.is_empty())
+                    .map(PathBuf::from)
+                    .collect(),
+                None => vec![],
+            };
+        let mut source_commands = HashMap::new();
+        for (name, command) in ini_conf.section_iter(COMMANDS_SECTION) {
+            if !crate::fetch::is_command_name(name) {
+                bail!("invalid command name: {}", name)
+            }
+            let argv = command
+                .split_whitespace()
+                .map(|a| a.to_owned())
+                .collect::<Vec<String>>();
+            if argv.is_empty() {
+                bail!("command {} is empty", name)
+            }
+            source_commands.insert(name.to_owned(), argv);
+        }
+
+        // +08:00 这样固定的 UTC 偏移, 不支持时区名
+        let digest_utc_offset =
+            match ini_conf.get::<String>(DIGEST_SECTION, DIGEST_UTC_OFFSET_FIELD) {
+                Some(tz) => Some(crate::digest::parse_utc_offset(&tz)?),
+                None => None,
+            };
+
+        let websub_callback = ini_conf.get(WEBSUB_SECTION, WEBSUB_CALLBACK_FIELD);
+        let websub_listen = match ini_conf.get::<String>(WEBSUB_SECTION, WEBSUB_LISTEN_FIELD) {
+            Some(addr) => Some(addr.parse()?),
+            None => None,
+        };
+
+        Ok(Config {
+            name,
+            token,
+            admins,
+            max_post_age,
+            seen_retention,
+            min_interval,
+            max_interval,
+            failure_notify,
+            suspend_after,
+            digest_utc_offset,
+            websub_callback,
+            websub_listen,
+            fetch_proxy,
+            fetch_ca_file,
+            fetch_allowed_networks,
+            fetch_max_concurrent,
+            fetch_per_host_concurrent,
+            fetch_per_host_delay,
+            rsshub_base_url,
+            rsshub_access_key,
+            source_file_roots,
+            source_commands,
+        })
     }
 }
 
@@ -55,3 +345,110 @@ impl BotConfig {
         Self { token, store_path }
     }
 }
+
+/// 拉取订阅源的 HTTP 客户端配置
+#[derive(Debug, Clone, Default)]
+pub struct FetchConfig {
+    // 订阅源的大小限制, 字节
+    pub max_feed_size: Option<u64>,
+    pub proxy: Option<String>,
+    // PEM 格式的 CA 证书文件
+    pub ca_file: Option<String>,
+    // 允许访问的内网网段
+    pub allowed_networks: Vec<Network>,
+    pub limits: FetchLimits,
+    pub rsshub: RssHubConfig,
+    pub sources: SourceConfig,
+}
+
+/// 拉取的并发和频率限制
+#[derive(Debug, Clone)]
+pub struct FetchLimits {
+    // 全局同时最多几个请求
+    pub max_concurrent: usize,
+    // 同一个主机同时最多几个请求
+    pub per_host_concurrent: usize,
+    // 同一个主机两次请求开始之间的最小间隔
+    pub per_host_delay: Duration,
+}
+
+impl Default for FetchLimits {
+    fn default() -> Self {
+        Self {
+            max_concurrent: DEFAULT_MAX_CONCURRENT,
+            per_host_concurrent: DEFAULT_PER_HOST_CONCURRENT,
+            per_host_delay: Duration::from_millis(DEFAULT_PER_HOST_DELAY_MS),
+        }
+    }
+}
+
+/// `rsshub:` 路由展开用的 RSSHub 实例
+#[derive(Debug, Clone)]
+pub struct RssHubConfig {
+    pub base_url: String,
+    // 实例开启了访问控制的话要带上密钥
+    pub access_key: Option<String>,
+}
+
+impl Default for RssHubConfig {
+    fn default() -> Self {
+        Self {
+            base_url: DEFAULT_RSSHUB_BASE_URL.to_owned(),
+            access_key: None,
+        }
+    }
+}
+
+/// 本地订阅源: 文件和命令, 只能由配置文件开放, 聊天里没法添加
+#[derive(Debug, Clone, Default)]
+pub struct SourceConfig {
+    // `file://` 只能读这些目录里的文件
+    pub file_roots: Vec<PathBuf>,
+    // `exec:名称` 运行的命令, 不经过 shell
+    pub commands: HashMap<String, Vec<String>>,
+}
+
+/// 拉取和推送订阅源时用到的配置
+#[derive(Debug, Clone, Default)]
+pub struct RuntimeConfig {
+    // 管理员的用户 ID
+    pub admins: Vec<String>,
+    // `digest daily` 没写 UTC 偏移的时候用的偏移, 秒
+    pub digest_utc_offset: i32,
+    // 文章的最大年龄, 秒. None 表示不限制
+    pub max_post_age: Option<u64>,
+    // 推送记录的保留时间, 秒
+    pub seen_retention: u64,
+    pub schedule: ScheduleConfig,
+    pub websub: Option<WebSubConfig>,
+}
+
+/// 拉取间隔的上下限, 秒. 每个订阅源的间隔在这中间按发文频率调整
+#[derive(Debug, Clone)]
+pub struct ScheduleConfig {
+    pub min_interval: u64,
+    pub max_interval: u64,
+    // 连续失败几次之后通知订阅的频道, 0 表示不通知
+    pub failure_notify: u32,
+    // 多久没有拉取成功就暂停拉取, 秒. None 表示不暂停
+    pub suspend_after: Option<u64>,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self {
+            min_interval: DEFAULT_MIN_INTERVAL_MINUTES * 60,
+            max_interval: DEFAULT_MAX_INTERVAL_MINUTES * 60,
+            failure_notify: DEFAULT_FAILURE_NOTIFY,
+            suspend_after: Some(DEFAULT_SUSPEND_AFTER_HOURS * 60 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WebSubConfig {
+    // 回调地址的前缀, 完整的地址是 `{callback}/websub/{feed_hash}/{token}`
+    pub callback: String,
+    // 回调服务监听的地址
+    pub listen: SocketAddr,
+}
@@ -5,7 +5,10 @@ use std::{
 
 use serde::{Deserialize, Serialize};
 
-use crate::{fetch::feed::Feed, utils};
+use crate::{
+    fetch::{feed::Feed, CacheValidator, FetchOptions, PageWatch},
+    schedule, utils,
+};
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct SubscribeFeed {
@@ -15,48 +18,115 @@ pub struct SubscribeFeed {
     pub link: String,
     // 标题
     pub title: String,
-    // 最后一次订阅时间
+    // 最后一次拉取成功的时间
     pub down_time: u64,
     pub ttl: Option<u32>,
-    pub posts_hash: Vec<String>,
     pub channel_ids: Vec<String>,
+    // 条件请求用, 上一次响应的 ETag 和 Last-Modified
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    // 上一次拉取失败的原因, 成功之后清空
+    pub last_error: Option<String>,
+    // 连续拉取失败的次数, 成功之后清零
+    #[serde(default)]
+    pub failure_count: u32,
+    // 太久没有拉取成功被暂停的时间, 暂停之后不再拉取, 需要用 `resume` 命令恢复
+    pub suspended_at: Option<u64>,
+    // 订阅源声明的自身地址: <atom:link rel="self">
+    pub self_link: Option<String>,
+    // 订阅源声明的 WebSub hub
+    pub hub: Option<String>,
+    // WebSub 订阅状态, 订阅有效期间不再轮询
+    pub websub: Option<WebSubLease>,
+    // 拉取时的请求选项: 请求头, cookie, 认证, 代理等
+    #[serde(default)]
+    pub fetch_options: FetchOptions,
+    // 下一次拉取的时间, 0 表示马上拉取
+    #[serde(default)]
+    pub next_fetch: u64,
+    // 观察到的发文间隔 (指数加权平均), 秒
+    pub post_interval: Option<u64>,
+    // 最后一次发现新文章的时间
+    pub last_post_time: Option<u64>,
+    // 订阅源声明的不用拉取的小时 (UTC, 0-23) 和星期 (0 是星期一)
+    #[serde(default)]
+    pub skip_hours: Vec<u32>,
+    #[serde(default)]
+    pub skip_days: Vec<u32>,
+    // `sy:updatePeriod` 和 `sy:updateFrequency` 算出来的更新间隔, 秒
+    pub update_period: Option<u64>,
+    // 旧版本留下的订阅源还没有推送记录, 下一次拉取只记录文章不推送, 免得现有的文章全推一遍.
+    // 新订阅的订阅源在订阅的时候就记录过了
+    #[serde(default = "legacy_record_only")]
+    pub record_only: bool,
+}
+
+fn legacy_record_only() -> bool {
+    true
 }
 
-const POSTS_HASH_MAX: usize = 16; // 最长存放15个
+/// 向 hub 发起的 WebSub 订阅
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebSubLease {
+    pub hub: String,
+    pub topic: String,
+    // 验证推送内容签名的密钥
+    pub secret: String,
+    // 回调地址里的随机令牌, 别人猜不到回调地址就没法冒充 hub
+    #[serde(default)]
+    pub token: String,
+    // 发起订阅的时间
+    pub requested_at: u64,
+    // 订阅到期时间, hub 验证之前是 0
+    pub expires_at: u64,
+}
+
+impl WebSubLease {
+    // 订阅是否有效
+    pub fn is_active(&self, now: u64) -> bool {
+        self.expires_at > now
+    }
+}
 
 impl SubscribeFeed {
     pub fn from(url: &str, rss: &Feed) -> Self {
-        let posts_hash = rss
-            .posts
-            .iter()
-            .take(POSTS_HASH_MAX)
-            .map(|t| utils::hash(&t.link.as_ref().unwrap()))
-            .collect();
-
         let start = SystemTime::now();
         let since_the_epoch = start
             .duration_since(UNIX_EPOCH)
             .expect("Time went backwards");
 
+        // 刚订阅的时候按已有文章的发布时间估计发文间隔
+        let estimate = schedule::estimate_post_interval(rss);
         Self {
             subscribe_url: url.to_owned(),
             title: rss.title.to_owned(),
             link: rss.link.to_owned(),
             down_time: since_the_epoch.as_secs(),
             ttl: rss.ttl,
-            posts_hash,
             channel_ids: vec![],
+            etag: None,
+            last_modified: None,
+            last_error: None,
+            failure_count: 0,
+            suspended_at: None,
+            self_link: rss.atom_link.to_owned(),
+            hub: rss.hub.to_owned(),
+            websub: None,
+            fetch_options: FetchOptions::default(),
+            next_fetch: 0,
+            post_interval: estimate.map(|(interval, _)| interval),
+            last_post_time: estimate.map(|(_, newest)| newest),
+            skip_hours: schedule::skip_hours(rss),
+            skip_days: schedule::skip_days(rss),
+            update_period: schedule::syndication_period(
+                rss.update_period.as_deref(),
+                rss.update_frequency,
+            ),
+            record_only: false,
         }
     }
 
-    pub fn from_old(old: &Self, rss: &Feed) -> Self {
-        let posts_hash = rss
-            .posts
-            .iter()
-            .take(POSTS_HASH_MAX)
-            .map(|t| utils::hash(t.link.as_ref().unwrap()))
-            .collect();
-
+    pub fn from_old(old: &Self, rss: &Feed, validator: CacheValidator) -> Self {
         let start = SystemTime::now();
         let since_the_epoch = start
             .duration_since(UNIX_EPOCH)
@@ -68,26 +138,144 @@ impl SubscribeFeed {
             link: rss.link.to_owned(),
             down_time: since_the_epoch.as_secs(),
             ttl: rss.ttl,
-            posts_hash,
             channel_ids: old.channel_ids.to_owned(),
+            etag: validator.etag,
+            last_modified: validator.last_modified,
+            last_error: None,
+            failure_count: 0,
+            suspended_at: None,
+            self_link: rss.atom_link.to_owned(),
+            hub: rss.hub.to_owned(),
+            websub: old.websub.to_owned(),
+            fetch_options: old.fetch_options.to_owned(),
+            next_fetch: old.next_fetch,
+            post_interval: old.post_interval,
+            last_post_time: old.last_post_time,
+            skip_hours: schedule::skip_hours(rss),
+            skip_days: schedule::skip_days(rss),
+            update_period: schedule::syndication_period(
+                rss.update_period.as_deref(),
+                rss.update_frequency,
+            ),
+            record_only: false,
         }
     }
 
-    // 对比 post_hash 不同的文章哈希
-    // 返回下标
-    pub fn diff_post_index(&self, feed: &SubscribeFeed) -> Vec<usize> {
-        let ph_1 = &self.posts_hash;
-        let ph_2 = &feed.posts_hash;
-        let diff = ph_1
-            .iter()
-            .enumerate()
-            .filter(|t| !ph_2.contains(t.1))
-            .map(|t| t.0)
-            .collect();
-        diff
+    pub fn cache_validator(&self) -> CacheValidator {
+        CacheValidator {
+            etag: self.etag.to_owned(),
+            last_modified: self.last_modified.to_owned(),
+        }
     }
 }
 
+/// 订阅源里每篇文章的哈希, 用来判断文章是否推送过.
+/// 下标和订阅源里的文章一一对应, 没法识别的文章是 None.
+pub fn post_hashes(rss: &Feed) -> Vec<Option<String>> {
+    rss.posts
+        .iter()
+        .map(|post| post.identity().map(utils::hash))
+        .collect()
+}
+
+/// 订阅源的规范地址, 和 `subscribe_url` 不一样的话订阅源就要搬到这个地址.
+///
+/// 永久重定向的新地址优先, 其次是订阅源声明的 `self` 地址,
+/// 但 `self` 地址只能是 `subscribe_url` 的另一种写法 (见 `utils::is_url_variant`), 免得被乱写的 `self` 带偏.
+///
+/// Arguments:
+///
+/// * `subscribe_url`: 现在的订阅地址
+/// * `moved_to`: 永久重定向到的新地址, 被监控的网页是带着选择器的 `watch:` 地址
+/// * `self_link`: 订阅源声明的 `<atom:link rel="self">`
+pub fn canonical_url(
+    subscribe_url: &str,
+    moved_to: Option<&str>,
+    self_link: Option<&str>,
+) -> Option<String> {
+    let url = match moved_to {
+        Some(u) => match PageWatch::from_url(u) {
+            Some(watch) => watch.to_url(),
+            None => utils::parse_http_url(u)?,
+        },
+        None => {
+            let url = utils::parse_http_url(self_link?)?;
+            if !utils::is_url_variant(subscribe_url, &url) {
+                return None;
+            }
+            url
+        }
+    };
+
+    if url == subscribe_url {
+        None
+    } else {
+        Some(url)
+    }
+}
+
+/// 推送队列里的一条消息, 推送成功之后才会从数据库里删掉
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutboxMessage {
+    pub channel_id: String,
+    // 消息来自哪个订阅源
+    pub subscribe_url: String,
+    // 渲染好的 KMarkdown
+    pub content: String,
+    pub created_at: u64,
+    // 推送失败的次数
+    #[serde(default)]
+    pub attempts: u32,
+    // 失败之后下一次重试的时间
+    #[serde(default)]
+    pub next_attempt: u64,
+    pub last_error: Option<String>,
+}
+
+impl OutboxMessage {
+    pub fn new(channel_id: &str, subscribe_url: &str, content: String, now: u64) -> Self {
+        Self {
+            channel_id: channel_id.to_owned(),
+            subscribe_url: subscribe_url.to_owned(),
+            content,
+            created_at: now,
+            ..Default::default()
+        }
+    }
+}
+
+/// 摘要模式下攒着等发送的一篇文章
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DigestEntry {
+    pub channel_id: String,
+    pub subscribe_url: String,
+    // 订阅源的标题, 摘要里按订阅源分组显示
+    pub feed_title: String,
+    pub title: Option<String>,
+    pub link: String,
+    pub created_at: u64,
+}
+
+/// 摘要多久发一次
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DigestEvery {
+    // 每隔几个小时, 按当地时间的整点对齐
+    Hours(u32),
+    // 每天当地时间的几点几分
+    Daily { hour: u32, minute: u32 },
+}
+
+/// 频道的摘要推送设置, 新文章先攒起来, 到点合成一条消息发出去
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DigestSchedule {
+    pub every: DigestEvery,
+    // 相对 UTC 的偏移, 秒
+    pub utc_offset: i32,
+    // 下一次发送摘要的时间
+    pub next_send: u64,
+}
+
 impl TryFrom<&SubscribeFeed> for String {
     type Error = serde_json::Error;
 
@@ -103,6 +291,9 @@ pub struct ChannelSubFeeds {
     pub feed_hash: Vec<String>,
     // K = feed_hash V = regex expression
     pub feed_regex: HashMap<String, String>,
+    // 设置了的话新文章攒成摘要定时发送, 没有就一篇一篇推送
+    #[serde(default)]
+    pub digest: Option<DigestSchedule>,
 }
 
 impl ChannelSubFeeds {
@@ -111,6 +302,7 @@ impl ChannelSubFeeds {
             id,
             feed_hash: vec![],
             feed_regex: HashMap::new(),
+            digest: None,
         }
     }
 }
@@ -123,3 +315,65 @@ impl TryFrom<&ChannelSubFeeds> for String {
         Ok(r)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::fetch::FromXmlWithBufRead;
+
+    #[test]
+    fn test_post_without_link() {
+        let s: &[u8] = include_bytes!("../test/data/no_link.xml");
+        let rss = Feed::from_xml_with_buf(Cursor::new(s)).unwrap();
+        let hashes = post_hashes(&rss);
+        assert_eq!(hashes.len(), 2);
+        assert_eq!(hashes[0], Some(utils::hash("no_link.item[0].guid")));
+        assert_eq!(
+            hashes[1],
+            Some(utils::hash("http://example.com/no_link/item1"))
+        );
+    }
+
+    #[test]
+    fn test_canonical_url() {
+        let url = "http://example.com/feed/";
+        assert_eq!(
+            canonical_url(
+                url,
+                Some("https://feeds.example.org/x"),
+                Some("https://example.com/feed")
+            ),
+            Some("https://feeds.example.org/x".to_owned())
+        );
+        assert_eq!(
+            canonical_url(url, None, Some("https://example.com/feed")),
+            Some("https://example.com/feed".to_owned())
+        );
+        // 乱写的 self 地址不管
+        assert_eq!(
+            canonical_url(url, None, Some("https://example.org/feed")),
+            None
+        );
+        assert_eq!(canonical_url(url, None, Some("/feed")), None);
+        assert_eq!(canonical_url(url, None, Some(url)), None);
+        assert_eq!(canonical_url(url, None, None), None);
+
+        // 被监控的网页搬家了, 新地址带着选择器
+        let watch = "watch:https://example.com/news#css=ul+li";
+        assert_eq!(
+            canonical_url(
+                watch,
+                Some("watch:https://example.com/news/#css=ul+li"),
+                None
+            ),
+            Some("watch:https://example.com/news/#css=ul+li".to_owned())
+        );
+        assert_eq!(canonical_url(watch, Some(watch), None), None);
+        assert_eq!(
+            canonical_url(watch, Some("watch:https://example.com/news"), None),
+            None
+        );
+    }
+}
@@ -1,10 +1,21 @@
-use sled::transaction::TransactionError;
+use std::collections::HashSet;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree,
+};
 use sled::IVec;
 use thiserror::Error;
-use tracing::{debug, error, info, warn};
-
-use crate::data::{ChannelSubFeeds, SubscribeFeed};
-use crate::utils;
+use tracing::{error, info};
+
+use crate::conf::ScheduleConfig;
+use crate::data::{
+    ChannelSubFeeds, DigestEntry, DigestSchedule, OutboxMessage, SubscribeFeed, WebSubLease,
+};
+use crate::fetch::FetchOptions;
+use crate::{schedule, utils};
 const DEFAULT_DATABASE_PATH: &str = "__bot.db";
 
 #[derive(Debug, Error)]
@@ -48,6 +59,13 @@ impl Database {
         Database { inner }
     }
 
+    // 测试用的临时数据库, 不会写到磁盘上
+    #[cfg(test)]
+    pub fn temporary() -> Self {
+        let inner = sled::Config::default().temporary(true).open().unwrap();
+        Database { inner }
+    }
+
     // 频道订阅
     pub fn channel_subscribed(
         &self,
@@ -80,29 +98,54 @@ impl Database {
         Ok(())
     }
 
-    // 频道取消订阅
+    // 频道取消订阅, 这个订阅源在频道的推送队列和摘要里还没发出去的文章也一起删掉
     pub fn channel_unsubscribed(
         &self,
         channel_id: &str,
         subscribe_url: &str,
     ) -> Result<(), StoreError> {
-        self.feed_operaiton(&feed_key(subscribe_url), |feed| {
+        // 事务里不能遍历, 先把要删的找出来
+        let mut pending = vec![];
+        for kv in self.inner.scan_prefix(outbox_key_prefix(channel_id)) {
+            let (k, v) = kv?;
+            let msg = serde_json::from_str::<OutboxMessage>(&utils::ivec_to_str(v))?;
+            if msg.subscribe_url == subscribe_url {
+                pending.push(k);
+            }
+        }
+        for (k, entry) in self.digest_entries(channel_id)? {
+            if entry.subscribe_url == subscribe_url {
+                pending.push(IVec::from(k.as_str()));
+            }
+        }
+
+        let (feed_key, chan_key) = (feed_key(subscribe_url), channel_key(channel_id));
+        let curr_feed_hash = utils::hash(subscribe_url);
+        self.inner.transaction(|tx| {
+            let mut feed = tx_get::<SubscribeFeed>(tx, &feed_key)?
+                .ok_or_else(|| abort(StoreError::NotFoundFeed(feed_key.to_owned())))?;
             for (idx, chan) in feed.channel_ids.iter().enumerate() {
                 if chan == channel_id {
                     feed.channel_ids.remove(idx);
                     break;
                 }
             }
-        })?;
+            tx_put(tx, &feed_key, &feed)?;
 
-        let curr_feed_hash = utils::hash(subscribe_url);
-        self.chan_operaiton(&channel_key(channel_id), |chan| {
+            let mut chan = tx_get::<ChannelSubFeeds>(tx, &chan_key)?
+                .ok_or_else(|| abort(StoreError::NotFoundChannel(chan_key.to_owned())))?;
             for (idx, f) in chan.feed_hash.iter().enumerate() {
                 if curr_feed_hash == *f {
                     chan.feed_hash.remove(idx);
                     break;
                 }
             }
+            tx_put(tx, &chan_key, &chan)?;
+
+            for k in &pending {
+                tx.remove(k.to_owned())?;
+            }
+            Ok(())
         })?;
 
         Ok(())
@@ -149,6 +192,90 @@ impl Database {
         Ok(result)
     }
 
+    // 订阅源没有变化 (304) 时只更新拉取时间, 304 也算拉取成功
+    pub fn update_feed_down_time(
+        &self,
+        subscribe_url: &str,
+        down_time: u64,
+    ) -> Result<(), StoreError> {
+        self.feed_operaiton(&feed_key(subscribe_url), |feed| {
+            feed.down_time = down_time;
+            feed.last_error = None;
+            feed.failure_count = 0;
+        })
+    }
+
+    // 拉取之后按发现的新文章数重新安排下一次拉取
+    pub fn update_feed_schedule(
+        &self,
+        subscribe_url: &str,
+        new_posts: usize,
+        conf: &ScheduleConfig,
+        now: u64,
+    ) -> Result<(), StoreError> {
+        self.feed_operaiton(&feed_key(subscribe_url), |feed| {
+            schedule::reschedule(feed, new_posts, conf, now);
+        })
+    }
+
+    // 服务端要求慢一点 (429 / 503 带了 Retry-After), 不算失败, 只推迟下一次拉取
+    pub fn update_feed_next_fetch(
+        &self,
+        subscribe_url: &str,
+        next_fetch: u64,
+    ) -> Result<(), StoreError> {
+        self.feed_operaiton(&feed_key(subscribe_url), |feed| {
+            feed.next_fetch = next_fetch;
+        })
+    }
+
+    // 更新 WebSub 订阅状态
+    pub fn update_feed_websub(
+        &self,
+        subscribe_url: &str,
+        websub: Option<WebSubLease>,
+    ) -> Result<(), StoreError> {
+        self.feed_operaiton(&feed_key(subscribe_url), |feed| {
+            feed.websub = websub;
+        })
+    }
+
+    // 更新订阅源的请求选项
+    pub fn update_feed_fetch_options(
+        &self,
+        subscribe_url: &str,
+        options: FetchOptions,
+    ) -> Result<(), StoreError> {
+        self.feed_operaiton(&feed_key(subscribe_url), |feed| {
+            feed.fetch_options = options;
+        })
+    }
+
+    // 拉取失败时记录原因并退避, 返回更新之后的订阅源
+    pub fn update_feed_failure(
+        &self,
+        subscribe_url: &str,
+        error: String,
+        conf: &ScheduleConfig,
+        now: u64,
+    ) -> Result<SubscribeFeed, StoreError> {
+        let mut updated = None;
+        self.feed_operaiton(&feed_key(subscribe_url), |feed| {
+            schedule::record_failure(feed, error, conf, now);
+            updated = Some(feed.to_owned());
+        })?;
+        Ok(updated.expect("feed operation not applied"))
+    }
+
+    // 恢复被暂停的订阅源, 马上重新拉取
+    pub fn resume_feed(&self, subscribe_url: &str) -> Result<(), StoreError> {
+        self.feed_operaiton(&feed_key(subscribe_url), |feed| {
+            feed.suspended_at = None;
+            feed.failure_count = 0;
+            feed.next_fetch = 0;
+        })
+    }
+
     // 创建或者更新channelFeeds通过channel_id
     pub fn update_or_create_channel(
         &self,
@@ -173,12 +300,500 @@ impl Database {
         let chan_list = self.feed_channel_list(subscribe_url)?;
         if chan_list.is_empty() {
             self.remove_feed(subscribe_url)?;
+            self.remove_seen_posts(subscribe_url)?;
             Ok(true)
         } else {
             Ok(false)
         }
     }
 
+    /// 订阅源搬家: 把 `from` 的订阅合并到 `to`, 然后删除 `from`.
+    ///
+    /// 频道的订阅列表和过滤正则跟着改到新地址, 推送记录也一起搬过去,
+    /// 推送队列, 死信和摘要里还没发出去的文章也改成新地址.
+    /// `to` 已经有人订阅的话就合并两边的频道, 保留 `to` 原来的设置.
+    ///
+    /// 整个搬家在一个事务里完成, 不会只搬了一半.
+    ///
+    /// Returns:
+    ///
+    /// 合并之后的订阅源, 以及旧地址的 WebSub 订阅 (搬家之后就不用了, 需要去 hub 退订)
+    pub fn migrate_feed(
+        &self,
+        from: &str,
+        to: &str,
+    ) -> Result<(SubscribeFeed, Option<WebSubLease>), StoreError> {
+        // 事务里不能遍历, 先把要搬的推送记录找出来
+        let seen_keys = self
+            .inner
+            .scan_prefix(seen_key_prefix(from))
+            .keys()
+            .collect::<Result<Vec<IVec>, sled::Error>>()?;
+        let mut messages = vec![];
+        for prefix in [OUTBOX_KEY_PREFIX, DEAD_KEY_PREFIX] {
+            for kv in self.inner.scan_prefix(prefix) {
+                let (k, v) = kv?;
+                let msg = serde_json::from_str::<OutboxMessage>(&utils::ivec_to_str(v))?;
+                if msg.subscribe_url == from {
+                    messages.push(utils::ivec_to_str(k));
+                }
+            }
+        }
+        let mut digests = vec![];
+        for kv in self.inner.scan_prefix(DIGEST_KEY_PREFIX) {
+            let (k, v) = kv?;
+            let entry = serde_json::from_str::<DigestEntry>(&utils::ivec_to_str(v))?;
+            if entry.subscribe_url == from {
+                digests.push(utils::ivec_to_str(k));
+            }
+        }
+        let to_prefix = seen_key_prefix(to);
+        let (from_key, to_key) = (feed_key(from), feed_key(to));
+        let (from_hash, to_hash) = (utils::hash(from), utils::hash(to));
+
+        let result = self.inner.transaction(|tx| {
+            let old = tx_get::<SubscribeFeed>(tx, &from_key)?
+                .ok_or_else(|| abort(StoreError::NotFoundFeed(from.to_owned())))?;
+
+            let feed = match tx_get::<SubscribeFeed>(tx, &to_key)? {
+                Some(mut feed) => {
+                    for channel_id in &old.channel_ids {
+                        if !feed.channel_ids.contains(channel_id) {
+                            feed.channel_ids.push(channel_id.to_owned());
+                        }
+                    }
+                    if feed.fetch_options.is_empty() {
+                        feed.fetch_options = old.fetch_options.to_owned();
+                    }
+                    feed
+                }
+                // WebSub 订阅的是旧地址, 换了地址要重新订阅
+                None => SubscribeFeed {
+                    subscribe_url: to.to_owned(),
+                    websub: None,
+                    ..old.clone()
+                },
+            };
+            tx_put(tx, &to_key, &feed)?;
+
+            for channel_id in &old.channel_ids {
+                let chan_key = channel_key(channel_id);
+                let mut chan = tx_get::<ChannelSubFeeds>(tx, &chan_key)?
+                    .ok_or_else(|| abort(StoreError::NotFoundChannel(chan_key.to_owned())))?;
+                // 保持订阅列表原来的顺序
+                if let Some(idx) = chan.feed_hash.iter().position(|h| *h == from_hash) {
+                    if chan.feed_hash.contains(&to_hash) {
+                        chan.feed_hash.remove(idx);
+                    } else {
+                        chan.feed_hash[idx] = to_hash.to_owned();
+                    }
+                }
+                if let Some(regex) = chan.feed_regex.remove(&from_hash) {
+                    chan.feed_regex.entry(to_hash.to_owned()).or_insert(regex);
+                }
+                tx_put(tx, &chan_key, &chan)?;
+            }
+
+            for k in &seen_keys {
+                if let Some(v) = tx.remove(k.to_owned())? {
+                    let post_hash = utils::ivec_to_str(k.to_owned());
+                    let post_hash = post_hash.rsplit("::").next().unwrap_or_default();
+                    tx.insert(&*format!("{}{}", to_prefix, post_hash), v)?;
+                }
+            }
+
+            for k in &messages {
+                if let Some(mut msg) = tx_get::<OutboxMessage>(tx, k)? {
+                    msg.subscribe_url = to.to_owned();
+                    tx_put(tx, k, &msg)?;
+                }
+            }
+            for k in &digests {
+                if let Some(mut entry) = tx_get::<DigestEntry>(tx, k)? {
+                    entry.subscribe_url = to.to_owned();
+                    tx_put(tx, k, &entry)?;
+                }
+            }
+
+            tx.remove(&*from_key)?;
+            Ok((feed, old.websub))
+        })?;
+        Ok(result)
+    }
+
+    /// 早期版本存的是用户原样发来的地址, 现在的地址都按 `utils::parse_http_url` 规范化过,
+    /// 哈希对不上的话 `unsub`, `reg`, `opt` 就找不到这些订阅源了.
+    /// 启动的时候把这些订阅源搬到规范化之后的地址上, 已经规范化过的不会动.
+    ///
+    /// Returns:
+    ///
+    /// 搬过家的订阅源数量
+    pub fn normalize_feed_urls(&self) -> Result<usize, StoreError> {
+        let mut count = 0;
+        for feed in self.feed_list()? {
+            let from = feed.subscribe_url;
+            let to = match utils::parse_http_url(&from) {
+                Some(to) if to != from => to,
+                _ => continue,
+            };
+            info!("订阅地址规范化: {} => {}", from, to);
+            // 早期版本还没有 WebSub, 这些订阅源不会有要退订的 hub
+            self.migrate_feed(&from, &to)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// 对比已见过的文章记录, 返回没见过的文章下标, 然后把这次拉取到的文章都记为见过.
+    ///
+    /// 同一次拉取里重复的文章只算一次, 没法识别 (`None`) 的文章不算.
+    /// 没有任何记录的话全都是新文章, 刚升级上来的订阅源由 `SubscribeFeed::record_only` 处理.
+    ///
+    /// Arguments:
+    ///
+    /// * `subscribe_url`: 订阅源
+    /// * `post_hashes`: 文章的哈希, 见 `data::post_hashes`
+    /// * `now`: 当前时间, 秒
+    ///
+    /// Returns:
+    ///
+    /// 新文章的下标
+    #[cfg(test)]
+    pub fn diff_seen_posts(
+        &self,
+        subscribe_url: &str,
+        post_hashes: &[Option<String>],
+        now: u64,
+    ) -> Result<Vec<usize>, StoreError> {
+        let new_indexs = self.new_post_indexs(subscribe_url, post_hashes)?;
+        self.mark_posts_seen(subscribe_url, post_hashes.iter().flatten(), now)?;
+        Ok(new_indexs)
+    }
+
+    /// 对比已见过的文章记录, 返回没见过的文章下标, 规则和 `diff_seen_posts` 一样但不会记录
+    pub fn new_post_indexs(
+        &self,
+        subscribe_url: &str,
+        post_hashes: &[Option<String>],
+    ) -> Result<Vec<usize>, StoreError> {
+        let prefix = seen_key_prefix(subscribe_url);
+        let mut new_indexs = vec![];
+        let mut visited = HashSet::new();
+        for (idx, hash) in post_hashes.iter().enumerate() {
+            let hash = match hash {
+                Some(h) if visited.insert(h) => h,
+                _ => continue,
+            };
+            if !self.inner.contains_key(format!("{}{}", prefix, hash))? {
+                new_indexs.push(idx);
+            }
+        }
+        Ok(new_indexs)
+    }
+
+    /// 保存一次拉取的结果: 更新订阅源, 把文章记为见过, 新文章的消息放进推送队列.
+    /// 三样东西在同一个事务里写入, 中途崩溃的话要么都没写, 要么都写了, 不会丢文章.
+    ///
+    /// 订阅源是在数据库里最新的记录上更新的, 拉取期间的订阅, 退订和选项修改不会被覆盖.
+    /// 拉取期间订阅源被删掉了的话什么都不写, 已经退订的频道也不会再收到消息.
+    ///
+    /// Arguments:
+    ///
+    /// * `subscribe_url`: 订阅地址
+    /// * `update`: 用拉取的结果更新订阅源, 事务冲突重试的时候会再调用
+    /// * `post_hashes`: 这次拉取到的文章的哈希
+    /// * `now`: 当前时间, 秒
+    /// * `messages`: 要推送的消息
+    /// * `digests`: 摘要模式的频道攒起来的文章
+    ///
+    /// Returns:
+    ///
+    /// 订阅源已经被删掉了的话返回 false
+    pub fn commit_posts(
+        &self,
+        subscribe_url: &str,
+        update: impl Fn(&mut SubscribeFeed),
+        post_hashes: &[Option<String>],
+        now: u64,
+        messages: &[OutboxMessage],
+        digests: &[DigestEntry],
+    ) -> Result<bool, StoreError> {
+        let key = feed_key(subscribe_url);
+        let prefix = seen_key_prefix(subscribe_url);
+        let seen_at = now.to_string();
+
+        let committed = self.inner.transaction(|tx| {
+            let mut feed = match tx_get::<SubscribeFeed>(tx, &key)? {
+                Some(feed) => feed,
+                None => return Ok(false),
+            };
+            update(&mut feed);
+            tx_put(tx, &key, &feed)?;
+
+            for hash in post_hashes.iter().flatten() {
+                tx.insert(&*format!("{}{}", prefix, hash), &*seen_at)?;
+            }
+
+            let subscribed = |chan: &str| feed.channel_ids.iter().any(|c| c == chan);
+            for msg in messages.iter().filter(|m| subscribed(&m.channel_id)) {
+                tx_put(tx, &outbox_key(&msg.channel_id, tx.generate_id()?), msg)?;
+            }
+            for entry in digests.iter().filter(|e| subscribed(&e.channel_id)) {
+                tx_put(tx, &digest_key(&entry.channel_id, tx.generate_id()?), entry)?;
+            }
+            Ok(true)
+        })?;
+        Ok(committed)
+    }
+
+    /// 设置频道的摘要推送, None 表示改回一篇一篇推送
+    pub fn update_channel_digest(
+        &self,
+        channel_id: &str,
+        digest: Option<DigestSchedule>,
+    ) -> Result<(), StoreError> {
+        if !self.contains_channel(channel_id)? {
+            self.update_or_create_channel(channel_id)?;
+        }
+
+        self.chan_operaiton(&channel_key(channel_id), |chan| {
+            chan.digest = digest;
+        })
+    }
+
+    /// 频道攒着的摘要文章, 按攒进来的顺序排列
+    ///
+    /// Returns:
+    ///
+    /// 文章在数据库里的 key 和文章
+    pub fn digest_entries(
+        &self,
+        channel_id: &str,
+    ) -> Result<Vec<(String, DigestEntry)>, StoreError> {
+        let mut entries = vec![];
+        for kv in self.inner.scan_prefix(digest_key_prefix(channel_id)) {
+            let (k, v) = kv?;
+            let entry = serde_json::from_str(&utils::ivec_to_str(v))?;
+            entries.push((utils::ivec_to_str(k), entry));
+        }
+        Ok(entries)
+    }
+
+    /// 发送摘要: 攒着的文章换成推送队列里的消息, 同时更新频道下一次发送的时间, 在一个事务里写入.
+    /// 频道记录是在事务里重新读出来的, 同时进行的订阅, 退订不会被覆盖.
+    ///
+    /// Arguments:
+    ///
+    /// * `channel_id`: 频道 ID
+    /// * `keys`: 已经合进摘要的文章的 key
+    /// * `messages`: 摘要消息
+    /// * `digest`: 频道新的摘要设置
+    pub fn commit_digest(
+        &self,
+        channel_id: &str,
+        keys: &[String],
+        messages: &[OutboxMessage],
+        digest: Option<DigestSchedule>,
+    ) -> Result<(), StoreError> {
+        let chan_key = channel_key(channel_id);
+        self.inner.transaction(|tx| {
+            let mut chan = tx_get::<ChannelSubFeeds>(tx, &chan_key)?
+                .ok_or_else(|| abort(StoreError::NotFoundChannel(chan_key.to_owned())))?;
+            chan.digest = digest.to_owned();
+            tx_put(tx, &chan_key, &chan)?;
+
+            for key in keys {
+                tx.remove(key.as_str())?;
+            }
+            for msg in messages {
+                tx_put(tx, &outbox_key(&msg.channel_id, tx.generate_id()?), msg)?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    /// 推送队列里的全部消息, 同一个频道的消息按入队的顺序排列
+    ///
+    /// Returns:
+    ///
+    /// 消息在数据库里的 key 和消息
+    pub fn outbox_list(&self) -> Result<Vec<(String, OutboxMessage)>, StoreError> {
+        let mut messages = vec![];
+        for kv in self.inner.scan_prefix(OUTBOX_KEY_PREFIX) {
+            let (k, v) = kv?;
+            let msg = serde_json::from_str(&utils::ivec_to_str(v))?;
+            messages.push((utils::ivec_to_str(k), msg));
+        }
+        Ok(messages)
+    }
+
+    /// 把不属于文章推送的消息 (比如拉取失败的通知) 放进推送队列
+    pub fn enqueue_outbox(&self, messages: &[OutboxMessage]) -> Result<(), StoreError> {
+        let mut batch = sled::Batch::default();
+        for msg in messages {
+            let key = outbox_key(&msg.channel_id, self.inner.generate_id()?);
+            batch.insert(&*key, &*serde_json::to_string(msg)?);
+        }
+        self.inner.apply_batch(batch)?;
+        Ok(())
+    }
+
+    // 推送失败之后更新重试的信息
+    // 推送期间频道退订了的话消息已经被删掉了, 不再写回去
+    pub fn update_outbox(&self, key: &str, msg: &OutboxMessage) -> Result<(), StoreError> {
+        self.inner.transaction(|tx| {
+            if tx.get(key)?.is_some() {
+                tx_put(tx, key, msg)?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    // 推送成功之后删掉
+    pub fn remove_outbox(&self, key: &str) -> Result<(), StoreError> {
+        self.inner.remove(key)?;
+        Ok(())
+    }
+
+    /// 推送彻底失败的消息从推送队列移到死信里, 等管理员处理
+    ///
+    /// Returns:
+    ///
+    /// 死信的编号, `dlq retry` 和 `dlq discard` 用
+    pub fn move_to_dead(&self, key: &str, msg: &OutboxMessage) -> Result<u64, StoreError> {
+        let id = self.inner.generate_id()?;
+        let mut batch = sled::Batch::default();
+        batch.remove(key);
+        batch.insert(&*dead_key(id), &*serde_json::to_string(msg)?);
+        self.inner.apply_batch(batch)?;
+        Ok(id)
+    }
+
+    /// 全部死信, 按进入死信的先后排列
+    pub fn dead_letters(&self) -> Result<Vec<(u64, OutboxMessage)>, StoreError> {
+        let mut letters = vec![];
+        for kv in self.inner.scan_prefix(DEAD_KEY_PREFIX) {
+            let (k, v) = kv?;
+            let id = utils::ivec_to_str(k)
+                .trim_start_matches(DEAD_KEY_PREFIX)
+                .parse::<u64>()
+                .unwrap_or_default();
+            letters.push((id, serde_json::from_str(&utils::ivec_to_str(v))?));
+        }
+        Ok(letters)
+    }
+
+    /// 把死信放回推送队列重新推送, 失败次数清零
+    ///
+    /// Arguments:
+    ///
+    /// * `id`: 死信的编号, None 表示全部
+    ///
+    /// Returns:
+    ///
+    /// 放回去的数量
+    pub fn retry_dead(&self, id: Option<u64>) -> Result<usize, StoreError> {
+        let mut batch = sled::Batch::default();
+        let mut count = 0;
+        for (dead_id, mut msg) in self.dead_letters()? {
+            if id.filter(|id| *id != dead_id).is_some() {
+                continue;
+            }
+            msg.attempts = 0;
+            msg.next_attempt = 0;
+            let key = outbox_key(&msg.channel_id, self.inner.generate_id()?);
+            batch.insert(&*key, &*serde_json::to_string(&msg)?);
+            batch.remove(&*dead_key(dead_id));
+            count += 1;
+        }
+        self.inner.apply_batch(batch)?;
+        Ok(count)
+    }
+
+    /// 删掉死信
+    ///
+    /// Arguments:
+    ///
+    /// * `id`: 死信的编号, None 表示全部
+    ///
+    /// Returns:
+    ///
+    /// 删掉的数量
+    pub fn discard_dead(&self, id: Option<u64>) -> Result<usize, StoreError> {
+        let mut batch = sled::Batch::default();
+        let mut count = 0;
+        for (dead_id, _) in self.dead_letters()? {
+            if id.filter(|id| *id != dead_id).is_some() {
+                continue;
+            }
+            batch.remove(&*dead_key(dead_id));
+            count += 1;
+        }
+        self.inner.apply_batch(batch)?;
+        Ok(count)
+    }
+
+    // 记录见过的文章, 已经有的会刷新时间
+    pub fn mark_posts_seen<'a>(
+        &self,
+        subscribe_url: &str,
+        post_hashes: impl Iterator<Item = &'a String>,
+        now: u64,
+    ) -> Result<(), StoreError> {
+        let prefix = seen_key_prefix(subscribe_url);
+        let now = now.to_string();
+
+        let mut batch = sled::Batch::default();
+        for hash in post_hashes {
+            batch.insert(&*format!("{}{}", prefix, hash), &*now);
+        }
+        self.inner.apply_batch(batch)?;
+        Ok(())
+    }
+
+    /// 删除 `before` 之前就不在订阅源里的文章记录
+    ///
+    /// Returns:
+    ///
+    /// 删除的数量
+    pub fn purge_seen_posts(&self, subscribe_url: &str, before: u64) -> Result<usize, StoreError> {
+        let mut batch = sled::Batch::default();
+        let mut count = 0;
+        for kv in self.inner.scan_prefix(seen_key_prefix(subscribe_url)) {
+            let (k, v) = kv?;
+            let seen_at = utils::ivec_to_str(v).parse::<u64>().unwrap_or_default();
+            if seen_at < before {
+                batch.remove(k);
+                count += 1;
+            }
+        }
+        self.inner.apply_batch(batch)?;
+        Ok(count)
+    }
+
+    // 删除订阅源的所有文章记录
+    fn remove_seen_posts(&self, subscribe_url: &str) -> Result<(), StoreError> {
+        self.purge_seen_posts(subscribe_url, u64::MAX)?;
+        Ok(())
+    }
+
+    /// 通过订阅源 URL 的哈希查找订阅源
+    pub fn feed_by_hash(&self, feed_hash: &str) -> Result<Option<SubscribeFeed>, StoreError> {
+        self.query_feed_by_key(&format!("{}{}", FEED_KEY_PREFIX, feed_hash))
+    }
+
+    /// 全部频道
+    pub fn channel_list(&self) -> Result<Vec<ChannelSubFeeds>, StoreError> {
+        let mut chans = vec![];
+        for kv in self.inner.scan_prefix(CHANNEL_KEY_PREFIX) {
+            let (_, v) = kv?;
+            chans.push(serde_json::from_str(&utils::ivec_to_str(v))?);
+        }
+        Ok(chans)
+    }
+
     pub fn feed_list(&self) -> Result<Vec<SubscribeFeed>, StoreError> {
         let iter = self.inner.scan_prefix(FEED_KEY_PREFIX);
 
@@ -350,6 +965,39 @@ impl Database {
     }
 }
 
+type TxResult<T> = ConflictableTransactionResult<T, StoreError>;
+
+impl From<TransactionError<StoreError>> for StoreError {
+    fn from(e: TransactionError<StoreError>) -> Self {
+        match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => StoreError::Inner(e),
+        }
+    }
+}
+
+#[inline]
+fn abort(e: impl Into<StoreError>) -> ConflictableTransactionError<StoreError> {
+    ConflictableTransactionError::Abort(e.into())
+}
+
+// 事务里读取一条记录
+fn tx_get<T: DeserializeOwned>(tx: &TransactionalTree, key: &str) -> TxResult<Option<T>> {
+    match tx.get(key)? {
+        Some(v) => Ok(Some(
+            serde_json::from_str(&utils::ivec_to_str(v)).map_err(abort)?,
+        )),
+        None => Ok(None),
+    }
+}
+
+// 事务里写入一条记录
+fn tx_put<T: Serialize>(tx: &TransactionalTree, key: &str, value: &T) -> TxResult<()> {
+    let v = serde_json::to_string(value).map_err(abort)?;
+    tx.insert(key, &*v)?;
+    Ok(())
+}
+
 // feed::{subscribe_url_hash} = {Feed Struct}
 const FEED_KEY_PREFIX: &str = "feed::";
 #[inline]
@@ -365,6 +1013,45 @@ fn channel_key(channel_id: &str) -> String {
     format!("{}{}", CHANNEL_KEY_PREFIX, channel_id)
 }
 
+// seen::{subscribe_url_hash}::{post_hash} = 最后一次在订阅源里见到的时间
+const SEEN_KEY_PREFIX: &str = "seen::";
+#[inline]
+fn seen_key_prefix(subscribe_url: &str) -> String {
+    let ha = utils::hash(subscribe_url);
+    format!("{}{}::", SEEN_KEY_PREFIX, ha)
+}
+
+// outbox::{channel_id}::{序号} = {OutboxMessage Struct}, 序号补零, 同一个频道按入队顺序排列
+const OUTBOX_KEY_PREFIX: &str = "outbox::";
+#[inline]
+fn outbox_key_prefix(channel_id: &str) -> String {
+    format!("{}{}::", OUTBOX_KEY_PREFIX, channel_id)
+}
+
+#[inline]
+fn outbox_key(channel_id: &str, id: u64) -> String {
+    format!("{}{:020}", outbox_key_prefix(channel_id), id)
+}
+
+// digest::{channel_id}::{序号} = {DigestEntry Struct}, 摘要模式的频道攒着的文章
+const DIGEST_KEY_PREFIX: &str = "digest::";
+#[inline]
+fn digest_key_prefix(channel_id: &str) -> String {
+    format!("{}{}::", DIGEST_KEY_PREFIX, channel_id)
+}
+
+#[inline]
+fn digest_key(channel_id: &str, id: u64) -> String {
+    format!("{}{:020}", digest_key_prefix(channel_id), id)
+}
+
+// dead::{序号} = {OutboxMessage Struct}, 推送彻底失败的消息
+const DEAD_KEY_PREFIX: &str = "dead::";
+#[inline]
+fn dead_key(id: u64) -> String {
+    format!("{}{:020}", DEAD_KEY_PREFIX, id)
+}
+
 #[inline]
 fn feed_hash(feed: &SubscribeFeed) -> String {
     utils::hash(&feed.subscribe_url)
@@ -373,7 +1060,13 @@ fn feed_hash(feed: &SubscribeFeed) -> String {
 #[cfg(test)]
 mod test {
 
-    use crate::{data::SubscribeFeed, utils};
+    use crate::{
+        conf::ScheduleConfig,
+        data::{
+            DigestEntry, DigestEvery, DigestSchedule, OutboxMessage, SubscribeFeed, WebSubLease,
+        },
+        utils,
+    };
 
     use super::Database;
     use once_cell::sync::Lazy;
@@ -450,6 +1143,455 @@ mod test {
         assert_eq!(feeds_list.len(), 0);
     }
 
+    #[test]
+    fn test_seen_posts() {
+        let subscribe_url = "http://seen.test/feed.xml";
+        let hashes = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| Some(utils::hash(id)))
+                .collect::<Vec<Option<String>>>()
+        };
+
+        // 没有记录的时候全是新文章 (订阅的时候已经记录过了, 或者记录都过期了)
+        let first = hashes(&["a", "b", "c"]);
+        assert_eq!(
+            vec![0, 1, 2],
+            DB.diff_seen_posts(subscribe_url, &first, 100).unwrap()
+        );
+
+        // 新文章 d, 顺序变了也不影响, 重复的 d 只算一次, 没法识别的不算
+        let mut second = hashes(&["c", "d", "a", "d", "b"]);
+        second.push(None);
+        assert_eq!(
+            vec![1],
+            DB.diff_seen_posts(subscribe_url, &second, 200).unwrap()
+        );
+        assert!(DB
+            .diff_seen_posts(subscribe_url, &second, 300)
+            .unwrap()
+            .is_empty());
+
+        // a, b, c, d 都在 300 刷新过, e 是 100 记录的
+        DB.mark_posts_seen(subscribe_url, hashes(&["e"]).iter().flatten(), 100)
+            .unwrap();
+        assert_eq!(1, DB.purge_seen_posts(subscribe_url, 200).unwrap());
+        assert_eq!(
+            vec![0],
+            DB.diff_seen_posts(subscribe_url, &hashes(&["e", "a"]), 400)
+                .unwrap()
+        );
+
+        DB.remove_seen_posts(subscribe_url).unwrap();
+        assert_eq!(
+            vec![0],
+            DB.diff_seen_posts(subscribe_url, &hashes(&["x"]), 500)
+                .unwrap()
+        );
+        DB.remove_seen_posts(subscribe_url).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_feed() {
+        let db = Database::temporary();
+        let feed = |url: &str| SubscribeFeed {
+            title: "test_feed".to_owned(),
+            subscribe_url: url.to_owned(),
+            ..Default::default()
+        };
+        let (old, new, other) = (
+            "http://example.com/feed/",
+            "https://example.com/feed",
+            "http://other.test/feed",
+        );
+
+        // chan_a 订阅了新旧两个地址, chan_b 只订阅了旧地址
+        db.channel_subscribed("chan_a", feed(other)).unwrap();
+        db.channel_subscribed("chan_a", feed(old)).unwrap();
+        db.channel_subscribed("chan_a", feed(new)).unwrap();
+        db.channel_subscribed("chan_b", feed(old)).unwrap();
+        db.update_channel_feed_regex("chan_b", old, "Huawei")
+            .unwrap();
+        db.mark_posts_seen(old, [utils::hash("a")].iter(), 100)
+            .unwrap();
+
+        let lease = WebSubLease {
+            hub: "https://hub.example.com/".to_owned(),
+            topic: old.to_owned(),
+            secret: "secret".to_owned(),
+            token: "token".to_owned(),
+            requested_at: 100,
+            expires_at: 1000,
+        };
+        db.update_feed_websub(old, Some(lease.to_owned())).unwrap();
+
+        // 还没发出去的文章: 推送队列, 死信, 摘要
+        let messages = ["1", "2"]
+            .iter()
+            .map(|c| OutboxMessage::new("chan_b", old, c.to_string(), 100))
+            .collect::<Vec<OutboxMessage>>();
+        let digest = DigestEntry {
+            channel_id: "chan_a".to_owned(),
+            subscribe_url: old.to_owned(),
+            feed_title: "test_feed".to_owned(),
+            title: None,
+            link: "http://example.com/1".to_owned(),
+            created_at: 100,
+        };
+        db.commit_posts(old, |_| (), &[], 100, &messages, &[digest])
+            .unwrap();
+        let (key, msg) = db.outbox_list().unwrap().remove(0);
+        db.move_to_dead(&key, &msg).unwrap();
+
+        let (migrated, dropped) = db.migrate_feed(old, new).unwrap();
+        assert!(db
+            .outbox_list()
+            .unwrap()
+            .iter()
+            .all(|(_, m)| m.subscribe_url == new));
+        assert_eq!(new, db.dead_letters().unwrap()[0].1.subscribe_url);
+        assert_eq!(new, db.digest_entries("chan_a").unwrap()[0].1.subscribe_url);
+        // 旧地址的 WebSub 订阅要交给调用方退订
+        assert_eq!(Some(lease), dropped);
+        assert_eq!(None, migrated.websub);
+        assert_eq!(new, migrated.subscribe_url);
+        assert_eq!(vec!["chan_a", "chan_b"], migrated.channel_ids);
+        assert!(!db.contains_feed(old).unwrap());
+
+        let urls = |chan: &str| {
+            db.channel_feed_list(chan)
+                .unwrap()
+                .into_iter()
+                .map(|f| f.subscribe_url)
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(vec![other, new], urls("chan_a"));
+        assert_eq!(vec![new], urls("chan_b"));
+
+        let chans = db.feed_channel_list(new).unwrap();
+        let chan_b = chans.iter().find(|c| c.id == "chan_b").unwrap();
+        assert_eq!(
+            Some(&"Huawei".to_owned()),
+            chan_b.feed_regex.get(&utils::hash(new))
+        );
+        assert!(!chan_b.feed_regex.contains_key(&utils::hash(old)));
+
+        // 推送记录也搬过去了
+        let seen = vec![Some(utils::hash("a")), Some(utils::hash("b"))];
+        assert_eq!(vec![1], db.diff_seen_posts(new, &seen, 200).unwrap());
+        assert_eq!(vec![0, 1], db.diff_seen_posts(old, &seen, 200).unwrap());
+
+        // 新地址没人订阅过就直接改名
+        let (moved, dropped) = db.migrate_feed(other, "https://other.test/feed").unwrap();
+        assert_eq!(None, dropped);
+        assert_eq!(vec!["chan_a"], moved.channel_ids);
+        assert_eq!(vec!["https://other.test/feed", new], urls("chan_a"));
+    }
+
+    #[test]
+    fn test_feed_failure() {
+        let db = Database::temporary();
+        let url = "http://example.com/feed";
+        let feed = SubscribeFeed {
+            subscribe_url: url.to_owned(),
+            down_time: 100,
+            ..Default::default()
+        };
+        db.channel_subscribed("chan", feed).unwrap();
+
+        let conf = ScheduleConfig {
+            suspend_after: Some(1000),
+            ..Default::default()
+        };
+        let failed = db
+            .update_feed_failure(url, "timeout".to_owned(), &conf, 200)
+            .unwrap();
+        assert_eq!(1, failed.failure_count);
+        assert_eq!(None, failed.suspended_at);
+        let failed = db
+            .update_feed_failure(url, "timeout".to_owned(), &conf, 1100)
+            .unwrap();
+        assert_eq!(2, failed.failure_count);
+        assert_eq!(Some(1100), failed.suspended_at);
+        assert!(failed.next_fetch > 1100);
+
+        db.resume_feed(url).unwrap();
+        let resumed = db.feed_by_hash(&utils::hash(url)).unwrap().unwrap();
+        assert_eq!(0, resumed.failure_count);
+        assert_eq!(None, resumed.suspended_at);
+        assert_eq!(0, resumed.next_fetch);
+
+        // 拉取成功就清零
+        db.update_feed_failure(url, "timeout".to_owned(), &conf, 1200)
+            .unwrap();
+        db.update_feed_down_time(url, 1300).unwrap();
+        let feed = db.feed_by_hash(&utils::hash(url)).unwrap().unwrap();
+        assert_eq!(0, feed.failure_count);
+        assert_eq!(None, feed.last_error);
+    }
+
+    #[test]
+    fn test_normalize_feed_urls() {
+        let db = Database::temporary();
+        // 早期版本原样存下来的地址
+        let legacy = "HTTP://Example.com:80/feed";
+        let feed = SubscribeFeed {
+            subscribe_url: legacy.to_owned(),
+            ..Default::default()
+        };
+        db.channel_subscribed("chan", feed).unwrap();
+        db.update_channel_feed_regex("chan", legacy, "abc").unwrap();
+        db.mark_posts_seen(legacy, [utils::hash("a")].iter(), 100)
+            .unwrap();
+
+        assert_eq!(1, db.normalize_feed_urls().unwrap());
+        let normalized = "http://example.com/feed";
+        assert!(!db.contains_feed(legacy).unwrap());
+        let feed = db.feed_by_hash(&utils::hash(normalized)).unwrap().unwrap();
+        assert_eq!(normalized, feed.subscribe_url);
+        assert_eq!(vec!["chan"], feed.channel_ids);
+        let chan = &db.feed_channel_list(normalized).unwrap()[0];
+        assert_eq!(
+            Some("abc"),
+            chan.feed_regex
+                .get(&utils::hash(normalized))
+                .map(|s| s.as_str())
+        );
+        assert!(db
+            .new_post_indexs(normalized, &[Some(utils::hash("a"))])
+            .unwrap()
+            .is_empty());
+
+        // 已经规范化过的不会再动
+        assert_eq!(0, db.normalize_feed_urls().unwrap());
+    }
+
+    #[test]
+    fn test_digest() {
+        let db = Database::temporary();
+        let url = "http://example.com/feed";
+        let feed = SubscribeFeed {
+            subscribe_url: url.to_owned(),
+            ..Default::default()
+        };
+        db.channel_subscribed("chan", feed.to_owned()).unwrap();
+        db.channel_subscribed("chan2", feed.to_owned()).unwrap();
+
+        let schedule = DigestSchedule {
+            every: DigestEvery::Hours(1),
+            utc_offset: 0,
+            next_send: 3600,
+        };
+        db.update_channel_digest("chan", Some(schedule)).unwrap();
+        let chans = db.channel_list().unwrap();
+        assert_eq!(2, chans.len());
+        assert_eq!(
+            Some(schedule),
+            chans.iter().find(|c| c.id == "chan").unwrap().digest
+        );
+
+        let digests = (0..3)
+            .map(|i| DigestEntry {
+                channel_id: "chan".to_owned(),
+                subscribe_url: url.to_owned(),
+                link: format!("http://example.com/{}", i),
+                ..Default::default()
+            })
+            .collect::<Vec<DigestEntry>>();
+        db.commit_posts(url, |_| (), &[], 100, &[], &digests)
+            .unwrap();
+        assert!(db.outbox_list().unwrap().is_empty());
+        // 频道 ID 是前缀的频道不会混进来
+        assert!(db.digest_entries("chan2").unwrap().is_empty());
+        let entries = db.digest_entries("chan").unwrap();
+        assert_eq!(
+            digests,
+            entries
+                .iter()
+                .map(|(_, e)| e.to_owned())
+                .collect::<Vec<_>>()
+        );
+
+        // 摘要发出去之后攒着的文章换成推送队列里的消息
+        let keys = entries.into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+        let message = OutboxMessage::new("chan", "digest", "digest".to_owned(), 200);
+        db.commit_digest("chan", &keys, &[message], None).unwrap();
+        assert!(db.digest_entries("chan").unwrap().is_empty());
+        assert_eq!(1, db.outbox_list().unwrap().len());
+        assert!(db
+            .channel_list()
+            .unwrap()
+            .iter()
+            .all(|c| c.digest.is_none()));
+    }
+
+    #[test]
+    fn test_outbox() {
+        let db = Database::temporary();
+        let url = "http://example.com/feed";
+        let feed = SubscribeFeed {
+            subscribe_url: url.to_owned(),
+            ..Default::default()
+        };
+        db.channel_subscribed("chan_0", feed.to_owned()).unwrap();
+        db.channel_subscribed("chan_1", feed.to_owned()).unwrap();
+        let hashes = vec![Some(utils::hash("a")), Some(utils::hash("b"))];
+
+        // 还没有任何记录的时候都是新文章
+        assert_eq!(vec![0, 1], db.new_post_indexs(url, &hashes).unwrap());
+
+        let messages = (0..12)
+            .map(|i| OutboxMessage::new(&format!("chan_{}", i % 2), url, i.to_string(), 100))
+            .collect::<Vec<OutboxMessage>>();
+        let update = |f: &mut SubscribeFeed| f.title = "updated".to_owned();
+        assert!(db
+            .commit_posts(url, update, &hashes[..1], 100, &messages, &[])
+            .unwrap());
+
+        // 在数据库里最新的记录上更新, 频道列表还在
+        let updated = db.feed_by_hash(&utils::hash(url)).unwrap().unwrap();
+        assert_eq!("updated", updated.title);
+        assert_eq!(vec!["chan_0", "chan_1"], updated.channel_ids);
+        assert_eq!(vec![1], db.new_post_indexs(url, &hashes).unwrap());
+
+        // 同一个频道按入队顺序排列
+        let outbox = db.outbox_list().unwrap();
+        assert_eq!(12, outbox.len());
+        let contents = |chan: &str| {
+            outbox
+                .iter()
+                .filter(|(_, m)| m.channel_id == chan)
+                .map(|(_, m)| m.content.to_owned())
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(vec!["0", "2", "4", "6", "8", "10"], contents("chan_0"));
+        assert_eq!(vec!["1", "3", "5", "7", "9", "11"], contents("chan_1"));
+
+        let (key, mut msg) = outbox[0].to_owned();
+        msg.attempts = 1;
+        db.update_outbox(&key, &msg).unwrap();
+        assert_eq!(1, db.outbox_list().unwrap()[0].1.attempts);
+
+        db.remove_outbox(&key).unwrap();
+        assert_eq!(11, db.outbox_list().unwrap().len());
+
+        // 退订了的频道, 队列里的消息删掉, 拉取期间退订的也收不到新消息
+        db.channel_unsubscribed("chan_1", url).unwrap();
+        assert!(db
+            .commit_posts(url, |_| (), &hashes, 200, &messages, &[])
+            .unwrap());
+        let outbox = db.outbox_list().unwrap();
+        let count = |chan: &str| outbox.iter().filter(|(_, m)| m.channel_id == chan).count();
+        assert_eq!((11, 0), (count("chan_0"), count("chan_1")));
+
+        // 拉取期间订阅源被删掉了就什么都不写, 订阅源不会又冒出来
+        db.channel_unsubscribed("chan_0", url).unwrap();
+        assert!(db.try_remove_feed(url).unwrap());
+        assert!(!db
+            .commit_posts(url, |_| (), &hashes, 300, &messages, &[])
+            .unwrap());
+        assert!(!db.contains_feed(url).unwrap());
+        assert!(db.outbox_list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unsubscribe_pending() {
+        let db = Database::temporary();
+        let (a, b) = ("http://example.com/a", "http://example.com/b");
+        for (chan, url) in [("chan", a), ("chan", b), ("chan2", a)] {
+            let feed = SubscribeFeed {
+                subscribe_url: url.to_owned(),
+                ..Default::default()
+            };
+            db.channel_subscribed(chan, feed).unwrap();
+        }
+        for url in [a, b] {
+            let messages = ["chan", "chan2"]
+                .iter()
+                .map(|chan| OutboxMessage::new(chan, url, url.to_owned(), 100))
+                .collect::<Vec<OutboxMessage>>();
+            let digests = vec![DigestEntry {
+                channel_id: "chan".to_owned(),
+                subscribe_url: url.to_owned(),
+                ..Default::default()
+            }];
+            db.commit_posts(url, |_| (), &[], 100, &messages, &digests)
+                .unwrap();
+        }
+        let (stale_key, stale_msg) = db
+            .outbox_list()
+            .unwrap()
+            .into_iter()
+            .find(|(_, m)| m.channel_id == "chan" && m.subscribe_url == a)
+            .unwrap();
+
+        // 退订之后还没发出去的文章也不发了, 别的订阅源和别的频道不受影响
+        db.channel_unsubscribed("chan", a).unwrap();
+        let outbox = db
+            .outbox_list()
+            .unwrap()
+            .into_iter()
+            .map(|(_, m)| (m.channel_id, m.subscribe_url))
+            .collect::<Vec<(String, String)>>();
+        assert_eq!(
+            vec![
+                ("chan2".to_owned(), a.to_owned()),
+                ("chan".to_owned(), b.to_owned()),
+            ],
+            outbox
+        );
+        let digests = db.digest_entries("chan").unwrap();
+        assert_eq!(1, digests.len());
+        assert_eq!(b, digests[0].1.subscribe_url);
+
+        // 正在推送的消息失败了也不会写回推送队列
+        db.update_outbox(&stale_key, &stale_msg).unwrap();
+        assert_eq!(2, db.outbox_list().unwrap().len());
+    }
+
+    #[test]
+    fn test_dead_letters() {
+        let db = Database::temporary();
+        let url = "http://example.com/feed";
+        let feed = SubscribeFeed {
+            subscribe_url: url.to_owned(),
+            ..Default::default()
+        };
+        let messages = ["a", "b", "c"]
+            .iter()
+            .map(|c| OutboxMessage::new("chan", url, c.to_string(), 100))
+            .collect::<Vec<OutboxMessage>>();
+        db.channel_subscribed("chan", feed).unwrap();
+        db.commit_posts(url, |_| (), &[], 100, &messages, &[])
+            .unwrap();
+
+        for (key, mut msg) in db.outbox_list().unwrap() {
+            msg.attempts = 3;
+            msg.last_error = Some("没有权限".to_owned());
+            db.move_to_dead(&key, &msg).unwrap();
+        }
+        assert!(db.outbox_list().unwrap().is_empty());
+        let dead = db.dead_letters().unwrap();
+        assert_eq!(
+            vec!["a", "b", "c"],
+            dead.iter()
+                .map(|(_, m)| m.content.as_str())
+                .collect::<Vec<&str>>()
+        );
+        assert_eq!(Some("没有权限"), dead[0].1.last_error.as_deref());
+
+        // 放回推送队列, 失败次数清零
+        assert_eq!(1, db.retry_dead(Some(dead[1].0)).unwrap());
+        let outbox = db.outbox_list().unwrap();
+        assert_eq!(1, outbox.len());
+        assert_eq!("b", outbox[0].1.content);
+        assert_eq!(0, outbox[0].1.attempts);
+
+        assert_eq!(0, db.discard_dead(Some(dead[1].0)).unwrap());
+        assert_eq!(1, db.discard_dead(Some(dead[0].0)).unwrap());
+        assert_eq!(1, db.discard_dead(None).unwrap());
+        assert!(db.dead_letters().unwrap().is_empty());
+    }
+
     #[test]
     fn test_serde() {
         let link = "http://a.b";
@@ -0,0 +1,461 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::{FixedOffset, TimeZone};
+use thiserror::Error;
+use tracing::*;
+
+use crate::data::{DigestEntry, DigestEvery, DigestSchedule, OutboxMessage};
+use crate::db::{Database, StoreError};
+use crate::{push, runtime::unix_now};
+
+const HOUR: i64 = 60 * 60;
+const DAY: i64 = HOUR * 24;
+
+// 多久检查一次有没有到点的摘要
+const DIGEST_POLL_INTERVAL: Duration = Duration::from_secs(60);
+// 一条摘要消息最多多少字节, 超过就拆成几条
+const DIGEST_MAX_LEN: usize = 4000;
+// `digest every` 的间隔, 都能整除 24, 每天都在当地时间同样的几个整点发送
+const DIGEST_HOURS: [u32; 8] = [1, 2, 3, 4, 6, 8, 12, 24];
+// 推送队列里摘要消息的订阅源一栏
+const DIGEST_SOURCE: &str = "digest";
+
+const DIGEST_EVERY: &str = "every";
+const DIGEST_DAILY: &str = "daily";
+
+#[derive(Debug, Error)]
+pub enum DigestError {
+    #[error("间隔应该是 1, 2, 3, 4, 6, 8, 12, 24 小时中的一个: {0}")]
+    InvalidHours(String),
+    #[error("时间应该写成 09:00 这样: {0}")]
+    InvalidTime(String),
+    #[error("UTC 偏移应该写成 +08:00 这样, 不支持 Asia/Shanghai 这样的时区名: {0}")]
+    InvalidOffset(String),
+    #[error("用法: digest every <小时>, digest daily <时间> [UTC 偏移], digest off")]
+    Usage,
+}
+
+/// 解析 `digest` 命令的参数, 算出第一次发送的时间
+///
+/// Arguments:
+///
+/// * `args`: `every 2` 或者 `daily 09:00 +08:00`
+/// * `default_offset`: 没写 UTC 偏移的时候用的偏移, 秒
+/// * `now`: 当前时间, 秒
+pub fn parse_schedule(
+    args: &[&str],
+    default_offset: i32,
+    now: u64,
+) -> Result<DigestSchedule, DigestError> {
+    let (every, utc_offset) = match args {
+        [DIGEST_EVERY, hours] => {
+            let hours = hours
+                .trim_end_matches(['h', 'H'])
+                .parse::<u32>()
+                .ok()
+                .filter(|h| DIGEST_HOURS.contains(h))
+                .ok_or_else(|| DigestError::InvalidHours(hours.to_string()))?;
+            (DigestEvery::Hours(hours), default_offset)
+        }
+        [DIGEST_DAILY, time, rest @ ..] if rest.len() <= 1 => {
+            let (hour, minute) = parse_time(time)?;
+            let utc_offset = match rest.first() {
+                Some(offset) => parse_utc_offset(offset)?,
+                None => default_offset,
+            };
+            (DigestEvery::Daily { hour, minute }, utc_offset)
+        }
+        _ => return Err(DigestError::Usage),
+    };
+
+    Ok(DigestSchedule {
+        every,
+        utc_offset,
+        next_send: next_send(every, utc_offset, now),
+    })
+}
+
+// 09:00, 9:30, 21
+fn parse_time(s: &str) -> Result<(u32, u32), DigestError> {
+    let invalid = || DigestError::InvalidTime(s.to_owned());
+    let (hour, minute) = s.split_once(':').unwrap_or((s, "0"));
+    let hour = hour.parse::<u32>().map_err(|_| invalid())?;
+    let minute = minute.parse::<u32>().map_err(|_| invalid())?;
+    if hour >= 24 || minute >= 60 {
+        return Err(invalid());
+    }
+    Ok((hour, minute))
+}
+
+/// 解析 UTC 偏移: `+08:00`, `+8`, `-0530`, `UTC+8`, `UTC`.
+/// 只是固定的偏移, 不认识时区名, 也不会切换夏令时.
+///
+/// Returns:
+///
+/// 相对 UTC 的秒数
+pub fn parse_utc_offset(s: &str) -> Result<i32, DigestError> {
+    let invalid = || DigestError::InvalidOffset(s.to_owned());
+    let trimmed = s.trim();
+    let offset = trimmed
+        .strip_prefix("UTC")
+        .or_else(|| trimmed.strip_prefix("utc"))
+        .unwrap_or(trimmed);
+    if offset.is_empty() || offset == "Z" {
+        return Ok(0);
+    }
+
+    let (sign, offset) = if let Some(rest) = offset.strip_prefix('+') {
+        (1, rest)
+    } else if let Some(rest) = offset.strip_prefix('-') {
+        (-1, rest)
+    } else {
+        return Err(invalid());
+    };
+    // 只剩数字和冒号, 下面按字节切分不会切到字符中间
+    if offset.is_empty() || !offset.bytes().all(|b| b.is_ascii_digit() || b == b':') {
+        return Err(invalid());
+    }
+    let (hours, minutes) = match offset.split_once(':') {
+        Some(hm) => hm,
+        None if offset.len() == 4 => offset.split_at(2),
+        None => (offset, "0"),
+    };
+    let hours = hours.parse::<i32>().map_err(|_| invalid())?;
+    let minutes = minutes.parse::<i32>().map_err(|_| invalid())?;
+    if hours > 14 || minutes >= 60 {
+        return Err(invalid());
+    }
+    Ok(sign * (hours * HOUR as i32 + minutes * 60))
+}
+
+/// 摘要下一次发送的时间, 一定在 `now` 之后
+///
+/// Arguments:
+///
+/// * `every`: 多久发一次
+/// * `utc_offset`: 相对 UTC 的偏移, 秒
+/// * `now`: 当前时间, 秒
+pub fn next_send(every: DigestEvery, utc_offset: i32, now: u64) -> u64 {
+    let local = now as i64 + utc_offset as i64;
+    let next = match every {
+        // 按当地时间的整点对齐, 每 6 小时就是 0 点, 6 点, 12 点, 18 点
+        DigestEvery::Hours(hours) => {
+            let period = hours.max(1) as i64 * HOUR;
+            (local.div_euclid(period) + 1) * period
+        }
+        DigestEvery::Daily { hour, minute } => {
+            let at = local - local.rem_euclid(DAY) + hour as i64 * HOUR + minute as i64 * 60;
+            if at <= local {
+                at + DAY
+            } else {
+                at
+            }
+        }
+    };
+    (next - utc_offset as i64).max(0) as u64
+}
+
+/// 给人看的摘要设置: `每天 09:00 (UTC+08:00), 下一次 10-18 09:00`
+pub fn describe(schedule: &DigestSchedule) -> String {
+    let every = match schedule.every {
+        DigestEvery::Hours(1) => "每小时".to_owned(),
+        DigestEvery::Hours(hours) => format!("每 {} 小时", hours),
+        DigestEvery::Daily { hour, minute } => format!("每天 {:02}:{:02}", hour, minute),
+    };
+
+    match FixedOffset::east_opt(schedule.utc_offset) {
+        Some(tz) => {
+            let next = tz
+                .timestamp_opt(schedule.next_send as i64, 0)
+                .single()
+                .map(|t| t.format("%m-%d %H:%M").to_string())
+                .unwrap_or_default();
+            format!("{} (UTC{}), 下一次 {}", every, tz, next)
+        }
+        None => every,
+    }
+}
+
+/// 把攒着的文章按订阅源分组渲染成 KMarkdown, 太长的话拆成几条消息
+pub fn render_digest(entries: &[DigestEntry]) -> Vec<String> {
+    if entries.is_empty() {
+        return vec![];
+    }
+
+    // 保持订阅源第一次出现的顺序
+    let mut groups: Vec<(&str, Vec<&DigestEntry>)> = vec![];
+    for entry in entries {
+        match groups
+            .iter_mut()
+            .find(|(url, _)| *url == entry.subscribe_url)
+        {
+            Some((_, posts)) => posts.push(entry),
+            None => groups.push((&entry.subscribe_url, vec![entry])),
+        }
+    }
+
+    let mut messages = vec![];
+    let mut content = format!("**{} 篇新文章**", entries.len());
+    for (url, posts) in groups {
+        let title = match posts[0].feed_title.trim() {
+            "" => url,
+            title => title,
+        };
+        let heading = format!("\n\n**{}** ({})", escape_kmarkdown(title), posts.len());
+
+        let mut needs_heading = true;
+        for post in posts {
+            let line = match post.title.as_deref().map(str::trim) {
+                Some(t) if !t.is_empty() => format!("\n- [{}]({})", escape_kmarkdown(t), post.link),
+                _ => format!("\n- {}", post.link),
+            };
+            if !content.is_empty() && content.len() + heading.len() + line.len() > DIGEST_MAX_LEN {
+                messages.push(std::mem::take(&mut content));
+                needs_heading = true;
+            }
+            if needs_heading {
+                content.push_str(&heading);
+                needs_heading = false;
+            }
+            content.push_str(&line);
+        }
+    }
+    messages.push(content);
+
+    messages
+        .into_iter()
+        .map(|m| m.trim_start().to_owned())
+        .collect()
+}
+
+// 标题里的这些字符会被当成 KMarkdown 语法
+fn escape_kmarkdown(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '\\' | '*' | '[' | ']' | '~' | '`') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// 把频道攒着的文章合成摘要放进推送队列, 同时换上新的摘要设置
+///
+/// Arguments:
+///
+/// * `channel_id`: 频道 ID
+/// * `digest`: 频道新的摘要设置, None 表示改回一篇一篇推送
+/// * `now`: 当前时间, 秒
+///
+/// Returns:
+///
+/// 合进摘要的文章数
+pub fn flush(
+    db: &Database,
+    channel_id: &str,
+    digest: Option<DigestSchedule>,
+    now: u64,
+) -> Result<usize, StoreError> {
+    let entries = db.digest_entries(channel_id)?;
+    let keys = entries
+        .iter()
+        .map(|(k, _)| k.to_owned())
+        .collect::<Vec<_>>();
+    let entries = entries.into_iter().map(|(_, e)| e).collect::<Vec<_>>();
+    let messages = render_digest(&entries)
+        .into_iter()
+        .map(|content| OutboxMessage::new(channel_id, DIGEST_SOURCE, content, now))
+        .collect::<Vec<OutboxMessage>>();
+
+    db.commit_digest(channel_id, &keys, &messages, digest)?;
+    if !messages.is_empty() {
+        info!("摘要: {} 篇文章 => {}", entries.len(), channel_id);
+        push::wake_delivery();
+    }
+    Ok(entries.len())
+}
+
+/// 摘要的定时任务, 到点的频道把攒着的文章合成摘要交给推送队列.
+/// ksbot 没在运行的时候错过的摘要会在启动之后马上补发.
+pub async fn send_digests(db: Arc<Database>) {
+    loop {
+        if let Err(e) = send_due(&db, unix_now()) {
+            error!("发送摘要出错: {}", e);
+        }
+        tokio::time::sleep(DIGEST_POLL_INTERVAL).await;
+    }
+}
+
+fn send_due(db: &Database, now: u64) -> Result<(), StoreError> {
+    for chan in db.channel_list()? {
+        let mut digest = match chan.digest {
+            Some(d) if d.next_send <= now => d,
+            _ => continue,
+        };
+        digest.next_send = next_send(digest.every, digest.utc_offset, now);
+        flush(db, &chan.id, Some(digest), now)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // 2023-10-17 01:30:00 UTC, 星期二
+    const NOW: u64 = 1697506200;
+    const CST: i32 = 8 * 60 * 60;
+
+    fn entry(url: &str, title: Option<&str>, link: &str) -> DigestEntry {
+        DigestEntry {
+            channel_id: "chan".to_owned(),
+            subscribe_url: url.to_owned(),
+            feed_title: format!("{} title", url),
+            title: title.map(|t| t.to_owned()),
+            link: link.to_owned(),
+            created_at: NOW,
+        }
+    }
+
+    #[test]
+    fn test_next_send() {
+        // 每小时: 下一个整点
+        assert_eq!(NOW + 30 * 60, next_send(DigestEvery::Hours(1), 0, NOW));
+        // 每 6 小时, 东八区: 当地 09:30, 下一次是当地 12:00
+        assert_eq!(NOW + 150 * 60, next_send(DigestEvery::Hours(6), CST, NOW));
+
+        let at_nine = DigestEvery::Daily { hour: 9, minute: 0 };
+        // UTC 01:30, 今天 09:00 还没到
+        assert_eq!(NOW + 450 * 60, next_send(at_nine, 0, NOW));
+        // 东八区已经 09:30 了, 明天 09:00
+        assert_eq!(NOW + (24 * 60 - 30) * 60, next_send(at_nine, CST, NOW));
+        // 正好到点的时候排到下一天
+        let due = next_send(at_nine, 0, NOW);
+        assert_eq!(due + 24 * 60 * 60, next_send(at_nine, 0, due));
+        // 西五区: 当地 20:30, 第二天 09:00
+        assert_eq!(
+            NOW + (12 * 60 + 30) * 60,
+            next_send(at_nine, -5 * 60 * 60, NOW)
+        );
+    }
+
+    #[test]
+    fn test_parse_schedule() {
+        let s = parse_schedule(&["every", "2"], 0, NOW).unwrap();
+        assert_eq!(DigestEvery::Hours(2), s.every);
+        assert_eq!(NOW + 30 * 60, s.next_send);
+        assert_eq!(
+            DigestEvery::Hours(12),
+            parse_schedule(&["every", "12h"], 0, NOW).unwrap().every
+        );
+
+        let s = parse_schedule(&["daily", "09:00"], CST, NOW).unwrap();
+        assert_eq!(DigestEvery::Daily { hour: 9, minute: 0 }, s.every);
+        assert_eq!(CST, s.utc_offset);
+        let s = parse_schedule(&["daily", "21:15", "-05:30"], CST, NOW).unwrap();
+        assert_eq!(
+            DigestEvery::Daily {
+                hour: 21,
+                minute: 15
+            },
+            s.every
+        );
+        assert_eq!(-(5 * 60 + 30) * 60, s.utc_offset);
+
+        assert!(matches!(
+            parse_schedule(&["every", "0"], 0, NOW),
+            Err(DigestError::InvalidHours(_))
+        ));
+        assert!(matches!(
+            parse_schedule(&["every", "25"], 0, NOW),
+            Err(DigestError::InvalidHours(_))
+        ));
+        // 不能整除 24 的话每天发送的整点都不一样
+        assert!(matches!(
+            parse_schedule(&["every", "5"], 0, NOW),
+            Err(DigestError::InvalidHours(_))
+        ));
+        assert!(matches!(
+            parse_schedule(&["daily", "24:00"], 0, NOW),
+            Err(DigestError::InvalidTime(_))
+        ));
+        assert!(matches!(
+            parse_schedule(&["daily", "9:00", "Asia/Shanghai"], 0, NOW),
+            Err(DigestError::InvalidOffset(_))
+        ));
+        assert!(matches!(
+            parse_schedule(&["weekly"], 0, NOW),
+            Err(DigestError::Usage)
+        ));
+    }
+
+    #[test]
+    fn test_parse_utc_offset() {
+        assert_eq!(0, parse_utc_offset("UTC").unwrap());
+        assert_eq!(0, parse_utc_offset("Z").unwrap());
+        assert_eq!(CST, parse_utc_offset("+08:00").unwrap());
+        assert_eq!(CST, parse_utc_offset("+8").unwrap());
+        assert_eq!(CST, parse_utc_offset("UTC+8").unwrap());
+        assert_eq!(-(5 * 60 + 30) * 60, parse_utc_offset("-0530").unwrap());
+        assert!(parse_utc_offset("8").is_err());
+        assert!(parse_utc_offset("+15").is_err());
+        assert!(parse_utc_offset("+08:60").is_err());
+        assert!(parse_utc_offset("+-5").is_err());
+        assert!(parse_utc_offset("+").is_err());
+
+        // 非 ASCII 的输入不能 panic
+        for s in [
+            "东八区",
+            "东8",
+            "+东8",
+            "+8东",
+            "-05东0",
+            "UTC+８",
+            "Asia/Shanghai",
+        ] {
+            assert!(parse_utc_offset(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_describe() {
+        let s = parse_schedule(&["daily", "09:00", "+08:00"], 0, NOW).unwrap();
+        assert_eq!("每天 09:00 (UTC+08:00), 下一次 10-18 09:00", describe(&s));
+        let s = parse_schedule(&["every", "1"], 0, NOW).unwrap();
+        assert_eq!("每小时 (UTC+00:00), 下一次 10-17 02:00", describe(&s));
+    }
+
+    #[test]
+    fn test_render_digest() {
+        assert!(render_digest(&[]).is_empty());
+
+        let entries = vec![
+            entry("a", Some("第一篇 [置顶]"), "http://a/1"),
+            entry("b", None, "http://b/1"),
+            entry("a", Some("第二篇"), "http://a/2"),
+        ];
+        assert_eq!(
+            vec!["**3 篇新文章**\n\n**a title** (2)\n- [第一篇 \\[置顶\\]](http://a/1)\n- [第二篇](http://a/2)\n\n**b title** (1)\n- http://b/1"],
+            render_digest(&entries)
+        );
+
+        // 太长的话拆开, 后面的消息重新带上订阅源的标题
+        let entries = (0..200)
+            .map(|i| entry("a", Some(&"标题".repeat(10)), &format!("http://a/{}", i)))
+            .collect::<Vec<DigestEntry>>();
+        let messages = render_digest(&entries);
+        assert!(messages.len() > 1);
+        for m in &messages {
+            assert!(m.len() <= DIGEST_MAX_LEN);
+        }
+        assert!(messages[1].starts_with("**a title** (200)\n- "));
+        assert_eq!(
+            200,
+            messages
+                .iter()
+                .map(|m| m.matches("\n- ").count())
+                .sum::<usize>()
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+// 开头的星期: "Sun, " / "Sunday, " / "Sun "
+static REGEX_WEEKDAY: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[A-Za-z]+,?\s+").unwrap());
+
+// 结尾的 "+08:00" 写法, RFC 822 要求是 "+0800"
+static REGEX_COLON_OFFSET: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\s([+-]\d{2}):(\d{2})$").unwrap());
+
+// 带时区的非标准格式
+const FORMATS_WITH_OFFSET: &[&str] = &[
+    "%Y-%m-%dT%H:%M:%S%.f%z",
+    "%Y-%m-%dT%H:%M%z",
+    "%Y-%m-%d %H:%M:%S%.f%z",
+    "%Y-%m-%d %H:%M:%S%.f %z",
+    "%d %b %Y %H:%M %z",
+    "%d %B %Y %H:%M:%S %z",
+];
+
+// 没有时区的格式, 按 UTC 处理
+const NAIVE_FORMATS: &[&str] = &[
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M",
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%d %H:%M",
+    "%Y/%m/%d %H:%M:%S",
+    "%Y/%m/%d %H:%M",
+    "%d %b %Y %H:%M:%S",
+];
+
+const DATE_FORMATS: &[&str] = &["%Y-%m-%d", "%Y/%m/%d", "%d %b %Y"];
+
+// chrono 的 RFC 822 解析不认识的时区名
+const ZONE_NAMES: &[(&str, &str)] = &[("UTC", "+0000"), ("Z", "+0000"), ("BST", "+0100")];
+
+/// 解析订阅源里的日期.
+///
+/// RSS 用的是 RFC 822, Atom 和 JSON Feed 用的是 RFC 3339,
+/// 除此之外还兼容一些常见的错误写法: 星期对不上, 时区带冒号, 没有时区, 只有日期等.
+/// 没有时区的一律当作 UTC.
+///
+/// Arguments:
+///
+/// * `s`: 日期字符串
+///
+/// Returns:
+///
+/// 解析失败返回 None
+pub fn parse_date(s: &str) -> Option<DateTime<FixedOffset>> {
+    let s = s.split_whitespace().collect::<Vec<_>>().join(" ");
+    if s.is_empty() {
+        return None;
+    }
+
+    if let Ok(d) = DateTime::parse_from_rfc3339(&s) {
+        return Some(d);
+    }
+    if let Ok(d) = DateTime::parse_from_rfc2822(&s) {
+        return Some(d);
+    }
+
+    // 去掉星期 (经常写错), 统一时区写法之后再试一次
+    let mut s = REGEX_WEEKDAY.replace(&s, "").into_owned();
+    s = REGEX_COLON_OFFSET.replace(&s, " $1$2").into_owned();
+    for (name, offset) in ZONE_NAMES {
+        if let Some(rest) = s.strip_suffix(name) {
+            if rest.ends_with(' ') || rest.ends_with(|c: char| c.is_ascii_digit()) {
+                s = format!("{} {}", rest.trim_end(), offset);
+                break;
+            }
+        }
+    }
+
+    if let Ok(d) = DateTime::parse_from_rfc2822(&s) {
+        return Some(d);
+    }
+
+    let found = FORMATS_WITH_OFFSET
+        .iter()
+        .find_map(|f| DateTime::parse_from_str(&s, f).ok());
+    if found.is_some() {
+        return found;
+    }
+
+    let utc = FixedOffset::east_opt(0).unwrap();
+    NAIVE_FORMATS
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(&s, f).ok())
+        .or_else(|| {
+            DATE_FORMATS
+                .iter()
+                .find_map(|f| NaiveDate::parse_from_str(&s, f).ok())
+                .and_then(|d| d.and_hms_opt(0, 0, 0))
+        })
+        .and_then(|d| utc.from_local_datetime(&d).single())
+}
+
+/// 当前时间和 `date` 相差多少秒, `date` 在未来返回 0
+pub fn age_secs(date: &DateTime<FixedOffset>) -> u64 {
+    Utc::now().signed_duration_since(*date).num_seconds().max(0) as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ts(s: &str) -> Option<i64> {
+        parse_date(s).map(|d| d.timestamp())
+    }
+
+    // 2023-04-02T10:00:00Z
+    const EXPECTED: i64 = 1680429600;
+
+    #[test]
+    fn test_standard() {
+        assert_eq!(ts("Sun, 02 Apr 2023 10:00:00 GMT"), Some(EXPECTED));
+        assert_eq!(ts("Sun, 02 Apr 2023 18:00:00 +0800"), Some(EXPECTED));
+        assert_eq!(ts("2023-04-02T10:00:00Z"), Some(EXPECTED));
+        assert_eq!(ts("2023-04-02T18:00:00+08:00"), Some(EXPECTED));
+        assert_eq!(ts("2023-04-02T10:00:00.123Z"), Some(EXPECTED));
+        assert_eq!(ts("Sun, 02 Apr 2023 06:00:00 EDT"), Some(EXPECTED));
+    }
+
+    #[test]
+    fn test_malformed() {
+        // 星期写错
+        assert_eq!(ts("Mon, 02 Apr 2023 10:00:00 GMT"), Some(EXPECTED));
+        // 没有星期, 多余的空白
+        assert_eq!(ts("  2 Apr 2023  10:00:00 GMT "), Some(EXPECTED));
+        // 完整的星期名
+        assert_eq!(ts("Sunday, 02 Apr 2023 10:00:00 GMT"), Some(EXPECTED));
+        // 时区带冒号, UTC
+        assert_eq!(ts("Sun, 02 Apr 2023 18:00:00 +08:00"), Some(EXPECTED));
+        assert_eq!(ts("Sun, 02 Apr 2023 10:00:00 UTC"), Some(EXPECTED));
+        // ISO 8601 但不是 RFC 3339
+        assert_eq!(ts("2023-04-02T18:00:00+0800"), Some(EXPECTED));
+        assert_eq!(ts("2023-04-02 18:00:00 +0800"), Some(EXPECTED));
+        // 没有时区
+        assert_eq!(ts("2023-04-02 10:00:00"), Some(EXPECTED));
+        assert_eq!(ts("2023-04-02T10:00:00"), Some(EXPECTED));
+        assert_eq!(ts("2023/04/02 10:00"), Some(EXPECTED));
+        // 只有日期
+        assert_eq!(ts("2023-04-02"), Some(EXPECTED - 10 * 3600));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(ts(""), None);
+        assert_eq!(ts("昨天"), None);
+        assert_eq!(ts("2023-13-45"), None);
+    }
+}
@@ -0,0 +1,288 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use url::Url;
+
+use super::feed::Feed;
+use super::http::{parse_feed, read_body, read_feed, send};
+use super::source::FeedSource;
+use super::{CacheValidator, FeedError, FetchOptions};
+
+static REGEX_LINK_TAG: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?is)<link\b[^>]*>").unwrap());
+
+static REGEX_ATTRIBUTE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"([\w:-]+)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))"#).unwrap());
+
+// 订阅源的 MIME 类型
+const FEED_TYPES: &[&str] = &[
+    "application/rss+xml",
+    "application/atom+xml",
+    "application/rdf+xml",
+    "application/feed+json",
+    "application/json",
+];
+
+// 页面没有声明订阅源的时候, 挨个试一下这些常见路径
+const COMMON_FEED_PATHS: &[&str] = &[
+    "/feed",
+    "/rss",
+    "/atom.xml",
+    "/feed.xml",
+    "/rss.xml",
+    "/index.xml",
+];
+
+/// 网页里找到的订阅源
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeedLink {
+    pub url: String,
+    pub title: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum Discovered {
+    // 只有一个订阅源, 已经拉取好了
+    Feed(String, Box<Feed>),
+    // 有多个订阅源, 让用户自己选
+    Candidates(Vec<FeedLink>),
+}
+
+/// 拉取订阅源, 如果 URL 是一个普通网页就在网页里找订阅源.
+///
+/// 先看网页里的 `<link rel="alternate">`, 一个都没有的话再试常见的路径.
+/// 订阅源永久重定向了的话, 返回的是新地址.
+///
+/// Arguments:
+///
+/// * `url`: 订阅源或者网页的 URL
+/// * `options`: 请求选项, 网页和找到的订阅源都会用
+///
+/// Returns:
+///
+/// 找到的订阅源
+pub async fn discover_feed(url: &str, options: &FetchOptions) -> Result<Discovered, FeedError> {
+    // 本地订阅源和被监控的网页不用找
+    if !matches!(FeedSource::from_url(url), FeedSource::Http(_)) {
+        let feed = super::pull_feed(url, options).await?;
+        return Ok(Discovered::Feed(url.to_owned(), Box::new(feed)));
+    }
+
+    let (resp, moved_to, permit) = send(url, &CacheValidator::default(), options).await?;
+    let page_url = resp.url().clone();
+    let (content_type, body) = read_body(resp).await?;
+    // 后面还要请求同一个主机, 读完就释放, 不然限制了每个主机的并发数时会自己等自己
+    drop(permit);
+
+    // 本来就是订阅源
+    let body = match parse_feed(content_type.as_deref(), body.clone()) {
+        Ok(feed) => {
+            let url = moved_to.unwrap_or_else(|| url.to_owned());
+            return Ok(Discovered::Feed(url, Box::new(feed)));
+        }
+        Err(FeedError::HtmlPage) => super::encoding::decode_to_utf8(content_type.as_deref(), body),
+        Err(e) => return Err(e),
+    };
+
+    let html = String::from_utf8_lossy(&body);
+    let mut links = find_feed_links(&html, &page_url);
+    if links.is_empty() {
+        links = probe_common_paths(&page_url, options).await;
+    }
+
+    match links.len() {
+        0 => Err(FeedError::NoFeedFound),
+        1 => {
+            let link = links.remove(0);
+            let (resp, moved_to, _permit) =
+                send(&link.url, &CacheValidator::default(), options).await?;
+            let feed = read_feed(resp).await?;
+            Ok(Discovered::Feed(
+                moved_to.unwrap_or(link.url),
+                Box::new(feed),
+            ))
+        }
+        _ => Ok(Discovered::Candidates(links)),
+    }
+}
+
+/// 找到网页里所有的 `<link rel="alternate" type="application/rss+xml" href="...">`,
+/// 相对地址会按网页的地址补全
+fn find_feed_links(html: &str, base: &Url) -> Vec<FeedLink> {
+    let mut links: Vec<FeedLink> = vec![];
+
+    for tag in REGEX_LINK_TAG.find_iter(html) {
+        let attr = |name: &str| {
+            REGEX_ATTRIBUTE
+                .captures_iter(tag.as_str())
+                .find(|c| c[1].eq_ignore_ascii_case(name))
+                .and_then(|c| c.get(2).or_else(|| c.get(3)).or_else(|| c.get(4)))
+                .map(|v| v.as_str().replace("&amp;", "&"))
+        };
+
+        let is_alternate = attr("rel").is_some_and(|rel| {
+            rel.split_whitespace()
+                .any(|r| r.eq_ignore_ascii_case("alternate"))
+        });
+        let is_feed = attr("type")
+            .is_some_and(|t| FEED_TYPES.iter().any(|f| t.trim().eq_ignore_ascii_case(f)));
+        if !is_alternate || !is_feed {
+            continue;
+        }
+
+        let url = match attr("href").and_then(|href| base.join(href.trim()).ok()) {
+            Some(u) if matches!(u.scheme(), "http" | "https") => u.to_string(),
+            _ => continue,
+        };
+
+        if !links.iter().any(|l| l.url == url) {
+            links.push(FeedLink {
+                url,
+                title: attr("title").filter(|t| !t.trim().is_empty()),
+            });
+        }
+    }
+
+    links
+}
+
+/// 按顺序试常见的订阅源路径, 找到一个能解析的就停下
+async fn probe_common_paths(base: &Url, options: &FetchOptions) -> Vec<FeedLink> {
+    for path in COMMON_FEED_PATHS {
+        let url = match base.join(path) {
+            Ok(u) => u.to_string(),
+            Err(_) => continue,
+        };
+
+        if let Ok(feed) = super::pull_feed(&url, options).await {
+            return vec![FeedLink {
+                url,
+                title: Some(feed.title),
+            }];
+        }
+    }
+
+    vec![]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const RSS: &[u8] = include_bytes!("../../test/data/rss_2.0.xml");
+
+    // 按路径返回内容的 HTTP 服务: (路径, 内容), 其他路径都返回 404
+    async fn serve(pages: &'static [(&'static str, &'static [u8])]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut req = vec![0; 4096];
+                let n = socket.read(&mut req).await.unwrap();
+                let req = String::from_utf8_lossy(&req[..n]).to_string();
+                let path = req.split_whitespace().nth(1).unwrap_or_default().to_owned();
+                let resp = match pages.iter().find(|(p, _)| *p == path) {
+                    Some((_, body)) => {
+                        let mut r = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                            body.len()
+                        )
+                        .into_bytes();
+                        r.extend_from_slice(body);
+                        r
+                    }
+                    None => {
+                        b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                            .to_vec()
+                    }
+                };
+                socket.write_all(&resp).await.unwrap();
+                socket.shutdown().await.unwrap();
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_discover_same_host() {
+        crate::fetch::init_test_client();
+        let timeout = std::time::Duration::from_secs(5);
+
+        // 网页里声明了订阅源
+        let base = serve(&[
+            (
+                "/",
+                br#"<html><head><link rel="alternate" type="application/rss+xml" href="/posts.xml"></head></html>"#,
+            ),
+            ("/posts.xml", RSS),
+        ])
+        .await;
+        let found = tokio::time::timeout(timeout, discover_feed(&base, &FetchOptions::default()))
+            .await
+            .expect("discover_feed 卡住了")
+            .unwrap();
+        match found {
+            Discovered::Feed(url, feed) => {
+                assert_eq!(url, format!("{}/posts.xml", base));
+                assert_eq!(feed.title, "rss_2.0.channel.title");
+            }
+            d => panic!("{:?}", d),
+        }
+
+        // 没有声明, 试常见路径
+        let base = serve(&[("/", b"<html></html>"), ("/rss", RSS)]).await;
+        let found = tokio::time::timeout(timeout, discover_feed(&base, &FetchOptions::default()))
+            .await
+            .expect("discover_feed 卡住了")
+            .unwrap();
+        match found {
+            Discovered::Feed(url, _) => assert_eq!(url, format!("{}/rss", base)),
+            d => panic!("{:?}", d),
+        }
+    }
+
+    #[test]
+    fn test_find_feed_links() {
+        let html = r#"<!DOCTYPE html>
+<html><head>
+<link rel="stylesheet" href="/style.css">
+<link rel="alternate" type="application/rss+xml" title="全部文章" href="/feed.xml">
+<LINK REL='alternate' TYPE='application/atom+xml' HREF='atom.xml?a=1&amp;b=2'>
+<link type="application/feed+json" rel="alternate home" href="https://cdn.example.org/feed.json" title="">
+<link rel="alternate" type="text/html" hreflang="en" href="/en/">
+<link rel="alternate" type="application/rss+xml" href="/feed.xml">
+</head></html>"#;
+
+        let base = Url::parse("https://example.org/blog/").unwrap();
+        let links = find_feed_links(html, &base);
+        assert_eq!(
+            links,
+            vec![
+                FeedLink {
+                    url: "https://example.org/feed.xml".to_owned(),
+                    title: Some("全部文章".to_owned()),
+                },
+                FeedLink {
+                    url: "https://example.org/blog/atom.xml?a=1&b=2".to_owned(),
+                    title: None,
+                },
+                FeedLink {
+                    url: "https://cdn.example.org/feed.json".to_owned(),
+                    title: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_no_feed_links() {
+        let base = Url::parse("https://example.org/").unwrap();
+        assert!(find_feed_links("<html><head></head></html>", &base).is_empty());
+        assert!(find_feed_links(
+            r#"<link rel="alternate" type="application/rss+xml" href="javascript:alert(1)">"#,
+            &base
+        )
+        .is_empty());
+    }
+}
@@ -0,0 +1,108 @@
+use std::borrow::Cow;
+
+use encoding_rs::{Encoding, UTF_8};
+use once_cell::sync::Lazy;
+use regex::bytes::Regex;
+
+// 只看文档开头的 XML 声明: <?xml version="1.0" encoding="gb2312"?>
+static REGEX_XML_ENCODING: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"^\s*<\?xml[^>]*?encoding\s*=\s*["']([A-Za-z0-9._:\-]+)["']"#).unwrap()
+});
+
+// XML 声明只会出现在开头, 没必要扫描整个文档
+const XML_DECLARATION_SCAN_LEN: usize = 256;
+
+/// 从 `Content-Type` 里取出 charset
+fn charset_from_content_type(content_type: &str) -> Option<&'static Encoding> {
+    content_type
+        .split(';')
+        .skip(1)
+        .filter_map(|param| param.split_once('='))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case("charset"))
+        .and_then(|(_, v)| Encoding::for_label(v.trim().trim_matches('"').as_bytes()))
+}
+
+/// 从 XML 声明里取出 encoding
+fn charset_from_xml_declaration(body: &[u8]) -> Option<&'static Encoding> {
+    let head = &body[..body.len().min(XML_DECLARATION_SCAN_LEN)];
+    let caps = REGEX_XML_ENCODING.captures(head)?;
+    Encoding::for_label(caps.get(1)?.as_bytes())
+}
+
+/// 把订阅源的正文转成 UTF-8, 之后的解析都假定是 UTF-8.
+///
+/// 优先级: BOM > HTTP `Content-Type` 的 charset > XML 声明 > UTF-8.
+/// 有不少服务端的 `Content-Type` 写着 UTF-8 正文却是 GBK, 这种情况下如果正文不是合法的 UTF-8 就以 XML 声明为准.
+pub fn decode_to_utf8(content_type: Option<&str>, body: Vec<u8>) -> Vec<u8> {
+    let declared = charset_from_xml_declaration(&body);
+    let encoding = match content_type.and_then(charset_from_content_type) {
+        Some(enc) if enc == UTF_8 && std::str::from_utf8(&body).is_err() => declared.unwrap_or(enc),
+        Some(enc) => enc,
+        None => declared.unwrap_or(UTF_8),
+    };
+
+    let (text, _, _) = encoding.decode(&body);
+    match text {
+        // 已经是 UTF-8 了(BOM 也去掉了), 原样返回
+        Cow::Borrowed(s) if s.len() == body.len() => body,
+        text => text.into_owned().into_bytes(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::fetch::{feed::Feed, FromXmlWithBufRead};
+
+    fn parse(content_type: Option<&str>, body: &[u8]) -> Feed {
+        let utf8 = decode_to_utf8(content_type, body.to_vec());
+        Feed::from_xml_with_buf(Cursor::new(utf8)).unwrap()
+    }
+
+    #[test]
+    fn gbk_test() {
+        let s: &[u8] = include_bytes!("../../test/data/gbk.xml");
+        let r = parse(Some("text/xml"), s);
+        assert_eq!(&r.title, "新浪新闻 - 国内新闻");
+        assert_eq!(r.posts[0].title.as_deref(), Some("测试标题：中文编码"));
+        assert_eq!(
+            r.posts[0].description.as_deref(),
+            Some("正文内容，包含全角符号“引号”。")
+        );
+
+        // 服务端谎报 UTF-8
+        let r = parse(Some("application/rss+xml; charset=UTF-8"), s);
+        assert_eq!(&r.title, "新浪新闻 - 国内新闻");
+    }
+
+    #[test]
+    fn big5_test() {
+        let s: &[u8] = include_bytes!("../../test/data/big5.xml");
+        let r = parse(None, s);
+        assert_eq!(&r.title, "中央社 即時新聞");
+        assert_eq!(r.posts[0].title.as_deref(), Some("測試標題：繁體編碼"));
+    }
+
+    #[test]
+    fn content_type_charset_test() {
+        // 没有 XML 声明, 只能靠 Content-Type
+        let body = encoding_rs::SHIFT_JIS
+            .encode("<rss><channel><title>ニュース</title></channel></rss>")
+            .0
+            .into_owned();
+        let r = parse(Some("text/xml; charset=\"Shift_JIS\""), &body);
+        assert_eq!(&r.title, "ニュース");
+    }
+
+    #[test]
+    fn utf8_passthrough_test() {
+        let s: &[u8] = include_bytes!("../../test/data/3dm.xml");
+        assert_eq!(decode_to_utf8(None, s.to_vec()), s);
+        assert_eq!(
+            decode_to_utf8(None, b"\xEF\xBB\xBF<rss/>".to_vec()),
+            b"<rss/>"
+        );
+    }
+}
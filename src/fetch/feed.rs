@@ -7,7 +7,10 @@ use super::buf::BufPool;
 use super::item::FeedPost;
 use super::utils::attrs_get_str;
 use super::utils::{parse_atom_link, AtomLink, NumberData, TextOrCData};
-use super::{FromXmlWithBufRead, FromXmlWithReader, FromXmlWithStr, SkipThisElement};
+use super::{FeedError, FromXmlWithBufRead, FromXmlWithReader, FromXmlWithStr, SkipThisElement};
+
+// 订阅源的根元素: RSS 2.0, RSS 1.0, Atom 1.0
+const FEED_ROOT_ELEMENTS: &[&str] = &["rss", "rdf:RDF", "feed"];
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 #[serde(rename = "rss")]
@@ -24,6 +27,9 @@ pub struct Feed {
     #[serde(rename = "atomLink")]
     pub atom_link: Option<String>,
 
+    // WebSub hub: <atom:link rel="hub">
+    pub hub: Option<String>,
+
     pub language: Option<String>,
 
     #[serde(rename = "webMaster")]
@@ -36,6 +42,20 @@ pub struct Feed {
 
     pub ttl: Option<u32>,
 
+    // 不用拉取的小时 (UTC, 0-23) 和星期: <skipHours>, <skipDays>
+    #[serde(rename = "skipHours", default)]
+    pub skip_hours: Vec<String>,
+
+    #[serde(rename = "skipDays", default)]
+    pub skip_days: Vec<String>,
+
+    // 更新频率: <sy:updatePeriod>, <sy:updateFrequency>
+    #[serde(rename = "updatePeriod")]
+    pub update_period: Option<String>,
+
+    #[serde(rename = "updateFrequency")]
+    pub update_frequency: Option<u32>,
+
     pub image: Option<ChannelImage>,
 
     pub posts: Vec<FeedPost>,
@@ -71,9 +91,9 @@ impl FromXmlWithBufRead for Feed {
 
 impl FromXmlWithReader for Feed {
     /// > Read the XML document, and for each element, if it's a `<rss>` element, get the `version`
-    /// attribute, if it's a `<channel>` element, read the `<title>`, `<description>`, `<link>` and
-    /// `<item>` elements, and if it's a `<item>` element, read the `<title>`, `<description>`, `<link>`,
-    /// `<pubDate>`, `<guid>` and `<category>` elements
+    /// > attribute, if it's a `<channel>` element, read the `<title>`, `<description>`, `<link>` and
+    /// > `<item>` elements, and if it's a `<item>` element, read the `<title>`, `<description>`, `<link>`,
+    /// > `<pubDate>`, `<guid>` and `<category>` elements
     ///
     /// Arguments:
     ///
@@ -88,6 +108,8 @@ impl FromXmlWithReader for Feed {
         reader: &mut Reader<B>,
     ) -> quick_xml::Result<Self> {
         let mut feed = Feed::default();
+        // RSS 1.0 的 item 和 channel 是平级的, channel 结束了还不能停
+        let mut is_rdf = false;
 
         reader.trim_text(true);
 
@@ -95,27 +117,32 @@ impl FromXmlWithReader for Feed {
 
         loop {
             match reader.read_event(&mut buf) {
-                Ok(Event::Empty(ref ce)) => match reader.decode(ce.name())? {
-                    // Atom 1.0 兼容
-                    "link" => match parse_atom_link(reader, ce.attributes())? {
+                // Atom 1.0 兼容
+                Ok(Event::Empty(ref ce)) if reader.decode(ce.name())? == "link" => {
+                    match parse_atom_link(reader, ce.attributes())? {
                         Some(AtomLink::Alternate(link)) => feed.link = link,
                         Some(AtomLink::Source(link)) => feed.atom_link = Some(link),
+                        Some(AtomLink::Hub(link)) => feed.hub = Some(link),
                         _ => {}
-                    },
-
-                    _ => (),
-                },
+                    }
+                }
 
                 Ok(Event::Start(ref re)) => match reader.decode(re.name())? {
                     // RSS 版本
                     "rss" => feed.version = attrs_get_str(reader, re.attributes(), "version")?,
 
+                    // RSS 1.0
+                    "rdf:RDF" => {
+                        is_rdf = true;
+                        feed.version = Some("1.0".to_owned());
+                    }
+
                     // feed: Atom 1.0
                     "channel" | "feed" => continue,
 
                     "title" => {
-                        feed.title = TextOrCData::from_xml_with_reader(bufs, reader)?
-                            .expect("没有title的订阅源？！");
+                        feed.title =
+                            TextOrCData::from_xml_with_reader(bufs, reader)?.unwrap_or_default();
                     }
 
                     "description" => {
@@ -128,12 +155,15 @@ impl FromXmlWithReader for Feed {
                             None => match parse_atom_link(reader, re.attributes())? {
                                 Some(AtomLink::Source(e)) => feed.atom_link = Some(e),
                                 Some(AtomLink::Alternate(e)) => feed.link = e,
+                                Some(AtomLink::Hub(e)) => feed.hub = Some(e),
                                 _ => (),
                             },
                         };
                     }
 
-                    "language" => feed.language = TextOrCData::from_xml_with_reader(bufs, reader)?,
+                    "language" | "dc:language" => {
+                        feed.language = TextOrCData::from_xml_with_reader(bufs, reader)?
+                    }
 
                     "webMaster" => {
                         feed.web_master = TextOrCData::from_xml_with_reader(bufs, reader)?
@@ -143,21 +173,42 @@ impl FromXmlWithReader for Feed {
                         feed.generator = TextOrCData::from_xml_with_reader(bufs, reader)?
                     }
 
-                    "lastBuildDate" => {
+                    "lastBuildDate" | "dc:date" => {
                         feed.last_build_date = TextOrCData::from_xml_with_reader(bufs, reader)?
                     }
 
                     "ttl" => feed.ttl = NumberData::from_xml_with_reader(bufs, reader)?,
 
+                    "skipHours" => {
+                        feed.skip_hours = ChildTexts::from_xml_with_reader(bufs, reader)?.0
+                    }
+
+                    "skipDays" => {
+                        feed.skip_days = ChildTexts::from_xml_with_reader(bufs, reader)?.0
+                    }
+
+                    "sy:updatePeriod" => {
+                        feed.update_period = TextOrCData::from_xml_with_reader(bufs, reader)?
+                    }
+
+                    "sy:updateFrequency" => {
+                        feed.update_frequency = NumberData::from_xml_with_reader(bufs, reader)?
+                    }
+
                     "image" => feed.image = Some(ChannelImage::from_xml_with_reader(bufs, reader)?),
 
                     // entry: Atom 1.0 兼容
                     "item" | "entry" => {
-                        let item = FeedPost::from_xml_with_reader(bufs, reader)?;
+                        // RSS 1.0 的 item 用 rdf:about 做唯一标识
+                        let about = attrs_get_str(reader, re.attributes(), "rdf:about")?;
+                        let mut item = FeedPost::from_xml_with_reader(bufs, reader)?;
+                        if item.guid.is_none() {
+                            item.guid = about;
+                        }
                         feed.posts.push(item);
                     }
 
-                    "copyright" => {
+                    "copyright" | "dc:rights" => {
                         feed.copyright = TextOrCData::from_xml_with_reader(bufs, reader)?
                     }
 
@@ -166,8 +217,9 @@ impl FromXmlWithReader for Feed {
                     }
                 },
 
+                Ok(Event::End(ref e)) if is_rdf && e.name() == b"channel" => (),
                 Ok(Event::Eof | Event::End(_)) => break,
-                Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
+                Err(e) => return Err(e),
 
                 _ => (),
             }
@@ -178,6 +230,63 @@ impl FromXmlWithReader for Feed {
     }
 }
 
+impl Feed {
+    /// 解析 XML 订阅源, 和 `from_xml_with_buf` 不同的是会检查文档是不是订阅源,
+    /// 出错的时候带上出错的位置
+    ///
+    /// Arguments:
+    ///
+    /// * `body`: UTF-8 的 XML 文档
+    ///
+    /// Returns:
+    ///
+    /// A `Result<Feed, FeedError>`
+    pub fn from_xml_slice(body: &[u8]) -> Result<Feed, FeedError> {
+        match root_element(body)? {
+            None => return Err(FeedError::Empty),
+            Some(root) if !FEED_ROOT_ELEMENTS.contains(&root.as_str()) => {
+                return Err(FeedError::NotFeed(root))
+            }
+            _ => (),
+        }
+
+        let bufs: BufPool = Default::default();
+        let mut reader = Reader::from_reader(body);
+        Self::from_xml_with_reader(&bufs, &mut reader).map_err(|source| FeedError::Malformed {
+            position: reader.buffer_position(),
+            source,
+        })
+    }
+}
+
+/// 文档的第一个元素, 空文档返回 None
+fn root_element(body: &[u8]) -> Result<Option<String>, FeedError> {
+    let mut reader = Reader::from_reader(body);
+    let mut buf = vec![];
+
+    let malformed = |reader: &Reader<&[u8]>, source| FeedError::Malformed {
+        position: reader.buffer_position(),
+        source,
+    };
+
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(ref e) | Event::Empty(ref e)) => {
+                let name = reader.decode(e.name()).map_err(|e| malformed(&reader, e))?;
+                return Ok(Some(name.to_owned()));
+            }
+            Ok(Event::Eof) => return Ok(None),
+            // 开头的文本只能是空白
+            Ok(Event::Text(ref e)) if !e.iter().all(|b| b.is_ascii_whitespace()) => {
+                return Err(FeedError::NotFeed("#text".to_owned()))
+            }
+            Ok(_) => (),
+            Err(e) => return Err(malformed(&reader, e)),
+        }
+        buf.clear();
+    }
+}
+
 impl FromStr for Feed {
     type Err = quick_xml::Error;
 
@@ -200,7 +309,7 @@ impl FromStr for Feed {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename = "image")]
 pub struct ChannelImage {
-    url: Option<String>,
+    pub url: Option<String>,
 }
 
 impl FromXmlWithReader for ChannelImage {
@@ -232,12 +341,81 @@ impl FromXmlWithReader for ChannelImage {
     }
 }
 
+// 子元素的文本: <skipHours><hour>0</hour><hour>1</hour></skipHours>
+struct ChildTexts(Vec<String>);
+
+impl FromXmlWithReader for ChildTexts {
+    fn from_xml_with_reader<B: std::io::BufRead>(
+        bufs: &BufPool,
+        reader: &mut Reader<B>,
+    ) -> quick_xml::Result<Self> {
+        let mut texts = vec![];
+
+        let mut buf = bufs.pop();
+
+        loop {
+            match reader.read_event(&mut buf) {
+                Ok(Event::Start(_)) => {
+                    if let Some(text) = TextOrCData::from_xml_with_reader(bufs, reader)? {
+                        texts.push(text);
+                    }
+                }
+                Ok(Event::End(_) | Event::Eof) => break,
+                Ok(_) => (),
+
+                Err(e) => return Err(e),
+            }
+            buf.clear();
+        }
+
+        Ok(Self(texts))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::io::Cursor;
 
     use super::*;
 
+    #[test]
+    fn from_xml_slice_test() {
+        let s: &[u8] = include_bytes!("../../test/data/rss_2.0.xml");
+        let r = Feed::from_xml_slice(s).unwrap();
+        assert_eq!(&r.title, "rss_2.0.channel.title");
+
+        let s: &[u8] = include_bytes!("../../test/data/malformed.xml");
+        match Feed::from_xml_slice(s) {
+            Err(FeedError::Malformed { position, .. }) => assert!(position > 0),
+            r => panic!("expect Malformed, got {:?}", r),
+        }
+
+        let s: &[u8] = include_bytes!("../../test/data/not_feed.xml");
+        match Feed::from_xml_slice(s) {
+            Err(FeedError::NotFeed(root)) => assert_eq!(&root, "urlset"),
+            r => panic!("expect NotFeed, got {:?}", r),
+        }
+
+        let s: &[u8] = include_bytes!("../../test/data/empty.xml");
+        assert!(matches!(Feed::from_xml_slice(s), Err(FeedError::Empty)));
+        assert!(matches!(
+            Feed::from_xml_slice(b"<?xml version=\"1.0\"?>\n  "),
+            Err(FeedError::Empty)
+        ));
+        assert!(matches!(
+            Feed::from_xml_slice(b"not xml at all"),
+            Err(FeedError::NotFeed(_))
+        ));
+    }
+
+    #[test]
+    fn missing_title_test() {
+        let s: &[u8] = include_bytes!("../../test/data/missing_title.xml");
+        let r = Feed::from_xml_slice(s).unwrap();
+        assert_eq!(&r.title, "");
+        assert_eq!(r.posts.len(), 1);
+    }
+
     #[test]
     fn encoding() {
         let s: &[u8] = include_bytes!("../../test/data/rss_2.0.xml");
@@ -248,6 +426,11 @@ mod test {
             r.description,
             Some("rss_2.0.channel.description".to_owned())
         );
+        assert_eq!(r.ttl, Some(100));
+        assert_eq!(r.skip_hours.len(), 23);
+        assert_eq!(r.skip_hours[22], "23");
+        // 不是 <skipDays>
+        assert!(r.skip_days.is_empty());
     }
 
     #[test]
@@ -265,17 +448,180 @@ mod test {
         );
     }
 
+    #[test]
+    fn rdf_test() {
+        let s: &[u8] = include_bytes!("../../test/data/rss_1.0.xml");
+        let r = Feed::from_xml_with_buf(Cursor::new(s)).unwrap();
+        assert_eq!(r.version.as_deref(), Some("1.0"));
+        assert_eq!(&r.title, "rss_1.0.channel.title");
+        assert_eq!(&r.link, "http://example.com/rss_1.0");
+        assert_eq!(
+            r.description.as_deref(),
+            Some("rss_1.0.channel.description")
+        );
+        assert_eq!(r.language.as_deref(), Some("ja"));
+        assert_eq!(r.update_period.as_deref(), Some("hourly"));
+        assert_eq!(r.update_frequency, Some(2));
+        assert_eq!(r.copyright.as_deref(), Some("rss_1.0.channel.rights"));
+        assert_eq!(
+            r.last_build_date.as_deref(),
+            Some("2023-04-02T09:00:00+09:00")
+        );
+        assert_eq!(
+            r.image.and_then(|i| i.url).as_deref(),
+            Some("http://example.com/rss_1.0/logo.png")
+        );
+
+        assert_eq!(r.posts.len(), 2);
+        assert_eq!(r.posts[0].title.as_deref(), Some("rss_1.0.item[0].title"));
+        assert_eq!(
+            r.posts[0].link.as_deref(),
+            Some("http://example.com/rss_1.0/item0")
+        );
+        assert_eq!(
+            r.posts[0].guid.as_deref(),
+            Some("http://example.com/rss_1.0/item0#about")
+        );
+        assert_eq!(
+            r.posts[0].pub_date.as_deref(),
+            Some("2023-04-02T08:30:00+09:00")
+        );
+        assert_eq!(
+            r.posts[0].author.as_deref(),
+            Some("rss_1.0.item[0].creator")
+        );
+        assert_eq!(r.posts[0].category, vec!["rss_1.0.item[0].subject"]);
+        assert_eq!(r.posts[1].title.as_deref(), Some("rss_1.0.item[1].title"));
+    }
+
     #[test]
     fn atom_test() {
         let s: &[u8] = include_bytes!("../../test/data/atom_1.0.xml");
         let r = Feed::from_xml_with_buf(Cursor::new(s)).unwrap();
         assert_eq!(&r.title, "atom_1.0.feed.title");
         assert_eq!(&r.link, "http://example.com/blog_plain");
+        assert_eq!(r.hub.as_deref(), Some("http://example.com/hub"));
         assert_eq!(r.description.as_deref(), None,);
+        assert_eq!(r.posts.len(), 2);
         assert_eq!(
             r.posts[0].title.as_deref(),
             Some("atom_1.0.feed.entry[0].title")
         );
+        assert_eq!(r.posts[0].id.as_deref(), Some("atom_1.0.feed.entry[0]^id"));
+        assert_eq!(
+            r.posts[0].author.as_deref(),
+            Some("atom_1.0.feed.entry[0].author.name")
+        );
+        assert_eq!(
+            r.posts[0].published.as_deref(),
+            Some("2000-01-01T01:00:00Z")
+        );
+        assert_eq!(r.posts[0].updated.as_deref(), Some("2000-01-01T00:00:00Z"));
+        assert_eq!(r.posts[0].date(), Some("2000-01-01T01:00:00Z"));
+        assert_eq!(
+            r.posts[0].summary.as_deref(),
+            Some("atom_1.0.feed.entry[0].summary")
+        );
+        assert_eq!(
+            r.posts[0].content.as_deref(),
+            Some("atom_1.0.feed.entry[0].content[0]")
+        );
+        assert_eq!(
+            r.posts[1].title.as_deref(),
+            Some("atom_1.0.feed.entry[1].title")
+        );
+        assert_eq!(r.posts[1].id.as_deref(), Some("atom_1.0.feed.entry[1]^id"));
+        assert_eq!(
+            r.posts[1].link.as_deref(),
+            Some("http://example.com/blog/entry2")
+        );
+        assert_eq!(
+            r.posts[1].thumbnail().map(|a| a.url.as_str()),
+            Some("http://example.com/blog/enclosure2.gif")
+        );
+    }
+
+    #[test]
+    fn rss_enclosure_test() {
+        use crate::fetch::media::AttachmentKind;
+
+        let s: &[u8] = include_bytes!("../../test/data/rss_2.0.xml");
+        let r = Feed::from_xml_with_buf(Cursor::new(s)).unwrap();
+        let attachments = &r.posts[0].attachments;
+        assert_eq!(attachments.len(), 2);
+        assert_eq!(attachments[0].kind, AttachmentKind::Enclosure);
+        assert_eq!(
+            attachments[0].url,
+            "rss_2.0.channel.item[0].enclousure[0]^url"
+        );
+        assert_eq!(
+            attachments[0].mime_type.as_deref(),
+            Some("rss_2.0.channel.item[0].enclousure[0]^type")
+        );
+        assert_eq!(attachments[0].length, Some(100));
+    }
+
+    #[test]
+    fn podcast_test() {
+        use crate::fetch::media::AttachmentKind;
+
+        let s: &[u8] = include_bytes!("../../test/data/podcast.xml");
+        let r = Feed::from_xml_with_buf(Cursor::new(s)).unwrap();
+        assert_eq!(r.posts.len(), 2);
+
+        let episode = &r.posts[0];
+        assert_eq!(episode.title.as_deref(), Some("podcast.item[0].title"));
+        let audio = episode.media().unwrap();
+        assert_eq!(audio.url, "https://example.com/podcast/ep1.mp3");
+        assert_eq!(audio.mime_type.as_deref(), Some("audio/mpeg"));
+        assert_eq!(audio.length, Some(34216300));
+        assert_eq!(audio.duration, Some(3723));
+        assert_eq!(
+            episode.thumbnail().map(|a| a.url.as_str()),
+            Some("https://example.com/podcast/ep1.jpg")
+        );
+
+        // media:group
+        let video = &r.posts[1];
+        assert_eq!(video.attachments.len(), 3);
+        let media = video.media().unwrap();
+        assert_eq!(media.kind, AttachmentKind::Media);
+        assert_eq!(media.url, "https://example.com/video/1080.mp4");
+        assert_eq!(media.width, Some(1920));
+        assert_eq!(media.height, Some(1080));
+        assert_eq!(
+            video.thumbnail().map(|a| a.url.as_str()),
+            Some("https://example.com/video/thumb.jpg?a=1&b=2")
+        );
+    }
+
+    #[test]
+    fn atom_xhtml_content_test() {
+        let s = r#"<feed xmlns="http://www.w3.org/2005/Atom">
+            <title>t</title>
+            <entry>
+                <title>e</title>
+                <content type="xhtml"><div xmlns="http://www.w3.org/1999/xhtml"><p>a &amp; b</p><p>c</p></div></content>
+                <author><name>n</name><email>e@example.com</email></author>
+            </entry>
+        </feed>"#;
+        let r = Feed::from_str(s).unwrap();
+        assert_eq!(r.posts[0].content.as_deref(), Some("a & b c"));
+        assert_eq!(r.posts[0].author.as_deref(), Some("n"));
+    }
+
+    #[test]
+    fn rss_content_encoded_test() {
+        let s: &[u8] = include_bytes!("../../test/data/rss_2.0.xml");
+        let r = Feed::from_xml_with_buf(Cursor::new(s)).unwrap();
+        assert_eq!(
+            r.posts[0].author.as_deref(),
+            Some("rss_2.0.channel.item[0].author")
+        );
+        assert_eq!(
+            r.posts[0].content.as_deref(),
+            Some("rss_2.0.channel.item[0].content")
+        );
     }
 
     #[test]
@@ -292,5 +638,22 @@ mod test {
                 "https://github.com/sdttttt/ksbot/commit/f0b049e0ed0d936c82010fbe53d34f4ecce99e9d"
             )
         );
+        assert_eq!(r.posts.len(), 20);
+        assert_eq!(
+            r.posts[0].id.as_deref(),
+            Some("tag:github.com,2008:Grit::Commit/f0b049e0ed0d936c82010fbe53d34f4ecce99e9d")
+        );
+        assert_eq!(r.posts[0].author.as_deref(), Some("sdttttt"));
+        let thumbnail = r.posts[0].thumbnail().unwrap();
+        assert_eq!(
+            thumbnail.url,
+            "https://avatars.githubusercontent.com/u/42728902?s=30&v=4"
+        );
+        assert_eq!(thumbnail.width, Some(30));
+        assert_eq!(r.posts[0].updated.as_deref(), Some("2023-04-13T07:44:23Z"));
+        assert_eq!(
+            r.posts[0].content.as_deref(),
+            Some("<pre style='white-space:pre-wrap;width:81ex'>Update README.md</pre>")
+        );
     }
 }
@@ -0,0 +1,293 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::redirect::{Attempt, Policy};
+use reqwest::StatusCode;
+use thiserror::Error;
+use url::{Host, Url};
+
+// 最多跟随几次重定向
+pub const MAX_REDIRECTS: usize = 5;
+
+/// 订阅源指向了不允许访问的地址
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("address {addr} of {host} is not allowed")]
+pub struct BlockedAddress {
+    pub host: String,
+    pub addr: IpAddr,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("invalid network: {0}")]
+pub struct InvalidNetwork(String);
+
+/// 一个网段, 比如 `10.1.0.0/16`, `fd00::/8`, 也可以是单个地址
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = InvalidNetwork;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || InvalidNetwork(s.to_owned());
+
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse::<u8>().map_err(|_| invalid())?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+
+        Ok(Network { addr, prefix })
+    }
+}
+
+/// 环回, 链路本地, 内网这些地址默认都不允许访问, 云服务器的元数据接口也在里面
+pub fn is_internal(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_internal_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => is_internal_v4(&v4),
+            None => is_internal_v6(ip),
+        },
+    }
+}
+
+fn is_internal_v4(ip: &Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        // 0.0.0.0/8
+        || a == 0
+        // 100.64.0.0/10, 运营商级 NAT, 有的云服务器元数据接口在这里
+        || (a == 100 && (b & 0xc0) == 64)
+}
+
+fn is_internal_v6(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // fc00::/7 唯一本地地址
+        || (first & 0xfe00) == 0xfc00
+        // fe80::/10 链路本地地址
+        || (first & 0xffc0) == 0xfe80
+}
+
+/// 检查地址是否允许访问, `allowed` 里的网段不受限制
+pub fn check_addr(host: &str, addr: IpAddr, allowed: &[Network]) -> Result<(), BlockedAddress> {
+    if is_internal(&addr) && !allowed.iter().any(|n| n.contains(&addr)) {
+        return Err(BlockedAddress {
+            host: host.to_owned(),
+            addr,
+        });
+    }
+    Ok(())
+}
+
+/// URL 的主机是 IP 地址时直接检查, 域名留给 [`GuardedResolver`] 解析的时候检查
+pub fn check_url(url: &Url, allowed: &[Network]) -> Result<(), BlockedAddress> {
+    let addr = match url.host() {
+        Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+        Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+        _ => return Ok(()),
+    };
+    check_addr(&addr.to_string(), addr, allowed)
+}
+
+/// 解析域名的时候去掉不允许访问的地址, 一个都不剩就报错
+pub struct GuardedResolver {
+    allowed: Arc<Vec<Network>>,
+}
+
+impl GuardedResolver {
+    pub fn new(allowed: Arc<Vec<Network>>) -> Self {
+        Self { allowed }
+    }
+}
+
+impl Resolve for GuardedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allowed = self.allowed.clone();
+        Box::pin(async move {
+            let host = name.as_str();
+            let mut blocked = None;
+            let addrs = tokio::net::lookup_host((host, 0))
+                .await?
+                .filter(|addr| match check_addr(host, addr.ip(), &allowed) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        blocked = Some(e);
+                        false
+                    }
+                })
+                .collect::<Vec<SocketAddr>>();
+
+            match blocked {
+                Some(e) if addrs.is_empty() => Err(e.into()),
+                _ => Ok(Box::new(addrs.into_iter()) as Addrs),
+            }
+        })
+    }
+}
+
+pub fn is_permanent_redirect(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::MOVED_PERMANENTLY | StatusCode::PERMANENT_REDIRECT
+    )
+}
+
+/// 代理的地址由用户设置, 域名也要先解析出来检查.
+/// 解析失败的话交给后面的请求报错.
+pub async fn check_proxy(proxy: &Url, allowed: &[Network]) -> Result<(), BlockedAddress> {
+    check_url(proxy, allowed)?;
+
+    let host = match proxy.host() {
+        Some(Host::Domain(host)) => host,
+        _ => return Ok(()),
+    };
+    let port = proxy.port_or_known_default().unwrap_or(0);
+    if let Ok(addrs) = tokio::net::lookup_host((host, port)).await {
+        for addr in addrs {
+            check_addr(host, addr.ip(), allowed)?;
+        }
+    }
+    Ok(())
+}
+
+/// 跟随重定向之前也要检查一遍新地址.
+/// 永久重定向 (301, 308) 不跟随, 留给调用方记下新地址之后自己跟随.
+pub fn redirect_policy(allowed: Arc<Vec<Network>>) -> Policy {
+    Policy::custom(move |attempt: Attempt| {
+        if is_permanent_redirect(attempt.status()) {
+            return attempt.stop();
+        }
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            return attempt.error("too many redirects");
+        }
+        match check_url(attempt.url(), &allowed) {
+            Ok(()) => attempt.follow(),
+            Err(e) => attempt.error(e),
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_network() {
+        let net: Network = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(!net.contains(&"10.2.0.1".parse().unwrap()));
+        assert!(!net.contains(&"::1".parse().unwrap()));
+
+        let single: Network = "192.168.1.10".parse().unwrap();
+        assert!(single.contains(&"192.168.1.10".parse().unwrap()));
+        assert!(!single.contains(&"192.168.1.11".parse().unwrap()));
+
+        let v6: Network = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(&"fd12::1".parse().unwrap()));
+
+        let all: Network = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(&"127.0.0.1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("example.com".parse::<Network>().is_err());
+        assert!("10.0.0.0/x".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn test_is_internal() {
+        for ip in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.5.4",
+            "192.168.0.1",
+            "169.254.169.254",
+            "100.100.100.200",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(is_internal(&ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["1.1.1.1", "172.32.0.1", "100.128.0.1", "2606:4700::1111"] {
+            assert!(!is_internal(&ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_check_url() {
+        let allowed = vec!["10.1.0.0/16".parse().unwrap()];
+        let check = |url: &str| check_url(&Url::parse(url).unwrap(), &allowed);
+
+        assert!(check("http://127.0.0.1:8080/feed").is_err());
+        assert!(check("http://[::1]/feed").is_err());
+        assert!(check("http://169.254.169.254/latest/meta-data/").is_err());
+        // 域名要等解析的时候再检查
+        assert!(check("http://localhost/feed").is_ok());
+        assert!(check("http://10.1.2.3/feed").is_ok());
+        assert!(check("https://1.1.1.1/feed").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_guarded_resolver() {
+        let resolver = GuardedResolver::new(Arc::new(vec![]));
+        let err = resolver
+            .resolve(Name::from_str("localhost").unwrap())
+            .await
+            .err()
+            .unwrap();
+        let blocked = err.downcast_ref::<BlockedAddress>().unwrap();
+        assert_eq!(blocked.host, "localhost");
+
+        let allowed = vec!["127.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()];
+        let resolver = GuardedResolver::new(Arc::new(allowed));
+        let addrs = resolver
+            .resolve(Name::from_str("localhost").unwrap())
+            .await
+            .unwrap()
+            .collect::<Vec<SocketAddr>>();
+        assert!(addrs.iter().all(|a| a.ip().is_loopback()));
+    }
+}
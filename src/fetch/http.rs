@@ -1,33 +1,434 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use super::{feed::Feed, FromXmlWithBufRead};
-use once_cell::sync::OnceCell;
+use super::feed::Feed;
+use super::guard::{self, BlockedAddress, GuardedResolver, Network};
+use super::limiter::{self, Limiter, Permit, RateLimited};
+use super::options::{FetchOptions, PROXY_DIRECT};
+use super::source::{self, FeedSource, SourceError};
+use super::watch::{PageWatch, WatchError};
+use crate::conf::FetchConfig;
+use once_cell::sync::{Lazy, OnceCell};
+use reqwest::header::{self, HeaderMap};
+use reqwest::{Response, StatusCode};
 use thiserror::Error;
 
 static RESP_SIZE_LIMIT: OnceCell<u64> = OnceCell::new();
 static CLIENT: OnceCell<reqwest::Client> = OnceCell::new();
+// 配置文件里的全局代理
+static GLOBAL_PROXY: OnceCell<Option<String>> = OnceCell::new();
+// 配置文件里额外信任的 CA 证书
+static CA_CERTS: OnceCell<Vec<reqwest::Certificate>> = OnceCell::new();
+// 允许访问的内网网段
+static ALLOWED_NETWORKS: OnceCell<Arc<Vec<Network>>> = OnceCell::new();
+// 并发和每个主机的请求频率限制
+static LIMITER: OnceCell<Limiter> = OnceCell::new();
+
+// 订阅源单独设置了代理或者跳过证书验证时用的客户端
+static OPTION_CLIENTS: Lazy<Mutex<HashMap<ClientKey, reqwest::Client>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+const PEM_CERT_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_CERT_END: &str = "-----END CERTIFICATE-----";
 
 const DEFAULT_RESP_SIZE_LIMIT: u64 = 1024 * 1024 * 4; // 4MB
 
 #[derive(Error, Debug)]
 pub enum FeedError {
-    #[error("network error")]
+    #[error("network error: {0}")]
     Network(#[from] reqwest::Error),
-    #[error("feed parsing failed")]
-    Parsing(#[from] quick_xml::Error),
-    #[error("feed is too large")]
+    #[error("malformed feed at byte {position}: {source}")]
+    Malformed {
+        position: usize,
+        source: quick_xml::Error,
+    },
+    #[error("json feed parsing failed: {0}")]
+    JsonParsing(#[from] serde_json::Error),
+    #[error("feed is missing required element <{0}>")]
+    MissingElement(&'static str),
+    #[error("not a feed, root element is <{0}>")]
+    NotFeed(String),
+    #[error("feed is empty")]
+    Empty,
+    #[error("feed is too large (limit {0} bytes)")]
     TooLarge(u64),
+    #[error("this is a web page, not a feed")]
+    HtmlPage,
+    #[error("no feed found on this page")]
+    NoFeedFound,
+    #[error("TLS certificate rejected: {0}")]
+    Certificate(String),
+    #[error("{0}")]
+    Blocked(#[from] BlockedAddress),
+    #[error("{0}")]
+    Source(#[from] SourceError),
+    #[error("{0}")]
+    Watch(#[from] WatchError),
+    #[error("{0}")]
+    RateLimited(#[from] RateLimited),
+    #[error("invalid RSSHub route: {0}")]
+    InvalidRoute(String),
+    #[error("too many redirects")]
+    TooManyRedirects,
+}
+
+#[derive(Error, Debug)]
+pub enum CaFileError {
+    #[error("cannot read CA file {0}: {1}")]
+    Io(String, std::io::Error),
+    #[error("no certificate found in CA file {0}")]
+    Empty(String),
+    #[error("invalid certificate #{1} in CA file {0}: {2}")]
+    Invalid(String, usize, reqwest::Error),
+}
+
+// 拉取用的客户端设置, 同时也是客户端缓存的 key
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ClientKey {
+    // 代理地址, `direct` 表示不走代理
+    proxy: Option<String>,
+    // 不验证 TLS 证书
+    insecure: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum FeedFormat {
+    Xml,
+    Json,
+    Html,
+}
+
+/// 先看 `Content-Type`, 不可靠的话再看正文的第一个非空白字符
+fn sniff_format(content_type: Option<&str>, body: &[u8]) -> FeedFormat {
+    if let Some(ct) = content_type {
+        let mime = ct.split(';').next().unwrap_or_default().trim();
+        if mime.eq_ignore_ascii_case("application/feed+json")
+            || mime.eq_ignore_ascii_case("application/json")
+        {
+            return FeedFormat::Json;
+        }
+    }
+
+    // 跳过 UTF-8 BOM
+    let body = body.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(body);
+    let start = body
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(body.len());
+    let head = &body[start..body.len().min(start + 16)];
+
+    // 有的订阅源会把 Content-Type 写成 text/html, 所以只看正文
+    if head.first() == Some(&b'{') {
+        FeedFormat::Json
+    } else if starts_with_ignore_case(head, b"<!doctype html")
+        || starts_with_ignore_case(head, b"<html")
+    {
+        FeedFormat::Html
+    } else {
+        FeedFormat::Xml
+    }
+}
+
+fn starts_with_ignore_case(s: &[u8], prefix: &[u8]) -> bool {
+    s.len() >= prefix.len() && s[..prefix.len()].eq_ignore_ascii_case(prefix)
 }
 
-pub async fn pull_feed(url: &str) -> Result<Feed, FeedError> {
-    let mut resp = CLIENT
+/// 条件请求 (Conditional GET) 用到的校验信息
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheValidator {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl CacheValidator {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let get = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_owned())
+        };
+
+        Self {
+            etag: get(header::ETAG),
+            last_modified: get(header::LAST_MODIFIED),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FeedResponse {
+    Modified(Box<Feed>, CacheValidator),
+    // 304, 订阅源没有变化, 不需要解析
+    NotModified,
+}
+
+/// 解析订阅源的正文, 编码和格式 (XML / JSON) 都会自动识别
+pub fn parse_feed(content_type: Option<&str>, body: Vec<u8>) -> Result<Feed, FeedError> {
+    let body = super::encoding::decode_to_utf8(content_type, body);
+    let feed = match sniff_format(content_type, &body) {
+        FeedFormat::Json => Feed::from_json_slice(&body)?,
+        FeedFormat::Xml => Feed::from_xml_slice(&body)?,
+        FeedFormat::Html => return Err(FeedError::HtmlPage),
+    };
+
+    if feed.title.trim().is_empty() {
+        return Err(FeedError::MissingElement("title"));
+    }
+    Ok(feed)
+}
+
+/// 拉取订阅源, 除了 HTTP 还可以是配置文件里允许的本地文件和命令, 以及被监控的网页
+pub async fn pull_feed(url: &str, options: &FetchOptions) -> Result<Feed, FeedError> {
+    let source = FeedSource::from_url(url);
+    let url = match source {
+        FeedSource::Http(url) => url,
+        FeedSource::Watch(ref watch) => &watch.url,
+        ref local => return read_local_feed(local).await,
+    };
+    let (resp, _, _permit) = send(url, &CacheValidator::default(), options).await?;
+    match source {
+        FeedSource::Watch(ref watch) => read_watch(resp, watch).await,
+        _ => read_feed(resp).await,
+    }
+}
+
+/// 按选择器或者正则把网页变成订阅源
+async fn read_watch(resp: Response, watch: &PageWatch) -> Result<Feed, FeedError> {
+    let page_url = resp.url().clone();
+    let (content_type, body) = read_body(resp).await?;
+    let body = super::encoding::decode_to_utf8(content_type.as_deref(), body);
+    Ok(watch.extract(&String::from_utf8_lossy(&body), &page_url)?)
+}
+
+/// 本地订阅源没有 `Content-Type`, 格式只能从内容里看
+pub(super) async fn read_local_feed(source: &FeedSource<'_>) -> Result<Feed, FeedError> {
+    let size_limit = *RESP_SIZE_LIMIT
         .get()
-        .expect("CLIENT not initialized")
-        .get(url)
+        .expect("RESP_SIZE_LIMIT not initialized");
+    let body = source::read_local(source, size_limit).await?;
+    parse_feed(None, body)
+}
+
+/// 下载一个文件 (比如用户上传的 OPML), 和拉取订阅源一样受地址检查和大小限制
+pub async fn download(url: &str, options: &FetchOptions) -> Result<Vec<u8>, FeedError> {
+    let (resp, _, _permit) = send(url, &CacheValidator::default(), options).await?;
+    let (_, body) = read_body(resp).await?;
+    Ok(body)
+}
+
+/// 提交表单 (比如向 WebSub hub 订阅), 和拉取订阅源一样受地址检查, 代理和证书配置的限制
+pub async fn post_form(url: &str, form: &[(&str, &str)]) -> Result<(), FeedError> {
+    let allowed = allowed_networks();
+    // 域名在连接的时候检查, 这里只能先检查 IP 地址
+    if let Ok(ref u) = url::Url::parse(url) {
+        guard::check_url(u, &allowed)?;
+    }
+
+    client_for(&FetchOptions::default())?
+        .post(url)
+        .form(form)
         .send()
-        .await?
+        .await
+        .map_err(request_error)?
         .error_for_status()?;
+    Ok(())
+}
 
+/// 带上 `If-None-Match` / `If-Modified-Since` 拉取订阅源, 服务端返回 304 时不会读取正文
+///
+/// Returns:
+///
+/// 拉取结果, 以及订阅源永久重定向 (301, 308) 到的新地址. 本地订阅源每次都会读取.
+pub async fn pull_feed_if_modified(
+    url: &str,
+    validator: &CacheValidator,
+    options: &FetchOptions,
+) -> Result<(FeedResponse, Option<String>), FeedError> {
+    let source = FeedSource::from_url(url);
+    let (url, watch) = match source {
+        FeedSource::Http(url) => (url, None),
+        FeedSource::Watch(ref watch) => (watch.url.as_str(), Some(watch)),
+        ref local => {
+            let feed = read_local_feed(local).await?;
+            let resp = FeedResponse::Modified(Box::new(feed), CacheValidator::default());
+            return Ok((resp, None));
+        }
+    };
+    let (resp, moved_to, _permit) = send(url, validator, options).await?;
+    // 监控的网页搬家了, 新的订阅地址也要带上选择器
+    let moved_to = match watch {
+        Some(watch) => moved_to.map(|url| {
+            PageWatch {
+                url,
+                extractor: watch.extractor.to_owned(),
+            }
+            .to_url()
+        }),
+        None => moved_to,
+    };
+    if resp.status() == StatusCode::NOT_MODIFIED {
+        return Ok((FeedResponse::NotModified, moved_to));
+    }
+
+    let validator = CacheValidator::from_headers(resp.headers());
+    let feed = match watch {
+        Some(watch) => read_watch(resp, watch).await?,
+        None => read_feed(resp).await?,
+    };
+    Ok((FeedResponse::Modified(Box::new(feed), validator), moved_to))
+}
+
+/// 发送请求, 自己跟随永久重定向, 临时重定向交给 reqwest.
+/// RSSHub 路由会先展开成配置的实例上的地址.
+///
+/// 每一次请求都要先排队拿到 [`Permit`], 服务端返回 429 或者 503 的话按 `Retry-After` 暂停请求这个主机.
+/// 凭据只发给和订阅地址的协议, 主机, 端口都一样的地址, 重定向到别的站点就不带了.
+///
+/// Returns:
+///
+/// 响应, 以及永久重定向到的新地址. 中间经过了临时重定向的话就不算订阅源搬家了,
+/// RSSHub 路由也不会搬家, 换实例只需要改配置.
+/// 读完正文之前要拿着 [`Permit`].
+pub(super) async fn send(
+    url: &str,
+    validator: &CacheValidator,
+    options: &FetchOptions,
+) -> Result<(Response, Option<String>, Permit), FeedError> {
+    let allowed = allowed_networks();
+    if let Some(proxy) = options.proxy.as_deref().filter(|p| *p != PROXY_DIRECT) {
+        if let Ok(proxy) = url::Url::parse(proxy) {
+            guard::check_proxy(&proxy, &allowed).await?;
+        }
+    }
+
+    let client = client_for(options)?;
+    let is_route = super::rsshub::is_route(url);
+    let mut target = match is_route {
+        true => super::rsshub::expand_with_config(url)
+            .ok_or_else(|| FeedError::InvalidRoute(url.to_owned()))?
+            .to_string(),
+        false => url.to_owned(),
+    };
+    // 展开的地址里可能有访问密钥, 报错的时候不能带上
+    let hide_url = |e: reqwest::Error| match is_route {
+        true => e.without_url(),
+        false => e,
+    };
+    let origin = url::Url::parse(&target).ok().map(|u| u.origin());
+    let without_credentials = options.public();
+    let mut moved_to = None;
+    let mut all_permanent = !is_route;
+    for _ in 0..=guard::MAX_REDIRECTS {
+        // 域名在连接的时候检查, 这里只能先检查 IP 地址
+        let target_url = url::Url::parse(&target).ok();
+        if let Some(ref u) = target_url {
+            guard::check_url(u, &allowed)?;
+        }
+
+        let host = target_url.as_ref().map(host_key).unwrap_or_default();
+        let permit = LIMITER
+            .get()
+            .expect("LIMITER not initialized")
+            .acquire(&host)
+            .await?;
+
+        let same_origin = match (&origin, &target_url) {
+            (Some(o), Some(u)) => o.is_tuple() && *o == u.origin(),
+            _ => false,
+        };
+        let mut req = match same_origin {
+            true => options.apply(client.get(&target)),
+            false => without_credentials.apply(client.get(&target)),
+        };
+        if let Some(ref etag) = validator.etag {
+            req = req.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(ref last_modified) = validator.last_modified {
+            req = req.header(header::IF_MODIFIED_SINCE, last_modified);
+        }
+
+        let resp = req.send().await.map_err(|e| request_error(hide_url(e)))?;
+        if limiter::is_rate_limited(resp.status()) {
+            let limited = LIMITER.get().expect("LIMITER not initialized").back_off(
+                &host_key(resp.url()),
+                resp.status(),
+                resp.headers(),
+            );
+            if let Some(limited) = limited {
+                return Err(limited.into());
+            }
+        }
+
+        let location = resp
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .and_then(|l| resp.url().join(l).ok());
+        let location = match location {
+            Some(l) if guard::is_permanent_redirect(resp.status()) => l.to_string(),
+            _ => return Ok((resp.error_for_status().map_err(hide_url)?, moved_to, permit)),
+        };
+
+        // reqwest 自动跟随了临时重定向, 之后的永久重定向就不算订阅源搬家了
+        if target_url.as_ref() != Some(resp.url()) {
+            all_permanent = false;
+        }
+        if all_permanent {
+            moved_to = Some(location.to_owned());
+        }
+        target = location;
+    }
+
+    Err(FeedError::TooManyRedirects)
+}
+
+// 主机和端口, 同一个主机的不同端口当成不同的服务
+fn host_key(url: &url::Url) -> String {
+    format!(
+        "{}:{}",
+        url.host_str().unwrap_or_default(),
+        url.port_or_known_default().unwrap_or_default()
+    )
+}
+
+/// 证书验证失败的话单独拿出来, 不然只能看到一句 `error trying to connect`
+fn request_error(err: reqwest::Error) -> FeedError {
+    // 解析域名或者重定向的时候被拦下来了
+    let mut source = std::error::Error::source(&err);
+    while let Some(e) = source {
+        if let Some(blocked) = e.downcast_ref::<BlockedAddress>() {
+            return FeedError::Blocked(blocked.clone());
+        }
+        source = e.source();
+    }
+
+    if err.is_connect() {
+        let mut source = std::error::Error::source(&err);
+        while let Some(e) = source {
+            let msg = e.to_string();
+            if msg.contains("certificate") {
+                let reason = msg.trim_start_matches("error trying to connect: ");
+                return FeedError::Certificate(reason.to_owned());
+            }
+            source = e.source();
+        }
+    }
+    FeedError::Network(err)
+}
+
+pub(super) async fn read_feed(resp: Response) -> Result<Feed, FeedError> {
+    let (content_type, body) = read_body(resp).await?;
+    parse_feed(content_type.as_deref(), body)
+}
+
+/// 读取正文, 超过大小限制就放弃
+///
+/// Returns:
+///
+/// `Content-Type` 和正文
+pub(super) async fn read_body(mut resp: Response) -> Result<(Option<String>, Vec<u8>), FeedError> {
     let size_limit = *RESP_SIZE_LIMIT
         .get()
         .expect("RESP_SIZE_LIMIT not initialized");
@@ -38,48 +439,593 @@ pub async fn pull_feed(url: &str) -> Result<Feed, FeedError> {
         }
     }
 
-    let rss = {
-        let mut buf = vec![];
-        while let Some(bytes) = resp.chunk().await? {
-            if buf.len() + bytes.len() > size_limit as usize {
-                return Err(FeedError::TooLarge(size_limit));
-            }
-            buf.extend_from_slice(&bytes);
+    let content_type = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_owned());
+
+    let mut buf = vec![];
+    while let Some(bytes) = resp.chunk().await? {
+        if buf.len() + bytes.len() > size_limit as usize {
+            return Err(FeedError::TooLarge(size_limit));
         }
-        super::Feed::from_xml_with_buf(std::io::Cursor::new(buf))?
+        buf.extend_from_slice(&bytes);
+    }
+
+    Ok((content_type, buf))
+}
+
+/// 没有单独设置代理的订阅源用全局的客户端, 否则按代理地址缓存一个客户端
+fn client_for(options: &FetchOptions) -> Result<reqwest::Client, FeedError> {
+    if options.proxy.is_none() && !options.is_insecure() {
+        return Ok(CLIENT.get().expect("CLIENT not initialized").clone());
+    }
+
+    // 订阅源没有设置代理的话还是走全局代理
+    let key = ClientKey {
+        proxy: options
+            .proxy
+            .to_owned()
+            .or_else(|| GLOBAL_PROXY.get().cloned().flatten()),
+        insecure: options.is_insecure(),
     };
 
-    Ok(rss)
+    let mut clients = OPTION_CLIENTS.lock().unwrap();
+    if let Some(client) = clients.get(&key) {
+        return Ok(client.clone());
+    }
+
+    let ca_certs = CA_CERTS.get().map(|c| c.as_slice()).unwrap_or_default();
+    let client = build_client(&key, ca_certs, allowed_networks())?;
+    clients.insert(key, client.clone());
+    Ok(client)
 }
 
-pub fn init_rss_client(max_feed_size: Option<u64>) {
-    let client_builder = reqwest::Client::builder()
+fn allowed_networks() -> Arc<Vec<Network>> {
+    ALLOWED_NETWORKS.get().cloned().unwrap_or_default()
+}
+
+/// 证书默认都要验证, 除了系统的根证书还会信任 `ca_certs`.
+///
+/// 不走代理的时候, 域名解析出来的内网地址只有在 `allowed` 里才能访问.
+/// 走代理的时候域名由代理解析, 只能检查 IP 地址.
+fn build_client(
+    key: &ClientKey,
+    ca_certs: &[reqwest::Certificate],
+    allowed: Arc<Vec<Network>>,
+) -> Result<reqwest::Client, reqwest::Error> {
+    let mut client_builder = reqwest::Client::builder()
         .timeout(Duration::from_secs(16))
-        .redirect(reqwest::redirect::Policy::limited(5))
+        .redirect(guard::redirect_policy(allowed.clone()))
         .user_agent("Mozilla/5.0")
-        .danger_accept_invalid_certs(true);
+        .danger_accept_invalid_certs(key.insecure);
+
+    for cert in ca_certs {
+        client_builder = client_builder.add_root_certificate(cert.clone());
+    }
+
+    client_builder = match key.proxy.as_deref() {
+        Some(PROXY_DIRECT) => client_builder
+            .no_proxy()
+            .dns_resolver(Arc::new(GuardedResolver::new(allowed))),
+        Some(proxy) => client_builder.proxy(reqwest::Proxy::all(proxy)?),
+        None => client_builder.dns_resolver(Arc::new(GuardedResolver::new(allowed))),
+    };
 
-    let client = client_builder.build().unwrap();
+    client_builder.build()
+}
+
+/// 读取 PEM 格式的 CA 证书文件, 一个文件里可以有多个证书.
+///
+/// Arguments:
+///
+/// * `path`: 证书文件路径
+///
+/// Returns:
+///
+/// 文件里的所有证书
+pub fn load_ca_file(path: &str) -> Result<Vec<reqwest::Certificate>, CaFileError> {
+    let pem = std::fs::read_to_string(path).map_err(|e| CaFileError::Io(path.to_owned(), e))?;
+
+    // reqwest 一次只能解析一个证书, 只好自己拆开
+    let mut certs = vec![];
+    let mut rest = pem.as_str();
+    while let Some(begin) = rest.find(PEM_CERT_BEGIN) {
+        let end = match rest[begin..].find(PEM_CERT_END) {
+            Some(end) => begin + end + PEM_CERT_END.len(),
+            None => break,
+        };
+
+        let cert = reqwest::Certificate::from_pem(&rest.as_bytes()[begin..end])
+            .map_err(|e| CaFileError::Invalid(path.to_owned(), certs.len() + 1, e))?;
+        certs.push(cert);
+        rest = &rest[end..];
+    }
+
+    if certs.is_empty() {
+        return Err(CaFileError::Empty(path.to_owned()));
+    }
+    Ok(certs)
+}
+
+/// 测试用的客户端, 测试里的服务都在本机上, 只初始化一次
+#[cfg(test)]
+pub fn init_test_client() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| {
+        init_rss_client(FetchConfig {
+            allowed_networks: vec!["127.0.0.0/8".parse().unwrap()],
+            // 测试里的请求都发给本机, 不用等. 每个主机只许一个请求,
+            // 拿着许可再请求同一个主机的地方会在测试里卡住
+            limits: crate::conf::FetchLimits {
+                per_host_delay: Duration::ZERO,
+                per_host_concurrent: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    });
+}
+
+pub fn init_rss_client(conf: FetchConfig) {
+    let ca_certs = match conf.ca_file {
+        Some(ref path) => load_ca_file(path).expect("CA 证书文件错误"),
+        None => vec![],
+    };
+    let key = ClientKey {
+        proxy: conf.proxy.to_owned(),
+        insecure: false,
+    };
+    let allowed = Arc::new(conf.allowed_networks);
+    let client = build_client(&key, &ca_certs, allowed.clone()).expect("全局代理配置错误");
 
     CLIENT.set(client).expect("CLIENT already initialized");
+    GLOBAL_PROXY
+        .set(conf.proxy)
+        .expect("GLOBAL_PROXY already initialized");
+    super::rsshub::init(conf.rsshub);
+    source::init(conf.sources);
+    CA_CERTS
+        .set(ca_certs)
+        .expect("CA_CERTS already initialized");
+    ALLOWED_NETWORKS
+        .set(allowed)
+        .expect("ALLOWED_NETWORKS already initialized");
+    LIMITER
+        .set(Limiter::new(conf.limits))
+        .map_err(|_| ())
+        .expect("LIMITER already initialized");
     RESP_SIZE_LIMIT
-        .set(max_feed_size.unwrap_or(DEFAULT_RESP_SIZE_LIMIT))
+        .set(conf.max_feed_size.unwrap_or(DEFAULT_RESP_SIZE_LIMIT))
         .expect("RESP_SIZE_LIMIT already initialized");
 }
 #[cfg(test)]
 mod test {
 
     use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
 
     fn setup() {
-        init_rss_client(None);
+        init_test_client();
+    }
+
+    // 一个很简陋的 HTTP 服务, 只回应一次请求, 带了 If-None-Match 就返回 304
+    async fn serve_once_with_etag(body: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut req = vec![0; 4096];
+                let n = socket.read(&mut req).await.unwrap();
+                let req = String::from_utf8_lossy(&req[..n]).to_lowercase();
+                let resp = if req.contains("if-none-match: \"v1\"") {
+                    b"HTTP/1.1 304 Not Modified\r\nContent-Length: 0\r\n\r\n".to_vec()
+                } else {
+                    let mut r = format!(
+                        "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nLast-Modified: Sun, 02 Apr 2023 10:00:00 GMT\r\nContent-Length: {}\r\n\r\n",
+                        body.len()
+                    )
+                    .into_bytes();
+                    r.extend_from_slice(body);
+                    r
+                };
+                socket.write_all(&resp).await.unwrap();
+            }
+        });
+        format!("http://{}/feed.xml", addr)
+    }
+
+    // 用自签名证书的 HTTPS 服务, 每次请求都返回同一个订阅源
+    async fn serve_tls(body: &'static [u8]) -> String {
+        let identity = native_tls::Identity::from_pkcs8(
+            include_bytes!("../../test/data/tls/cert.pem"),
+            include_bytes!("../../test/data/tls/key.pem"),
+        )
+        .unwrap();
+        let acceptor: tokio_native_tls::TlsAcceptor =
+            native_tls::TlsAcceptor::new(identity).unwrap().into();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                // 客户端拒绝证书的时候握手会失败
+                let mut socket = match acceptor.accept(socket).await {
+                    Ok(s) => s,
+                    Err(_) => continue,
+                };
+                let mut req = vec![0; 4096];
+                let _ = socket.read(&mut req).await.unwrap();
+                let mut resp = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .into_bytes();
+                resp.extend_from_slice(body);
+                socket.write_all(&resp).await.unwrap();
+                socket.shutdown().await.unwrap();
+            }
+        });
+        format!("https://localhost:{}/feed.xml", addr.port())
+    }
+
+    #[tokio::test]
+    async fn test_tls_verification() {
+        setup();
+        let url = serve_tls(include_bytes!("../../test/data/rss_2.0.xml")).await;
+
+        let err = pull_feed(&url, &FetchOptions::default()).await.unwrap_err();
+        assert!(matches!(err, FeedError::Certificate(_)), "{:?}", err);
+
+        // 订阅源单独跳过证书验证
+        let mut options = FetchOptions::default();
+        options.set("insecure", Some("true")).unwrap();
+        let feed = pull_feed(&url, &options).await.unwrap();
+        assert_eq!(feed.title, "rss_2.0.channel.title");
+
+        // 信任自签名的证书
+        let ca_certs = load_ca_file("test/data/tls/cert.pem").unwrap();
+        let key = ClientKey {
+            proxy: None,
+            insecure: false,
+        };
+        let client = build_client(&key, &ca_certs, allowed_networks()).unwrap();
+        let resp = client.get(&url).send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    // 按路径重定向的 HTTP 服务: (路径, 状态码, 重定向到的地址), 其他路径都返回 `body`
+    async fn serve_redirects(
+        redirects: &'static [(&'static str, u16, &'static str)],
+        body: &'static [u8],
+    ) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut req = vec![0; 4096];
+                let n = socket.read(&mut req).await.unwrap();
+                let req = String::from_utf8_lossy(&req[..n]).to_string();
+                let path = req.split_whitespace().nth(1).unwrap_or_default();
+
+                let resp = match redirects.iter().find(|r| r.0 == path) {
+                    Some((_, status, location)) => format!(
+                        "HTTP/1.1 {} Redirect\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        status, location
+                    )
+                    .into_bytes(),
+                    None => {
+                        let mut r = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                            body.len()
+                        )
+                        .into_bytes();
+                        r.extend_from_slice(body);
+                        r
+                    }
+                };
+                socket.write_all(&resp).await.unwrap();
+            }
+        });
+        format!("http://127.0.0.1:{}", addr.port())
+    }
+
+    #[tokio::test]
+    async fn test_permanent_redirect() {
+        setup();
+        let base = serve_redirects(
+            &[
+                ("/old.xml", 301, "/new.xml"),
+                ("/older.xml", 308, "/old.xml"),
+                ("/temp.xml", 302, "/old.xml"),
+            ],
+            include_bytes!("../../test/data/rss_2.0.xml"),
+        )
+        .await;
+        let pull = |path: &str| {
+            let url = format!("{}{}", base, path);
+            async move {
+                pull_feed_if_modified(&url, &CacheValidator::default(), &FetchOptions::default())
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let (resp, moved_to) = pull("/old.xml").await;
+        assert!(matches!(resp, FeedResponse::Modified(..)));
+        assert_eq!(Some(format!("{}/new.xml", base)), moved_to);
+
+        // 连续的永久重定向, 直接搬到最后的地址
+        let (_, moved_to) = pull("/older.xml").await;
+        assert_eq!(Some(format!("{}/new.xml", base)), moved_to);
+
+        // 先临时重定向的不算搬家
+        let (resp, moved_to) = pull("/temp.xml").await;
+        assert!(matches!(resp, FeedResponse::Modified(..)));
+        assert_eq!(None, moved_to);
+
+        let (_, moved_to) = pull("/new.xml").await;
+        assert_eq!(None, moved_to);
+    }
+
+    // 记下收到的请求的 HTTP 服务, `/old.xml` 永久重定向到 `location`, 其他路径都返回 `body`
+    async fn serve_recording(
+        location: String,
+        body: &'static [u8],
+    ) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut req = vec![0; 4096];
+                let n = socket.read(&mut req).await.unwrap();
+                let req = String::from_utf8_lossy(&req[..n]).to_lowercase();
+                let resp = match req.starts_with("get /old.xml ") {
+                    true => format!(
+                        "HTTP/1.1 301 Moved Permanently\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        location
+                    )
+                    .into_bytes(),
+                    false => {
+                        let mut r = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                            body.len()
+                        )
+                        .into_bytes();
+                        r.extend_from_slice(body);
+                        r
+                    }
+                };
+                recorded.lock().unwrap().push(req);
+                socket.write_all(&resp).await.unwrap();
+            }
+        });
+        (format!("http://127.0.0.1:{}", addr.port()), requests)
+    }
+
+    #[tokio::test]
+    async fn test_redirect_credentials() {
+        setup();
+        let mut options = FetchOptions::default();
+        options.set("cookie", Some("session=secret")).unwrap();
+        options.set("bearer", Some("token")).unwrap();
+        options.set("header", Some("X-Token: abc")).unwrap();
+        options.set("ua", Some("ksbot-test")).unwrap();
+        let has_credentials = |req: &String| {
+            req.contains("session=secret")
+                || req.contains("authorization:")
+                || req.contains("x-token:")
+        };
+
+        // 同一个站点里搬家, 凭据照样带上
+        let (new_base, new_requests) = serve_recording(
+            "/feed.xml".to_owned(),
+            include_bytes!("../../test/data/rss_2.0.xml"),
+        )
+        .await;
+        let (_, moved_to) = pull_feed_if_modified(
+            &format!("{}/old.xml", new_base),
+            &CacheValidator::default(),
+            &options,
+        )
+        .await
+        .unwrap();
+        assert_eq!(moved_to, Some(format!("{}/feed.xml", new_base)));
+        assert!(new_requests.lock().unwrap().iter().all(has_credentials));
+        new_requests.lock().unwrap().clear();
+
+        // 搬到了别的站点 (端口不一样也算), 凭据不能跟过去
+        let (old_base, old_requests) = serve_recording(format!("{}/feed.xml", new_base), b"").await;
+        let (resp, moved_to) = pull_feed_if_modified(
+            &format!("{}/old.xml", old_base),
+            &CacheValidator::default(),
+            &options,
+        )
+        .await
+        .unwrap();
+        assert!(matches!(resp, FeedResponse::Modified(..)));
+        assert_eq!(moved_to, Some(format!("{}/feed.xml", new_base)));
+        assert!(old_requests.lock().unwrap().iter().all(has_credentials));
+
+        let new_requests = new_requests.lock().unwrap();
+        assert_eq!(new_requests.len(), 1);
+        assert!(!has_credentials(&new_requests[0]));
+        assert!(new_requests[0].contains("user-agent: ksbot-test"));
+    }
+
+    #[tokio::test]
+    async fn test_pull_watch() {
+        setup();
+        let base = serve_redirects(
+            &[("/old", 301, "/news")],
+            b"<html><head><title>News</title></head><body><ul><li><a href=\"/1\">one</a></li><li><a href=\"/2\">two</a></li></ul></body></html>",
+        )
+        .await;
+
+        let url = format!("watch:{}/news#css=ul+li", base);
+        let feed = pull_feed(&url, &FetchOptions::default()).await.unwrap();
+        assert_eq!(feed.title, "News");
+        assert_eq!(feed.posts.len(), 2);
+        assert_eq!(feed.posts[1].link, Some(format!("{}/2", base)));
+
+        // 网页搬家了, 新地址也要带上选择器
+        let url = format!("watch:{}/old#css=ul+li", base);
+        let (resp, moved_to) =
+            pull_feed_if_modified(&url, &CacheValidator::default(), &FetchOptions::default())
+                .await
+                .unwrap();
+        assert!(matches!(resp, FeedResponse::Modified(..)));
+        assert_eq!(moved_to, Some(format!("watch:{}/news#css=ul+li", base)));
+    }
+
+    #[tokio::test]
+    async fn test_blocked_address() {
+        setup();
+        let options = FetchOptions::default();
+
+        let err = pull_feed("http://169.254.169.254/latest/meta-data/", &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, FeedError::Blocked(_)), "{:?}", err);
+        let err = pull_feed("http://[::1]:8080/feed.xml", &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, FeedError::Blocked(_)), "{:?}", err);
+
+        // 重定向到内网
+        let base = serve_redirects(&[("/feed.xml", 302, "http://10.0.0.1/feed.xml")], b"").await;
+        let err = pull_feed(&format!("{}/feed.xml", base), &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, FeedError::Blocked(_)), "{:?}", err);
+
+        // 代理也不能指向内网
+        let mut proxied = FetchOptions::default();
+        proxied
+            .set("proxy", Some("http://192.168.1.1:3128"))
+            .unwrap();
+        let err = pull_feed("http://example.com/feed.xml", &proxied)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, FeedError::Blocked(_)), "{:?}", err);
+
+        // 域名解析出来是内网地址
+        let url = serve_once_with_etag(include_bytes!("../../test/data/rss_2.0.xml")).await;
+        let url = url.replace("127.0.0.1", "localhost");
+        let key = ClientKey {
+            proxy: None,
+            insecure: false,
+        };
+        let client = build_client(&key, &[], Arc::new(vec![])).unwrap();
+        let err = request_error(client.get(&url).send().await.unwrap_err());
+        assert!(matches!(err, FeedError::Blocked(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn test_load_ca_file() {
+        assert!(matches!(
+            load_ca_file("test/data/tls/key.pem"),
+            Err(CaFileError::Empty(_))
+        ));
+        assert!(matches!(
+            load_ca_file("test/data/tls/not_exists.pem"),
+            Err(CaFileError::Io(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_pull_feed_if_modified() {
+        setup();
+        let url = serve_once_with_etag(include_bytes!("../../test/data/rss_2.0.xml")).await;
+
+        let options = FetchOptions::default();
+        let validator = match pull_feed_if_modified(&url, &CacheValidator::default(), &options)
+            .await
+            .unwrap()
+        {
+            (FeedResponse::Modified(feed, validator), None) => {
+                assert_eq!(feed.title, "rss_2.0.channel.title");
+                validator
+            }
+            _ => panic!("first pull must be modified"),
+        };
+        assert_eq!(validator.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(
+            validator.last_modified.as_deref(),
+            Some("Sun, 02 Apr 2023 10:00:00 GMT")
+        );
+
+        let second = pull_feed_if_modified(&url, &validator, &options)
+            .await
+            .unwrap();
+        assert!(matches!(second, (FeedResponse::NotModified, None)));
+    }
+
+    #[test]
+    fn test_sniff_format() {
+        assert_eq!(
+            FeedFormat::Json,
+            sniff_format(Some("application/feed+json; charset=utf-8"), b"")
+        );
+        assert_eq!(
+            FeedFormat::Json,
+            sniff_format(Some("application/json"), b"")
+        );
+        assert_eq!(
+            FeedFormat::Json,
+            sniff_format(Some("text/plain"), b"\xEF\xBB\xBF \n {\"version\": \"\"}")
+        );
+        assert_eq!(
+            FeedFormat::Xml,
+            sniff_format(Some("application/rss+xml"), b"<?xml version=\"1.0\"?>")
+        );
+        assert_eq!(FeedFormat::Xml, sniff_format(None, b"  <rss></rss>"));
+        assert_eq!(
+            FeedFormat::Html,
+            sniff_format(Some("text/html"), b"\n<!DOCTYPE html><html>")
+        );
+        assert_eq!(FeedFormat::Html, sniff_format(None, b"<html lang=\"zh\">"));
+    }
+
+    #[test]
+    fn test_parse_feed() {
+        let json: &[u8] = include_bytes!("../../test/data/json_feed_1.1.json");
+        let r = parse_feed(Some("application/feed+json"), json.to_vec()).unwrap();
+        assert_eq!(&r.title, "json_feed_1.1.title");
+
+        let xml: &[u8] = include_bytes!("../../test/data/rss_2.0.xml");
+        let r = parse_feed(Some("application/xml"), xml.to_vec()).unwrap();
+        assert_eq!(&r.title, "rss_2.0.channel.title");
+
+        let missing_title: &[u8] = include_bytes!("../../test/data/missing_title.xml");
+        assert!(matches!(
+            parse_feed(None, missing_title.to_vec()),
+            Err(FeedError::MissingElement("title"))
+        ));
+        assert!(matches!(parse_feed(None, vec![]), Err(FeedError::Empty)));
+
+        let html = b"<!doctype html><html><head></head></html>".to_vec();
+        assert!(matches!(
+            parse_feed(Some("text/html"), html),
+            Err(FeedError::HtmlPage)
+        ));
     }
 
     #[ignore]
     #[tokio::test]
     async fn test_pull_feed_for_yystv() {
         setup();
-        let rss_chan = pull_feed("https://www.yystv.cn/rss/feed").await.unwrap();
+        let rss_chan = pull_feed("https://www.yystv.cn/rss/feed", &FetchOptions::default())
+            .await
+            .unwrap();
         assert_eq!("游研社", rss_chan.title);
     }
 
@@ -87,7 +1033,9 @@ mod test {
     #[tokio::test]
     async fn test_pull_feed_for_rsshub_3dm() {
         setup();
-        let rss_chan = pull_feed("https://rsshub.app/3dm/news").await.unwrap();
+        let rss_chan = pull_feed("https://rsshub.app/3dm/news", &FetchOptions::default())
+            .await
+            .unwrap();
         assert_eq!("游研社", rss_chan.title);
     }
 }
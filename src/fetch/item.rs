@@ -1,11 +1,13 @@
 use std::str::FromStr;
 
+use chrono::{DateTime, FixedOffset};
 use quick_xml::Result;
 use quick_xml::{events::Event, Reader};
 use serde::{Deserialize, Serialize};
 
 use super::buf::BufPool;
-use super::utils::{parse_atom_link, AtomLink, TextOrCData};
+use super::media::{media_kind, parse_duration, Attachment, AttachmentKind, MediaElements};
+use super::utils::{parse_atom_link, AtomLink, DeepText, Person, TextOrCData};
 use super::FromXmlWithReader;
 use super::FromXmlWithStr;
 use super::SkipThisElement;
@@ -21,6 +23,85 @@ pub struct FeedPost {
     pub link: Option<String>,
     pub author: Option<String>,
     pub category: Vec<String>,
+
+    // Atom 1.0
+    pub id: Option<String>,
+    pub published: Option<String>,
+    pub updated: Option<String>,
+    pub summary: Option<String>,
+    // Atom 的 content, 或者 RSS 的 content:encoded
+    pub content: Option<String>,
+
+    // enclosure, media:*, itunes:image
+    pub attachments: Vec<Attachment>,
+}
+
+impl FeedPost {
+    /// 文章的日期, 优先发布时间
+    pub fn date(&self) -> Option<&str> {
+        self.published
+            .as_deref()
+            .or(self.pub_date.as_deref())
+            .or(self.updated.as_deref())
+    }
+
+    /// 文章的唯一标识: id > guid > link > 标题 + 日期.
+    /// 什么都没有的文章无法识别, 返回 None
+    pub fn identity(&self) -> Option<String> {
+        let id = self
+            .id
+            .as_deref()
+            .or(self.guid.as_deref())
+            .or(self.link.as_deref())
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty());
+        if let Some(id) = id {
+            return Some(id.to_owned());
+        }
+
+        let title = self.title.as_deref().map(|s| s.trim()).unwrap_or_default();
+        let date = self.date().map(|s| s.trim()).unwrap_or_default();
+        if title.is_empty() && date.is_empty() {
+            None
+        } else {
+            Some(format!("{}|{}", title, date))
+        }
+    }
+
+    /// 解析后的文章日期, 用于排序和判断文章是否太旧
+    pub fn date_time(&self) -> Option<DateTime<FixedOffset>> {
+        [&self.published, &self.pub_date, &self.updated]
+            .into_iter()
+            .flatten()
+            .find_map(|d| super::date::parse_date(d))
+    }
+
+    /// 文章的缩略图
+    pub fn thumbnail(&self) -> Option<&Attachment> {
+        self.attachments
+            .iter()
+            .find(|a| a.kind == AttachmentKind::Thumbnail)
+            .or_else(|| {
+                self.attachments
+                    .iter()
+                    .find(|a| a.media_type() == Some("image"))
+            })
+    }
+
+    /// 文章的音频或者视频, 比如播客的节目
+    pub fn media(&self) -> Option<&Attachment> {
+        self.attachments
+            .iter()
+            .find(|a| matches!(a.media_type(), Some("audio" | "video")))
+    }
+
+    /// 文章摘要, 没有摘要就用正文
+    pub fn abstract_text(&self) -> Option<&str> {
+        self.summary
+            .as_deref()
+            .or(self.description.as_deref())
+            .or(self.content.as_deref())
+    }
 }
 
 impl FromXmlWithStr for FeedPost {
@@ -45,7 +126,7 @@ impl FromXmlWithStr for FeedPost {
 
 impl FromXmlWithReader for FeedPost {
     /// > The function reads the XML events from the reader, and when it encounters a start tag, it calls
-    /// the `from_xml_with_reader` function of the corresponding field
+    /// > the `from_xml_with_reader` function of the corresponding field
     ///
     /// Arguments:
     ///
@@ -60,6 +141,7 @@ impl FromXmlWithReader for FeedPost {
         reader: &mut Reader<B>,
     ) -> quick_xml::Result<Self> {
         let mut post = FeedPost::default();
+        let mut duration = None;
 
         reader.trim_text(true);
         let mut buf = bufs.pop();
@@ -67,21 +149,35 @@ impl FromXmlWithReader for FeedPost {
         loop {
             match reader.read_event(&mut buf) {
                 Ok(Event::Empty(ref ce)) => match reader.decode(ce.local_name())? {
-                    "link" => {
-                        if let Some(AtomLink::Alternate(l)) =
-                            parse_atom_link(reader, ce.attributes())?
-                        {
-                            post.link = Some(l);
+                    "link" => match parse_atom_link(reader, ce.attributes())? {
+                        Some(AtomLink::Alternate(l)) => post.link = Some(l),
+                        Some(AtomLink::Other(_, rel)) if rel == "enclosure" => {
+                            post.attachments.extend(Attachment::from_attributes(
+                                reader,
+                                ce.attributes(),
+                                AttachmentKind::Enclosure,
+                            )?);
                         }
-                    }
+                        _ => (),
+                    },
 
-                    _ => (),
+                    _ => {
+                        if let Some(kind) = media_kind(reader.decode(ce.name())?) {
+                            post.attachments.extend(Attachment::from_attributes(
+                                reader,
+                                ce.attributes(),
+                                kind,
+                            )?);
+                        }
+                    }
                 },
 
                 Ok(Event::Start(ref e)) => match reader.decode(e.name())? {
                     "title" => post.title = TextOrCData::from_xml_with_reader(bufs, reader)?,
 
-                    "pubDate" => post.pub_date = TextOrCData::from_xml_with_reader(bufs, reader)?,
+                    "pubDate" | "dc:date" => {
+                        post.pub_date = TextOrCData::from_xml_with_reader(bufs, reader)?
+                    }
 
                     "guid" => post.guid = TextOrCData::from_xml_with_reader(bufs, reader)?,
 
@@ -91,11 +187,46 @@ impl FromXmlWithReader for FeedPost {
                         post.description = TextOrCData::from_xml_with_reader(bufs, reader)?
                     }
 
-                    "author" => {
-                        post.author = TextOrCData::from_xml_with_reader(bufs, reader)?;
+                    "author" | "dc:creator" => {
+                        post.author = Person::from_xml_with_reader(bufs, reader)?.name;
+                    }
+
+                    "id" => post.id = TextOrCData::from_xml_with_reader(bufs, reader)?,
+
+                    // issued / modified: Atom 0.3
+                    "published" | "issued" => {
+                        post.published = TextOrCData::from_xml_with_reader(bufs, reader)?
                     }
 
-                    "category" => {
+                    "updated" | "modified" => {
+                        post.updated = TextOrCData::from_xml_with_reader(bufs, reader)?
+                    }
+
+                    "summary" => post.summary = DeepText::from_xml_with_reader(bufs, reader)?.0,
+
+                    "content" | "content:encoded" => {
+                        post.content = DeepText::from_xml_with_reader(bufs, reader)?.0
+                    }
+
+                    name @ ("enclosure" | "media:content" | "media:thumbnail" | "itunes:image"
+                    | "media:group") => {
+                        if let Some(kind) = media_kind(name) {
+                            post.attachments.extend(Attachment::from_attributes(
+                                reader,
+                                e.attributes(),
+                                kind,
+                            )?);
+                        }
+                        let children = MediaElements::from_xml_with_reader(bufs, reader)?;
+                        post.attachments.extend(children.0);
+                    }
+
+                    "itunes:duration" => {
+                        duration = TextOrCData::from_xml_with_reader(bufs, reader)?
+                            .and_then(|d| parse_duration(&d));
+                    }
+
+                    "category" | "dc:subject" => {
                         let category_item = TextOrCData::from_xml_with_reader(bufs, reader)?;
                         if let Some(c) = category_item {
                             post.category.push(c);
@@ -116,6 +247,15 @@ impl FromXmlWithReader for FeedPost {
             buf.clear();
         }
 
+        // itunes:duration 描述的是节目, 也就是 enclosure 的时长
+        if let Some(enclosure) = post
+            .attachments
+            .iter_mut()
+            .find(|a| a.kind == AttachmentKind::Enclosure && a.duration.is_none())
+        {
+            enclosure.duration = duration;
+        }
+
         Ok(post)
     }
 }
@@ -0,0 +1,238 @@
+use serde::Deserialize;
+
+use super::feed::{ChannelImage, Feed};
+use super::item::FeedPost;
+use super::media::{Attachment, AttachmentKind};
+
+/// JSON Feed 1.1 (兼容 1.0): https://www.jsonfeed.org/version/1.1/
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct JsonFeed {
+    version: String,
+    title: String,
+    home_page_url: Option<String>,
+    feed_url: Option<String>,
+    description: Option<String>,
+    icon: Option<String>,
+    favicon: Option<String>,
+    language: Option<String>,
+    // 1.1
+    authors: Vec<JsonFeedAuthor>,
+    // 1.0
+    author: Option<JsonFeedAuthor>,
+    hubs: Vec<JsonFeedHub>,
+    items: Vec<JsonFeedItem>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct JsonFeedHub {
+    #[serde(rename = "type")]
+    typ: String,
+    url: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct JsonFeedItem {
+    id: Option<serde_json::Value>,
+    url: Option<String>,
+    external_url: Option<String>,
+    title: Option<String>,
+    content_html: Option<String>,
+    content_text: Option<String>,
+    summary: Option<String>,
+    date_published: Option<String>,
+    date_modified: Option<String>,
+    authors: Vec<JsonFeedAuthor>,
+    author: Option<JsonFeedAuthor>,
+    tags: Vec<String>,
+    image: Option<String>,
+    banner_image: Option<String>,
+    attachments: Vec<JsonFeedAttachment>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct JsonFeedAttachment {
+    url: String,
+    mime_type: Option<String>,
+    size_in_bytes: Option<u64>,
+    duration_in_seconds: Option<f64>,
+}
+
+impl From<JsonFeedAttachment> for Attachment {
+    fn from(a: JsonFeedAttachment) -> Self {
+        Attachment {
+            mime_type: a.mime_type,
+            length: a.size_in_bytes,
+            duration: a.duration_in_seconds.map(|d| d as u64),
+            ..Attachment::new(AttachmentKind::Enclosure, a.url)
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct JsonFeedAuthor {
+    name: Option<String>,
+}
+
+/// 作者取第一个有名字的, 1.1 的 `authors` 优先于 1.0 的 `author`
+fn first_author_name(
+    authors: Vec<JsonFeedAuthor>,
+    author: Option<JsonFeedAuthor>,
+) -> Option<String> {
+    authors.into_iter().chain(author).find_map(|a| a.name)
+}
+
+impl From<JsonFeedItem> for FeedPost {
+    fn from(item: JsonFeedItem) -> Self {
+        // id 规范要求是字符串, 但是有些生成器会输出数字
+        let id = item.id.and_then(|id| match id {
+            serde_json::Value::String(s) => Some(s),
+            serde_json::Value::Number(n) => Some(n.to_string()),
+            _ => None,
+        });
+
+        let content = item.content_html.or(item.content_text);
+
+        let attachments = item
+            .image
+            .into_iter()
+            .chain(item.banner_image)
+            .map(|url| Attachment::new(AttachmentKind::Thumbnail, url))
+            .chain(item.attachments.into_iter().map(Attachment::from))
+            .filter(|a| !a.url.is_empty())
+            .collect();
+
+        FeedPost {
+            title: item.title,
+            description: item.summary.clone().or_else(|| content.clone()),
+            link: item.url.or(item.external_url),
+            author: first_author_name(item.authors, item.author),
+            category: item.tags,
+            id,
+            published: item.date_published,
+            updated: item.date_modified,
+            summary: item.summary,
+            content,
+            attachments,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<JsonFeed> for Feed {
+    fn from(json: JsonFeed) -> Self {
+        Feed {
+            version: Some(json.version),
+            title: json.title,
+            description: json.description,
+            link: json
+                .home_page_url
+                .or_else(|| json.feed_url.clone())
+                .unwrap_or_default(),
+            atom_link: json.feed_url,
+            hub: json
+                .hubs
+                .into_iter()
+                .find(|h| h.typ.eq_ignore_ascii_case("websub"))
+                .map(|h| h.url),
+            language: json.language,
+            web_master: first_author_name(json.authors, json.author),
+            image: json
+                .icon
+                .or(json.favicon)
+                .map(|url| ChannelImage { url: Some(url) }),
+            posts: json.items.into_iter().map(FeedPost::from).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Feed {
+    /// 把 JSON Feed 文档解析成和 RSS/Atom 一样的 `Feed`
+    pub fn from_json_slice(bytes: &[u8]) -> serde_json::Result<Feed> {
+        let json = serde_json::from_slice::<JsonFeed>(bytes)?;
+        Ok(Feed::from(json))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn json_feed_test() {
+        let s: &[u8] = include_bytes!("../../test/data/json_feed_1.1.json");
+        let r = Feed::from_json_slice(s).unwrap();
+        assert_eq!(
+            r.version.as_deref(),
+            Some("https://jsonfeed.org/version/1.1")
+        );
+        assert_eq!(&r.title, "json_feed_1.1.title");
+        assert_eq!(&r.link, "https://example.org/");
+        assert_eq!(
+            r.atom_link.as_deref(),
+            Some("https://example.org/feed.json")
+        );
+        assert_eq!(r.description.as_deref(), Some("json_feed_1.1.description"));
+        assert_eq!(r.web_master.as_deref(), Some("json_feed_1.1.author"));
+        assert_eq!(r.hub.as_deref(), Some("https://example.org/hub"));
+        assert_eq!(r.posts.len(), 2);
+
+        assert_eq!(
+            r.posts[0].title.as_deref(),
+            Some("json_feed_1.1.items[0].title")
+        );
+        assert_eq!(r.posts[0].id.as_deref(), Some("2"));
+        assert_eq!(
+            r.posts[0].link.as_deref(),
+            Some("https://example.org/second-item")
+        );
+        assert_eq!(
+            r.posts[0].description.as_deref(),
+            Some("<p>Hello, world!</p>")
+        );
+        assert_eq!(
+            r.posts[0].published.as_deref(),
+            Some("2023-04-02T10:00:00+08:00")
+        );
+        assert_eq!(r.posts[0].content.as_deref(), Some("<p>Hello, world!</p>"));
+        assert_eq!(r.posts[0].summary, None);
+        assert_eq!(
+            r.posts[0].thumbnail().map(|a| a.url.as_str()),
+            Some("https://example.org/second-item.png")
+        );
+        let media = r.posts[0].media().unwrap();
+        assert_eq!(media.url, "https://example.org/second-item.mp3");
+        assert_eq!(media.length, Some(1024));
+        assert_eq!(media.duration, Some(300));
+        assert_eq!(r.posts[0].author.as_deref(), Some("item_author"));
+        assert_eq!(
+            r.posts[0].category,
+            vec!["rust".to_owned(), "rss".to_owned()]
+        );
+
+        // 数字 id, 只有 external_url, 只有纯文本内容
+        assert_eq!(r.posts[1].id.as_deref(), Some("1"));
+        assert_eq!(
+            r.posts[1].link.as_deref(),
+            Some("https://example.com/external")
+        );
+        assert_eq!(
+            r.posts[1].description.as_deref(),
+            Some("This is a plain text item.")
+        );
+        assert_eq!(r.posts[1].title, None);
+    }
+
+    #[test]
+    fn json_feed_1_0_author() {
+        let s = br#"{"version":"https://jsonfeed.org/version/1","title":"t","author":{"name":"old"},"items":[]}"#;
+        let r = Feed::from_json_slice(s).unwrap();
+        assert_eq!(r.web_master.as_deref(), Some("old"));
+        assert!(r.posts.is_empty());
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use thiserror::Error;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::conf::FetchLimits;
+
+// 429 没带 Retry-After 的时候等多久
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(60);
+// Retry-After 最多等多久, 免得服务端一句话就让订阅源停更好几天
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// 服务端要求慢一点, 这段时间里不再请求这个主机
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("{host} asked to slow down, retry after {} seconds", .retry_after.as_secs())]
+pub struct RateLimited {
+    pub host: String,
+    pub retry_after: Duration,
+}
+
+#[derive(Default)]
+struct HostState {
+    semaphore: Option<Arc<Semaphore>>,
+    // 下一个请求最早什么时候开始
+    next_start: Option<Instant>,
+    // Retry-After 之前都不请求
+    blocked_until: Option<Instant>,
+}
+
+/// 拉取的并发和频率限制: 全局并发, 每个主机的并发, 同一主机两次请求之间的最小间隔
+pub struct Limiter {
+    limits: FetchLimits,
+    global: Arc<Semaphore>,
+    hosts: Mutex<HashMap<String, HostState>>,
+}
+
+/// 拿着它的时候才能发请求, 请求结束之后释放
+pub struct Permit {
+    _host: OwnedSemaphorePermit,
+    _global: OwnedSemaphorePermit,
+}
+
+impl Limiter {
+    pub fn new(limits: FetchLimits) -> Self {
+        Self {
+            global: Arc::new(Semaphore::new(limits.max_concurrent.max(1))),
+            hosts: Mutex::new(HashMap::new()),
+            limits,
+        }
+    }
+
+    /// 等到可以请求这个主机. 主机还在 Retry-After 的时间里就直接返回错误, 不占着位置干等.
+    ///
+    /// Arguments:
+    ///
+    /// * `host`: 主机和端口, `example.com:443`
+    pub async fn acquire(&self, host: &str) -> Result<Permit, RateLimited> {
+        let semaphore = {
+            let mut hosts = self.hosts.lock().unwrap();
+            let state = hosts.entry(host.to_owned()).or_default();
+            let now = Instant::now();
+            if let Some(until) = state.blocked_until.filter(|until| *until > now) {
+                return Err(RateLimited {
+                    host: host.to_owned(),
+                    retry_after: until - now,
+                });
+            }
+            state
+                .semaphore
+                .get_or_insert_with(|| {
+                    Arc::new(Semaphore::new(self.limits.per_host_concurrent.max(1)))
+                })
+                .clone()
+        };
+        let host_permit = semaphore.acquire_owned().await.expect("semaphore closed");
+
+        // 排队等到和上一个请求隔开最小间隔, 等的时候不占全局的位置
+        let start = {
+            let mut hosts = self.hosts.lock().unwrap();
+            let state = hosts.entry(host.to_owned()).or_default();
+            let start = state
+                .next_start
+                .map_or_else(Instant::now, |next| next.max(Instant::now()));
+            state.next_start = Some(start + self.limits.per_host_delay);
+            start
+        };
+        let wait = start.saturating_duration_since(Instant::now());
+        if !wait.is_zero() {
+            info!("请求 {} 太频繁, 等待 {} 毫秒", host, wait.as_millis());
+            tokio::time::sleep_until(start).await;
+        }
+
+        let global_permit = self
+            .global
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("semaphore closed");
+        Ok(Permit {
+            _host: host_permit,
+            _global: global_permit,
+        })
+    }
+
+    /// 服务端返回了 429 或者 503 时调用, 按 Retry-After 暂停请求这个主机
+    ///
+    /// Returns:
+    ///
+    /// 需要暂停的话返回暂停多久
+    pub fn back_off(
+        &self,
+        host: &str,
+        status: StatusCode,
+        headers: &HeaderMap,
+    ) -> Option<RateLimited> {
+        let retry_after = headers
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| parse_retry_after(v, SystemTime::now()));
+        let retry_after = match (status, retry_after) {
+            (_, Some(d)) => d.min(MAX_BACKOFF),
+            (StatusCode::TOO_MANY_REQUESTS, None) => DEFAULT_BACKOFF,
+            // 503 没说等多久的话就是普通的错误
+            _ => return None,
+        };
+
+        warn!(
+            "{} 返回了 {}, {} 秒内不再请求",
+            host,
+            status,
+            retry_after.as_secs()
+        );
+        let mut hosts = self.hosts.lock().unwrap();
+        let state = hosts.entry(host.to_owned()).or_default();
+        state.blocked_until = Some(Instant::now() + retry_after);
+        Some(RateLimited {
+            host: host.to_owned(),
+            retry_after,
+        })
+    }
+}
+
+pub fn is_rate_limited(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    )
+}
+
+/// `Retry-After` 可以是秒数, 也可以是 HTTP 日期
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = super::date::parse_date(value)?;
+    let date = SystemTime::UNIX_EPOCH + Duration::from_secs(date.timestamp().max(0) as u64);
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limits(per_host_concurrent: usize, delay_ms: u64) -> FetchLimits {
+        FetchLimits {
+            max_concurrent: 8,
+            per_host_concurrent,
+            per_host_delay: Duration::from_millis(delay_ms),
+        }
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        // Sun, 06 Nov 1994 08:49:37 GMT 是 784111777
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:51:37 GMT", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_per_host_delay() {
+        let limiter = Limiter::new(limits(4, 1000));
+        let begin = Instant::now();
+
+        drop(limiter.acquire("a.example:443").await.unwrap());
+        drop(limiter.acquire("a.example:443").await.unwrap());
+        drop(limiter.acquire("a.example:443").await.unwrap());
+        assert_eq!(begin.elapsed().as_secs(), 2);
+
+        // 别的主机不受影响
+        drop(limiter.acquire("b.example:443").await.unwrap());
+        assert_eq!(begin.elapsed().as_secs(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_per_host_concurrent() {
+        let limiter = Limiter::new(limits(1, 0));
+        let first = limiter.acquire("a.example:443").await.unwrap();
+
+        let second =
+            tokio::time::timeout(Duration::from_millis(50), limiter.acquire("a.example:443")).await;
+        assert!(second.is_err());
+        assert!(limiter.acquire("b.example:443").await.is_ok());
+
+        drop(first);
+        assert!(limiter.acquire("a.example:443").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_back_off() {
+        let limiter = Limiter::new(limits(2, 0));
+        let mut headers = HeaderMap::new();
+
+        // 503 没有 Retry-After 不用等
+        assert_eq!(
+            limiter.back_off("a.example:443", StatusCode::SERVICE_UNAVAILABLE, &headers),
+            None
+        );
+        assert!(limiter.acquire("a.example:443").await.is_ok());
+
+        headers.insert(RETRY_AFTER, "30".parse().unwrap());
+        let limited = limiter
+            .back_off("a.example:443", StatusCode::SERVICE_UNAVAILABLE, &headers)
+            .unwrap();
+        assert_eq!(limited.retry_after, Duration::from_secs(30));
+
+        let err = limiter.acquire("a.example:443").await.err().unwrap();
+        assert_eq!(err.host, "a.example:443");
+        assert!(err.retry_after <= Duration::from_secs(30));
+        assert!(limiter.acquire("b.example:443").await.is_ok());
+
+        // 太长的 Retry-After 只等一个小时
+        headers.insert(RETRY_AFTER, "86400".parse().unwrap());
+        let limited = limiter
+            .back_off("b.example:443", StatusCode::TOO_MANY_REQUESTS, &headers)
+            .unwrap();
+        assert_eq!(limited.retry_after, MAX_BACKOFF);
+    }
+}
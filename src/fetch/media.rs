@@ -0,0 +1,190 @@
+use quick_xml::{
+    events::{attributes::Attributes, Event},
+    Reader,
+};
+use serde::{Deserialize, Serialize};
+
+use super::buf::BufPool;
+use super::FromXmlWithReader;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AttachmentKind {
+    // <enclosure>, Atom <link rel="enclosure">
+    Enclosure,
+    // <media:content>
+    Media,
+    // <media:thumbnail>, <itunes:image>
+    Thumbnail,
+}
+
+/// 文章的附件: 播客音频, 视频, 图片等
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub kind: AttachmentKind,
+    pub url: String,
+    pub mime_type: Option<String>,
+    // 字节数
+    pub length: Option<u64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    // media:content 的 medium: image / audio / video / document / executable
+    pub medium: Option<String>,
+    // 时长, 秒
+    pub duration: Option<u64>,
+}
+
+impl Attachment {
+    pub fn new(kind: AttachmentKind, url: String) -> Self {
+        Self {
+            kind,
+            url,
+            mime_type: None,
+            length: None,
+            width: None,
+            height: None,
+            medium: None,
+            duration: None,
+        }
+    }
+
+    /// image / audio / video, 优先 medium, 其次看 MIME 类型
+    pub fn media_type(&self) -> Option<&str> {
+        if self.kind == AttachmentKind::Thumbnail {
+            return Some("image");
+        }
+
+        self.medium
+            .as_deref()
+            .or_else(|| self.mime_type.as_deref()?.split('/').next())
+    }
+
+    /// 从元素属性中读取附件, 没有 `url` / `href` 的元素会被忽略
+    pub fn from_attributes<B: std::io::BufRead>(
+        reader: &Reader<B>,
+        attributes: Attributes,
+        kind: AttachmentKind,
+    ) -> quick_xml::Result<Option<Self>> {
+        let mut attachment = Attachment::new(kind, String::new());
+
+        for attribute in attributes {
+            let attribute = attribute?;
+            let value = attribute.unescape_and_decode_value(reader)?;
+            match reader.decode(attribute.key)? {
+                "url" | "href" => attachment.url = value,
+                "type" => attachment.mime_type = Some(value),
+                "length" | "fileSize" => attachment.length = value.trim().parse().ok(),
+                "width" => attachment.width = value.trim().parse().ok(),
+                "height" => attachment.height = value.trim().parse().ok(),
+                "medium" => attachment.medium = Some(value),
+                "duration" => attachment.duration = parse_duration(&value),
+                _ => (),
+            }
+        }
+
+        if attachment.url.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(attachment))
+        }
+    }
+}
+
+/// 解析 `itunes:duration`: `HH:MM:SS`, `MM:SS` 或者直接是秒数
+pub fn parse_duration(s: &str) -> Option<u64> {
+    s.trim().split(':').try_fold(0u64, |acc, part| {
+        Some(acc * 60 + part.trim().parse::<u64>().ok()?)
+    })
+}
+
+/// `<media:group>` 或者带子元素的 `<media:content>` 里面的所有附件
+pub struct MediaElements(pub Vec<Attachment>);
+
+impl FromXmlWithReader for MediaElements {
+    /// It reads every `media:content` and `media:thumbnail` until the end of the current element,
+    /// no matter how deep they are nested
+    ///
+    /// Arguments:
+    ///
+    /// * `bufs`: &BufPool
+    /// * `reader`: &mut Reader<B>
+    ///
+    /// Returns:
+    ///
+    /// A MediaElements
+    fn from_xml_with_reader<B: std::io::BufRead>(
+        bufs: &BufPool,
+        reader: &mut Reader<B>,
+    ) -> quick_xml::Result<Self> {
+        let mut buf = bufs.pop();
+        let mut attachments = vec![];
+        let mut depth = 1usize;
+
+        loop {
+            match reader.read_event(&mut buf) {
+                Ok(Event::Start(ref e)) => {
+                    depth += 1;
+                    if let Some(kind) = media_kind(reader.decode(e.name())?) {
+                        attachments.extend(Attachment::from_attributes(
+                            reader,
+                            e.attributes(),
+                            kind,
+                        )?);
+                    }
+                }
+                Ok(Event::Empty(ref e)) => {
+                    if let Some(kind) = media_kind(reader.decode(e.name())?) {
+                        attachments.extend(Attachment::from_attributes(
+                            reader,
+                            e.attributes(),
+                            kind,
+                        )?);
+                    }
+                }
+                Ok(Event::End(_)) if depth == 1 => break,
+                Ok(Event::End(_)) => depth -= 1,
+                Ok(Event::Eof) => break,
+                Ok(_) => (),
+                Err(e) => return Err(e),
+            }
+            buf.clear();
+        }
+
+        Ok(MediaElements(attachments))
+    }
+}
+
+#[inline]
+pub fn media_kind(name: &str) -> Option<AttachmentKind> {
+    match name {
+        "enclosure" => Some(AttachmentKind::Enclosure),
+        "media:content" => Some(AttachmentKind::Media),
+        "media:thumbnail" | "itunes:image" => Some(AttachmentKind::Thumbnail),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(Some(3723), parse_duration("01:02:03"));
+        assert_eq!(Some(125), parse_duration("2:05"));
+        assert_eq!(Some(42), parse_duration(" 42 "));
+        assert_eq!(None, parse_duration("abc"));
+    }
+
+    #[test]
+    fn test_media_type() {
+        let mut a = Attachment::new(AttachmentKind::Enclosure, "http://a/b.mp3".to_owned());
+        assert_eq!(None, a.media_type());
+        a.mime_type = Some("audio/mpeg".to_owned());
+        assert_eq!(Some("audio"), a.media_type());
+        a.medium = Some("video".to_owned());
+        assert_eq!(Some("video"), a.media_type());
+
+        let t = Attachment::new(AttachmentKind::Thumbnail, "http://a/b.jpg".to_owned());
+        assert_eq!(Some("image"), t.media_type());
+    }
+}
@@ -1,8 +1,19 @@
 mod buf;
+pub mod date;
+mod discover;
+mod encoding;
 pub mod feed;
+mod guard;
 mod http;
 pub mod item;
+mod json_feed;
+mod limiter;
+pub mod media;
+mod options;
+mod rsshub;
+mod source;
 mod utils;
+mod watch;
 
 use std::io::BufRead;
 
@@ -10,12 +21,12 @@ use quick_xml::events::Event as XmlEvent;
 use quick_xml::Reader as XmlReader;
 
 use self::buf::BufPool;
-use self::feed::Feed;
 
 pub trait FromXmlWithStr: Sized {
     fn from_xml_with_str(bufs: &BufPool, text: &str) -> quick_xml::Result<Self>;
 }
 
+#[allow(dead_code)]
 pub trait FromXmlWithBufRead: Sized {
     fn from_xml_with_buf<B: std::io::BufRead>(bufs: B) -> quick_xml::Result<Self>;
 }
@@ -66,4 +77,10 @@ impl FromXmlWithReader for SkipThisElement {
     }
 }
 
+pub use discover::*;
+pub use guard::Network;
 pub use http::*;
+pub use options::*;
+pub use rsshub::parse_route;
+pub use source::{is_command_name, parse_local_source, shareable_url};
+pub use watch::{Extractor, PageWatch, WatchError};
@@ -0,0 +1,134 @@
+use once_cell::sync::OnceCell;
+use url::Url;
+
+use crate::conf::RssHubConfig;
+
+// `rsshub:/3dm/news` 这样的简写, 数据库里存的就是简写
+pub const RSSHUB_SCHEME: &str = "rsshub:";
+
+// 访问密钥的查询参数
+const ACCESS_KEY_PARAM: &str = "key";
+
+// 配置文件里的 RSSHub 实例
+static RSSHUB: OnceCell<RssHubConfig> = OnceCell::new();
+
+pub(super) fn init(conf: RssHubConfig) {
+    RSSHUB.set(conf).expect("RSSHUB already initialized");
+}
+
+pub(super) fn is_route(url: &str) -> bool {
+    url.starts_with(RSSHUB_SCHEME)
+}
+
+/// 规范化 RSSHub 路由的写法, `rsshub:3dm/news`, `rsshub://3dm/news` 都会变成 `rsshub:/3dm/news`
+///
+/// Returns:
+///
+/// 规范化之后的路由, 不是 RSSHub 路由就是 None
+pub fn parse_route(s: &str) -> Option<String> {
+    let route = s
+        .trim()
+        .strip_prefix(RSSHUB_SCHEME)?
+        .trim_start_matches('/');
+    if route.is_empty() || route.chars().any(char::is_whitespace) {
+        return None;
+    }
+
+    // 借用 URL 的解析检查一下路径和查询参数, 顺便去掉 `#` 后面的部分
+    let mut url = Url::parse("rsshub:/").ok()?.join(route).ok()?;
+    url.set_fragment(None);
+    Some(url.to_string())
+}
+
+/// 把 RSSHub 路由展开成实例上的地址, 配置了访问密钥的话会带上 `key` 参数
+///
+/// Arguments:
+///
+/// * `route`: `rsshub:/3dm/news`
+/// * `conf`: RSSHub 实例
+pub fn expand(route: &str, conf: &RssHubConfig) -> Option<Url> {
+    let route = parse_route(route)?;
+    let path = route.strip_prefix(RSSHUB_SCHEME)?;
+
+    let mut url = Url::parse(&format!("{}{}", conf.base_url.trim_end_matches('/'), path)).ok()?;
+    if let Some(ref key) = conf.access_key {
+        url.query_pairs_mut().append_pair(ACCESS_KEY_PARAM, key);
+    }
+    Some(url)
+}
+
+/// 按配置文件里的实例展开 RSSHub 路由
+pub(super) fn expand_with_config(route: &str) -> Option<Url> {
+    match RSSHUB.get() {
+        Some(conf) => expand(route, conf),
+        None => expand(route, &RssHubConfig::default()),
+    }
+}
+
+/// 按配置文件里的实例展开 RSSHub 路由, 但是不带访问密钥, 用在要给别人看的地方
+pub(super) fn expand_without_key(route: &str) -> Option<Url> {
+    let conf = RSSHUB.get().cloned().unwrap_or_default();
+    expand(
+        route,
+        &RssHubConfig {
+            access_key: None,
+            ..conf
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_route() {
+        for s in [
+            "rsshub:/3dm/news",
+            "rsshub:3dm/news",
+            "rsshub://3dm/news",
+            " rsshub:/3dm/news#top ",
+        ] {
+            assert_eq!(parse_route(s).as_deref(), Some("rsshub:/3dm/news"), "{}", s);
+        }
+        assert_eq!(
+            parse_route("rsshub:/github/issue/DIYgod/RSSHub?limit=5").as_deref(),
+            Some("rsshub:/github/issue/DIYgod/RSSHub?limit=5")
+        );
+        assert_eq!(
+            parse_route("rsshub:/bilibili/user/video/2267573/").as_deref(),
+            Some("rsshub:/bilibili/user/video/2267573/")
+        );
+
+        assert_eq!(parse_route("rsshub:"), None);
+        assert_eq!(parse_route("rsshub:/"), None);
+        assert_eq!(parse_route("rsshub:/a b"), None);
+        assert_eq!(parse_route("https://rsshub.app/3dm/news"), None);
+    }
+
+    #[test]
+    fn test_expand() {
+        let public = RssHubConfig::default();
+        assert_eq!(
+            expand("rsshub:/3dm/news", &public).unwrap().as_str(),
+            "https://rsshub.app/3dm/news"
+        );
+
+        let private = RssHubConfig {
+            base_url: "https://example.com/rsshub/".to_owned(),
+            access_key: Some("s3cr&t".to_owned()),
+        };
+        assert_eq!(
+            expand("rsshub:/3dm/news", &private).unwrap().as_str(),
+            "https://example.com/rsshub/3dm/news?key=s3cr%26t"
+        );
+        assert_eq!(
+            expand("rsshub:/github/issue/DIYgod/RSSHub?limit=5", &private)
+                .unwrap()
+                .as_str(),
+            "https://example.com/rsshub/github/issue/DIYgod/RSSHub?limit=5&key=s3cr%26t"
+        );
+
+        assert!(expand("https://rsshub.app/3dm/news", &public).is_none());
+    }
+}
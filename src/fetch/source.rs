@@ -0,0 +1,408 @@
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
+use thiserror::Error;
+use tokio::io::AsyncReadExt;
+use url::Url;
+
+use super::rsshub;
+use super::watch::{PageWatch, WATCH_SCHEME};
+use crate::conf::SourceConfig;
+
+// 本地文件: `file:///srv/feeds/build.xml`
+pub const FILE_SCHEME: &str = "file:";
+// 配置文件里的命令: `exec:build-status`
+pub const EXEC_SCHEME: &str = "exec:";
+
+// 命令最多跑多久
+const EXEC_TIMEOUT: Duration = Duration::from_secs(30);
+// 命令的错误输出最多留多少, 字节
+const STDERR_LIMIT: u64 = 4 * 1024;
+
+// 配置文件里允许的目录和命令
+static SOURCES: OnceCell<SourceConfig> = OnceCell::new();
+
+pub(super) fn init(conf: SourceConfig) {
+    SOURCES.set(conf).expect("SOURCES already initialized");
+}
+
+#[derive(Error, Debug)]
+pub enum SourceError {
+    #[error("file {0} is not in an allowed directory")]
+    FileNotAllowed(String),
+    #[error("command {0} is not configured")]
+    UnknownCommand(String),
+    #[error("cannot read {0}: {1}")]
+    Io(String, std::io::Error),
+    #[error("command {0} failed ({1}): {2}")]
+    Exit(String, ExitStatus, String),
+    #[error("command {0} timed out after {1} seconds")]
+    Timeout(String, u64),
+    #[error("output of {0} is too large (limit {1} bytes)")]
+    TooLarge(String, u64),
+}
+
+/// 订阅源从哪里来
+#[derive(Debug, PartialEq, Eq)]
+pub enum FeedSource<'a> {
+    // http, https, 以及 RSSHub 路由
+    Http(&'a str),
+    File(PathBuf),
+    // 配置文件里的命令名
+    Exec(&'a str),
+    // 监控网页变化
+    Watch(PageWatch),
+}
+
+impl<'a> FeedSource<'a> {
+    pub fn from_url(url: &'a str) -> Self {
+        if let Some(name) = url.strip_prefix(EXEC_SCHEME) {
+            return FeedSource::Exec(name);
+        }
+        if url.starts_with(WATCH_SCHEME) {
+            if let Some(watch) = PageWatch::from_url(url) {
+                return FeedSource::Watch(watch);
+            }
+        }
+        if url.starts_with(FILE_SCHEME) {
+            if let Some(path) = Url::parse(url).ok().and_then(|u| u.to_file_path().ok()) {
+                return FeedSource::File(path);
+            }
+        }
+        FeedSource::Http(url)
+    }
+}
+
+/// 订阅源在别的阅读器里能用的地址, RSSHub 路由会展开成实例上的地址 (不带访问密钥).
+///
+/// Returns:
+///
+/// 本地文件, 命令和网页监控只有 ksbot 自己能拉取, 返回 None
+pub fn shareable_url(url: &str) -> Option<String> {
+    match FeedSource::from_url(url) {
+        FeedSource::Http(url) if rsshub::is_route(url) => {
+            rsshub::expand_without_key(url).map(|u| u.to_string())
+        }
+        FeedSource::Http(url) => Url::parse(url)
+            .ok()
+            .filter(|u| matches!(u.scheme(), "http" | "https"))
+            .map(|_| url.to_owned()),
+        _ => None,
+    }
+}
+
+/// 规范化本地订阅源的写法, 这里只检查格式, 能不能用要等拉取的时候按配置检查
+///
+/// Returns:
+///
+/// `file:///...` 或者 `exec:name`, 不是本地订阅源就是 None
+pub fn parse_local_source(s: &str) -> Option<String> {
+    let s = s.trim();
+    if let Some(name) = s.strip_prefix(EXEC_SCHEME) {
+        return Some(format!("{}{}", EXEC_SCHEME, name)).filter(|_| is_command_name(name));
+    }
+
+    let mut url = Url::parse(s).ok().filter(|u| u.scheme() == "file")?;
+    url.to_file_path().ok()?;
+    url.set_query(None);
+    url.set_fragment(None);
+    Some(url.to_string())
+}
+
+/// 命令名只能是字母, 数字, `-` 和 `_`
+pub fn is_command_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// 读取本地订阅源的内容
+///
+/// Arguments:
+///
+/// * `source`: 本地文件或者命令
+/// * `size_limit`: 内容的大小限制, 字节
+pub(super) async fn read_local(
+    source: &FeedSource<'_>,
+    size_limit: u64,
+) -> Result<Vec<u8>, SourceError> {
+    let default_conf = SourceConfig::default();
+    let conf = SOURCES.get().unwrap_or(&default_conf);
+    match source {
+        FeedSource::File(path) => read_file(path, &conf.file_roots, size_limit).await,
+        FeedSource::Exec(name) => {
+            let argv = conf
+                .commands
+                .get(*name)
+                .ok_or_else(|| SourceError::UnknownCommand(name.to_string()))?;
+            run_command(name, argv, size_limit, EXEC_TIMEOUT).await
+        }
+        FeedSource::Http(_) | FeedSource::Watch(_) => unreachable!("not a local source"),
+    }
+}
+
+/// 读取文件, 文件必须在允许的目录里 (符号链接和 `..` 解析之后)
+async fn read_file(
+    path: &Path,
+    roots: &[PathBuf],
+    size_limit: u64,
+) -> Result<Vec<u8>, SourceError> {
+    let display = path.display().to_string();
+    let not_allowed = || SourceError::FileNotAllowed(display.to_owned());
+
+    // 不存在的文件也当成不允许, 免得用来探测目录结构
+    let path = tokio::fs::canonicalize(path)
+        .await
+        .map_err(|_| not_allowed())?;
+    let mut allowed = false;
+    for root in roots {
+        if let Ok(root) = tokio::fs::canonicalize(root).await {
+            allowed |= path.starts_with(root);
+        }
+    }
+    if !allowed {
+        return Err(not_allowed());
+    }
+
+    let io_error = |e| SourceError::Io(display.to_owned(), e);
+    let metadata = tokio::fs::metadata(&path).await.map_err(io_error)?;
+    if metadata.len() > size_limit {
+        return Err(SourceError::TooLarge(display, size_limit));
+    }
+    tokio::fs::read(&path).await.map_err(io_error)
+}
+
+/// 运行命令, 标准输出就是订阅源的内容. 不经过 shell, 也不接受聊天里传来的参数.
+async fn run_command(
+    name: &str,
+    argv: &[String],
+    size_limit: u64,
+    timeout: Duration,
+) -> Result<Vec<u8>, SourceError> {
+    let (program, args) = argv
+        .split_first()
+        .ok_or_else(|| SourceError::UnknownCommand(name.to_owned()))?;
+
+    let mut child = tokio::process::Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| SourceError::Io(name.to_owned(), e))?;
+
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let run = async {
+        let (mut body, mut errors) = (vec![], vec![]);
+        // 超过大小限制就不再读了, 直接杀掉命令
+        let read_stdout = async {
+            (&mut stdout)
+                .take(size_limit + 1)
+                .read_to_end(&mut body)
+                .await?;
+            if body.len() as u64 > size_limit {
+                child.kill().await?;
+            }
+            Ok::<_, std::io::Error>(())
+        };
+        // 错误输出只留开头用来说明原因, 剩下的读掉丢弃, 免得命令写满管道卡住
+        let read_stderr = async {
+            (&mut stderr)
+                .take(STDERR_LIMIT)
+                .read_to_end(&mut errors)
+                .await?;
+            tokio::io::copy(&mut stderr, &mut tokio::io::sink()).await
+        };
+        let (out, err) = tokio::join!(read_stdout, read_stderr);
+        out?;
+        err?;
+        let status = child.wait().await?;
+        Ok::<_, std::io::Error>((status, body, errors))
+    };
+
+    let (status, body, errors) = tokio::time::timeout(timeout, run)
+        .await
+        .map_err(|_| SourceError::Timeout(name.to_owned(), timeout.as_secs()))?
+        .map_err(|e| SourceError::Io(name.to_owned(), e))?;
+
+    if body.len() as u64 > size_limit {
+        return Err(SourceError::TooLarge(name.to_owned(), size_limit));
+    }
+    if !status.success() {
+        let errors = String::from_utf8_lossy(&errors);
+        let reason = errors.lines().next().unwrap_or_default().trim().to_owned();
+        return Err(SourceError::Exit(name.to_owned(), status, reason));
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LIMIT: u64 = 1024 * 1024;
+
+    fn argv(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_feed_source() {
+        assert_eq!(
+            FeedSource::from_url("https://example.com/feed.xml"),
+            FeedSource::Http("https://example.com/feed.xml")
+        );
+        assert_eq!(
+            FeedSource::from_url("rsshub:/3dm/news"),
+            FeedSource::Http("rsshub:/3dm/news")
+        );
+        assert_eq!(
+            FeedSource::from_url("file:///srv/feeds/a%20b.xml"),
+            FeedSource::File(PathBuf::from("/srv/feeds/a b.xml"))
+        );
+        assert_eq!(
+            FeedSource::from_url("exec:build-status"),
+            FeedSource::Exec("build-status")
+        );
+        assert!(matches!(
+            FeedSource::from_url("watch:https://example.com/#css=li"),
+            FeedSource::Watch(_)
+        ));
+    }
+
+    #[test]
+    fn test_shareable_url() {
+        assert_eq!(
+            shareable_url("https://example.com/feed.xml").as_deref(),
+            Some("https://example.com/feed.xml")
+        );
+        // 测试里没有配置 RSSHub 实例, 用的是公共实例
+        assert_eq!(
+            shareable_url("rsshub:/3dm/news").as_deref(),
+            Some("https://rsshub.app/3dm/news")
+        );
+        assert_eq!(shareable_url("file:///srv/feeds/a.xml"), None);
+        assert_eq!(shareable_url("exec:build-status"), None);
+        assert_eq!(shareable_url("watch:https://example.com/#css=li"), None);
+    }
+
+    #[test]
+    fn test_parse_local_source() {
+        assert_eq!(
+            parse_local_source(" file:///srv/feeds/a.xml#x ").as_deref(),
+            Some("file:///srv/feeds/a.xml")
+        );
+        assert_eq!(
+            parse_local_source("exec:build_status-2").as_deref(),
+            Some("exec:build_status-2")
+        );
+        assert_eq!(parse_local_source("exec:"), None);
+        assert_eq!(parse_local_source("exec:rm -rf /"), None);
+        assert_eq!(parse_local_source("exec:../bin/sh"), None);
+        assert_eq!(parse_local_source("https://example.com/feed.xml"), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_file() {
+        let roots = vec![PathBuf::from("test/data")];
+
+        let body = read_file(Path::new("test/data/rss_2.0.xml"), &roots, LIMIT)
+            .await
+            .unwrap();
+        assert!(body.starts_with(b"<?xml"));
+
+        for path in [
+            "Cargo.toml",
+            "test/data/../../Cargo.toml",
+            "test/data/none.xml",
+        ] {
+            assert!(
+                matches!(
+                    read_file(Path::new(path), &roots, LIMIT).await,
+                    Err(SourceError::FileNotAllowed(_))
+                ),
+                "{}",
+                path
+            );
+        }
+        // 没有配置目录就不能读任何文件
+        assert!(read_file(Path::new("test/data/rss_2.0.xml"), &[], LIMIT)
+            .await
+            .is_err());
+        assert!(matches!(
+            read_file(Path::new("test/data/rss_2.0.xml"), &roots, 10).await,
+            Err(SourceError::TooLarge(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_run_command() {
+        let timeout = Duration::from_secs(5);
+
+        let body = run_command(
+            "cat",
+            &argv(&["cat", "test/data/rss_2.0.xml"]),
+            LIMIT,
+            timeout,
+        )
+        .await
+        .unwrap();
+        assert!(body.starts_with(b"<?xml"));
+
+        let err = run_command("ls", &argv(&["ls", "/none"]), LIMIT, timeout)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, SourceError::Exit(..)), "{}", err);
+
+        let err = run_command(
+            "sleep",
+            &argv(&["sleep", "5"]),
+            LIMIT,
+            Duration::from_millis(100),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(matches!(err, SourceError::Timeout(..)), "{}", err);
+
+        // 输出没完没了的命令读到大小限制就杀掉, 不会等到超时
+        let err = run_command("yes", &argv(&["yes"]), 1024, timeout)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, SourceError::TooLarge(..)), "{}", err);
+
+        // 错误输出很多也不会卡住, 只留开头
+        let err = run_command(
+            "sh",
+            &argv(&[
+                "sh",
+                "-c",
+                "echo broken >&2; yes | head -c 1000000 >&2; exit 3",
+            ]),
+            LIMIT,
+            timeout,
+        )
+        .await
+        .err()
+        .unwrap();
+        match err {
+            SourceError::Exit(_, status, reason) => {
+                assert_eq!(status.code(), Some(3));
+                assert_eq!(reason, "broken");
+            }
+            e => panic!("{}", e),
+        }
+
+        assert!(matches!(
+            run_command("none", &argv(&["/none/ksbot"]), LIMIT, timeout).await,
+            Err(SourceError::Io(..))
+        ));
+    }
+}
@@ -1,16 +1,25 @@
 use quick_xml::{
-    events::{attributes::Attributes, Event},
+    events::{attributes::Attributes, BytesText, Event},
     Reader,
 };
 
 use std::borrow::Cow;
 
 use super::buf::BufPool;
-use super::FromXmlWithReader;
+use super::{FromXmlWithReader, SkipThisElement};
 
 pub type TextOrCData = Option<String>;
 pub type NumberData = Option<u32>;
 
+/// 元素内的全部文本, 会进入嵌套的子元素 (例如 `type="xhtml"` 的 Atom content)
+pub struct DeepText(pub Option<String>);
+
+/// Atom 的 `<author><name>..</name></author>`, 也兼容 RSS 的 `<author>text</author>`
+#[derive(Debug, Default)]
+pub struct Person {
+    pub name: Option<String>,
+}
+
 impl FromXmlWithReader for TextOrCData {
     /// It reads the next event from the reader, and if it's a text or CDATA event, it returns the text.
     /// Otherwise, it returns an empty string
@@ -33,7 +42,7 @@ impl FromXmlWithReader for TextOrCData {
 
         loop {
             match reader.read_event(&mut buf) {
-                Ok(Event::Text(ref e)) => text = Some(reader.decode(e)?.to_string()),
+                Ok(Event::Text(ref e)) => text = Some(unescape_text(reader, e)?),
                 Ok(Event::CData(ref e)) => text = Some(reader.decode(e)?.to_string()),
                 Ok(Event::End(_) | Event::Eof) => break,
                 Ok(_) => {}
@@ -46,6 +55,110 @@ impl FromXmlWithReader for TextOrCData {
     }
 }
 
+impl FromXmlWithReader for DeepText {
+    /// It reads every text and CDATA event until the end of the current element, descending into
+    /// child elements, and joins them with a space
+    ///
+    /// Arguments:
+    ///
+    /// * `bufs`: &BufPool
+    /// * `reader`: &mut Reader<B>
+    ///
+    /// Returns:
+    ///
+    /// A DeepText
+    fn from_xml_with_reader<B: std::io::BufRead>(
+        bufs: &BufPool,
+        reader: &mut Reader<B>,
+    ) -> quick_xml::Result<Self> {
+        let mut buf = bufs.pop();
+        let mut text: Option<String> = None;
+        let mut depth = 1usize;
+
+        loop {
+            let piece = match reader.read_event(&mut buf) {
+                Ok(Event::Start(_)) => {
+                    depth += 1;
+                    None
+                }
+                Ok(Event::End(_)) if depth == 1 => break,
+                Ok(Event::End(_)) => {
+                    depth -= 1;
+                    None
+                }
+                Ok(Event::Text(ref e)) => Some(unescape_text(reader, e)?),
+                Ok(Event::CData(ref e)) => Some(reader.decode(e)?.to_string()),
+                Ok(Event::Eof) => break,
+                Ok(_) => None,
+                Err(e) => return Err(e),
+            };
+
+            if let Some(piece) = piece {
+                match text {
+                    Some(ref mut t) => {
+                        t.push(' ');
+                        t.push_str(&piece);
+                    }
+                    None => text = Some(piece),
+                }
+            }
+            buf.clear();
+        }
+
+        Ok(DeepText(text))
+    }
+}
+
+impl FromXmlWithReader for Person {
+    /// It reads `<name>` from an Atom person construct, or the plain text of an RSS `<author>`
+    ///
+    /// Arguments:
+    ///
+    /// * `bufs`: &BufPool
+    /// * `reader`: &mut Reader<B>
+    ///
+    /// Returns:
+    ///
+    /// A Person
+    fn from_xml_with_reader<B: std::io::BufRead>(
+        bufs: &BufPool,
+        reader: &mut Reader<B>,
+    ) -> quick_xml::Result<Self> {
+        let mut buf = bufs.pop();
+        let mut person = Person::default();
+
+        loop {
+            match reader.read_event(&mut buf) {
+                Ok(Event::Start(ref e)) => match reader.decode(e.local_name())? {
+                    "name" => person.name = TextOrCData::from_xml_with_reader(bufs, reader)?,
+                    _ => {
+                        SkipThisElement::from_xml_with_reader(bufs, reader)?;
+                    }
+                },
+                Ok(Event::Text(ref e)) => person.name = Some(unescape_text(reader, e)?),
+                Ok(Event::CData(ref e)) => person.name = Some(reader.decode(e)?.to_string()),
+                Ok(Event::End(_) | Event::Eof) => break,
+                Ok(_) => {}
+                Err(e) => return Err(e),
+            }
+            buf.clear();
+        }
+
+        Ok(person)
+    }
+}
+
+/// 反转义文本, 遇到不认识的实体(比如 HTML 的 `&nbsp;`)就保留原文
+fn unescape_text<B: std::io::BufRead>(
+    reader: &Reader<B>,
+    e: &BytesText,
+) -> quick_xml::Result<String> {
+    match e.unescape_and_decode(reader) {
+        Ok(s) => Ok(s),
+        Err(_) => Ok(reader.decode(e)?.to_string()),
+    }
+}
+
 impl FromXmlWithReader for NumberData {
     /// It reads the XML file, and if it finds a number, it returns it
     ///
@@ -223,11 +336,7 @@ pub fn reader_get_sub_node_str<B: std::io::BufRead>(
     reader.check_end_names(false);
     loop {
         match reader.read_event(&mut buf) {
-            Ok(Event::End(ref e)) => {
-                if reader.decode(e.local_name())? == "item" {
-                    break;
-                }
-            }
+            Ok(Event::End(ref e)) if reader.decode(e.local_name())? == "item" => break,
             Ok(Event::Eof) => break,
             _ => (),
         }
@@ -0,0 +1,316 @@
+use regex::Regex;
+use scraper::{ElementRef, Html, Selector};
+use thiserror::Error;
+use url::{form_urlencoded, Url};
+
+use super::feed::Feed;
+use super::item::FeedPost;
+
+// 监控网页变化: `watch:https://example.com/news#css=ul.news+li`
+pub const WATCH_SCHEME: &str = "watch:";
+
+const CSS_KEY: &str = "css";
+const REGEX_KEY: &str = "regex";
+
+// 一个网页最多取多少条
+const MAX_WATCH_ITEMS: usize = 50;
+// 标题的最大字数, 正文按原样保留
+const TITLE_MAX_CHARS: usize = 80;
+
+#[derive(Error, Debug)]
+pub enum WatchError {
+    #[error("invalid CSS selector {0}: {1}")]
+    Selector(String, String),
+    #[error("invalid regex: {0}")]
+    Regex(#[from] regex::Error),
+    #[error("nothing on the page matches {0}")]
+    NothingMatched(String),
+}
+
+/// 怎么从网页里取出条目
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Extractor {
+    // 每个匹配的元素是一条, 链接取元素自己或者里面第一个 `<a href>`
+    Css(String),
+    // 每个匹配是一条, 可以用命名分组 `title` 和 `link` 指定标题和链接
+    Regex(String),
+}
+
+impl std::fmt::Display for Extractor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Extractor::Css(s) => write!(f, "{}={}", CSS_KEY, s),
+            Extractor::Regex(s) => write!(f, "{}={}", REGEX_KEY, s),
+        }
+    }
+}
+
+/// 一个被监控的网页, 匹配到的元素会变成订阅源里的文章
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageWatch {
+    pub url: String,
+    pub extractor: Extractor,
+}
+
+impl PageWatch {
+    /// 检查选择器或者正则能不能编译
+    pub fn new(url: &str, extractor: Extractor) -> Result<Self, WatchError> {
+        match extractor {
+            Extractor::Css(ref s) => {
+                Selector::parse(s)
+                    .map_err(|e| WatchError::Selector(s.to_owned(), e.to_string()))?;
+            }
+            Extractor::Regex(ref s) => {
+                Regex::new(s)?;
+            }
+        }
+        Ok(Self {
+            url: url.to_owned(),
+            extractor,
+        })
+    }
+
+    /// 解析 `watch:{网页地址}#css={选择器}` 或者 `watch:{网页地址}#regex={正则}`
+    pub fn from_url(s: &str) -> Option<Self> {
+        let (url, fragment) = s.trim().strip_prefix(WATCH_SCHEME)?.split_once('#')?;
+        let url = Url::parse(url)
+            .ok()
+            .filter(|u| matches!(u.scheme(), "http" | "https"))?;
+
+        let (key, value) = form_urlencoded::parse(fragment.as_bytes()).next()?;
+        let extractor = match key.as_ref() {
+            CSS_KEY => Extractor::Css(value.into_owned()),
+            REGEX_KEY => Extractor::Regex(value.into_owned()),
+            _ => return None,
+        };
+        Self::new(url.as_str(), extractor).ok()
+    }
+
+    /// 存进数据库的订阅地址
+    pub fn to_url(&self) -> String {
+        let (key, value) = match self.extractor {
+            Extractor::Css(ref s) => (CSS_KEY, s),
+            Extractor::Regex(ref s) => (REGEX_KEY, s),
+        };
+        let fragment = form_urlencoded::Serializer::new(String::new())
+            .append_pair(key, value)
+            .finish();
+        format!("{}{}#{}", WATCH_SCHEME, self.url, fragment)
+    }
+
+    /// 按选择器或者正则把网页变成订阅源.
+    /// 文章的 guid 由链接和正文算出来, 内容变了就会被当成新文章推送.
+    ///
+    /// Arguments:
+    ///
+    /// * `html`: 网页内容
+    /// * `base`: 网页的地址, 用来补全相对链接
+    pub fn extract(&self, html: &str, base: &Url) -> Result<Feed, WatchError> {
+        let document = Html::parse_document(html);
+        let posts = match self.extractor {
+            Extractor::Css(ref s) => {
+                let selector = Selector::parse(s)
+                    .map_err(|e| WatchError::Selector(s.to_owned(), e.to_string()))?;
+                document
+                    .select(&selector)
+                    .filter_map(|el| {
+                        let text = plain_text(el.text());
+                        let link = element_link(el).and_then(|href| base.join(href).ok());
+                        post(None, link.map(|l| l.to_string()), text, base)
+                    })
+                    .take(MAX_WATCH_ITEMS)
+                    .collect::<Vec<FeedPost>>()
+            }
+            Extractor::Regex(ref s) => {
+                let regex = Regex::new(s)?;
+                regex
+                    .captures_iter(html)
+                    .filter_map(|c| {
+                        let text = crate::utils::html_to_plain_text(&c[0], usize::MAX);
+                        let title = c
+                            .name("title")
+                            .map(|t| crate::utils::html_to_plain_text(t.as_str(), usize::MAX));
+                        let link = c
+                            .name("link")
+                            .and_then(|l| base.join(&l.as_str().replace("&amp;", "&")).ok());
+                        post(title, link.map(|l| l.to_string()), text, base)
+                    })
+                    .take(MAX_WATCH_ITEMS)
+                    .collect::<Vec<FeedPost>>()
+            }
+        };
+
+        if posts.is_empty() {
+            return Err(WatchError::NothingMatched(self.extractor.to_string()));
+        }
+
+        let title = Selector::parse("title")
+            .ok()
+            .and_then(|s| document.select(&s).next())
+            .map(|t| plain_text(t.text()))
+            .filter(|t| !t.is_empty())
+            .unwrap_or_else(|| self.url.to_owned());
+
+        Ok(Feed {
+            title,
+            link: self.url.to_owned(),
+            description: Some(format!("{} ({})", self.url, self.extractor)),
+            posts,
+            ..Default::default()
+        })
+    }
+}
+
+// 元素自己是链接就用自己的, 否则用里面第一个链接
+fn element_link<'a>(el: ElementRef<'a>) -> Option<&'a str> {
+    if let Some(href) = el.value().attr("href") {
+        return Some(href);
+    }
+    let selector = Selector::parse("a[href]").ok()?;
+    let link = el.select(&selector).next()?;
+    link.value().attr("href")
+}
+
+fn plain_text<'a>(text: impl Iterator<Item = &'a str>) -> String {
+    text.flat_map(|t| t.split_whitespace())
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+// 没有链接的条目链接到网页本身, 不然推送和摘要都没法带上它
+fn post(title: Option<String>, link: Option<String>, text: String, page: &Url) -> Option<FeedPost> {
+    if text.is_empty() && link.is_none() {
+        return None;
+    }
+
+    let title = title.filter(|t| !t.is_empty()).unwrap_or_else(|| {
+        match text.char_indices().nth(TITLE_MAX_CHARS) {
+            Some((idx, _)) => format!("{}…", &text[..idx]),
+            None => text.to_owned(),
+        }
+    });
+    Some(FeedPost {
+        guid: Some(crate::utils::hash((&link, &text))),
+        title: Some(title),
+        link: link.or_else(|| Some(page.to_string())),
+        description: Some(text),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PAGE: &str = r#"<!DOCTYPE html>
+<html><head><title> 公告 - Example </title></head>
+<body>
+<ul class="news">
+  <li><a href="/news/1">维护公告</a> <span>3 月 1 日</span></li>
+  <li><a href="https://other.example.org/2?a=1&amp;b=2">新版本发布</a></li>
+  <li>没有链接的条目</li>
+  <li>  </li>
+</ul>
+<a class="more" href="/news">更多</a>
+</body></html>"#;
+
+    #[test]
+    fn test_url() {
+        let watch = PageWatch::new(
+            "https://example.com/news",
+            Extractor::Css("ul.news li".to_owned()),
+        )
+        .unwrap();
+        let url = watch.to_url();
+        assert_eq!(url, "watch:https://example.com/news#css=ul.news+li");
+        assert_eq!(PageWatch::from_url(&url), Some(watch));
+
+        let watch = PageWatch::new(
+            "https://example.com/",
+            Extractor::Regex(r#"<h2>(?P<title>.*?)</h2>#"#.to_owned()),
+        )
+        .unwrap();
+        assert_eq!(PageWatch::from_url(&watch.to_url()), Some(watch));
+
+        assert_eq!(PageWatch::from_url("watch:https://example.com/"), None);
+        assert_eq!(PageWatch::from_url("watch:ftp://example.com/#css=li"), None);
+        assert_eq!(
+            PageWatch::from_url("watch:https://example.com/#xpath=li"),
+            None
+        );
+        assert_eq!(
+            PageWatch::from_url("watch:https://example.com/#css=li[["),
+            None
+        );
+        assert!(PageWatch::new("https://example.com/", Extractor::Regex("(".to_owned())).is_err());
+    }
+
+    #[test]
+    fn test_extract_css() {
+        let base = Url::parse("https://example.com/index.html").unwrap();
+        let watch = PageWatch::new(base.as_str(), Extractor::Css("ul.news li".to_owned())).unwrap();
+        let feed = watch.extract(PAGE, &base).unwrap();
+
+        assert_eq!(feed.title, "公告 - Example");
+        assert_eq!(feed.posts.len(), 3);
+        assert_eq!(feed.posts[0].title.as_deref(), Some("维护公告 3 月 1 日"));
+        assert_eq!(
+            feed.posts[0].link.as_deref(),
+            Some("https://example.com/news/1")
+        );
+        assert_eq!(
+            feed.posts[1].link.as_deref(),
+            Some("https://other.example.org/2?a=1&b=2")
+        );
+        assert_eq!(feed.posts[2].link.as_deref(), Some(base.as_str()));
+        assert_eq!(feed.posts[2].title.as_deref(), Some("没有链接的条目"));
+
+        // 内容变了 guid 就变了
+        let changed = PAGE.replace("3 月 1 日", "3 月 2 日");
+        let feed2 = watch.extract(&changed, &base).unwrap();
+        assert_ne!(feed.posts[0].guid, feed2.posts[0].guid);
+        assert_eq!(feed.posts[1].guid, feed2.posts[1].guid);
+
+        // 元素自己就是链接
+        let watch = PageWatch::new(base.as_str(), Extractor::Css("a.more".to_owned())).unwrap();
+        let feed = watch.extract(PAGE, &base).unwrap();
+        assert_eq!(
+            feed.posts[0].link.as_deref(),
+            Some("https://example.com/news")
+        );
+
+        let watch = PageWatch::new(base.as_str(), Extractor::Css("table".to_owned())).unwrap();
+        assert!(matches!(
+            watch.extract(PAGE, &base),
+            Err(WatchError::NothingMatched(_))
+        ));
+    }
+
+    #[test]
+    fn test_extract_regex() {
+        let base = Url::parse("https://example.com/").unwrap();
+        let watch = PageWatch::new(
+            base.as_str(),
+            Extractor::Regex(r#"<a href="(?P<link>[^"]+)">(?P<title>[^<]+)</a>"#.to_owned()),
+        )
+        .unwrap();
+        let feed = watch.extract(PAGE, &base).unwrap();
+        assert_eq!(feed.posts.len(), 2);
+        assert_eq!(feed.posts[0].title.as_deref(), Some("维护公告"));
+        assert_eq!(
+            feed.posts[0].link.as_deref(),
+            Some("https://example.com/news/1")
+        );
+        assert_eq!(
+            feed.posts[1].link.as_deref(),
+            Some("https://other.example.org/2?a=1&b=2")
+        );
+
+        // 没有命名分组就用整个匹配
+        let watch =
+            PageWatch::new(base.as_str(), Extractor::Regex(r"\d+ 月 \d+ 日".to_owned())).unwrap();
+        let feed = watch.extract(PAGE, &base).unwrap();
+        assert_eq!(feed.posts[0].title.as_deref(), Some("3 月 1 日"));
+        assert_eq!(feed.posts[0].link.as_deref(), Some(base.as_str()));
+    }
+}
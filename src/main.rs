@@ -1,10 +1,9 @@
 use anyhow::bail;
-use tracing::instrument::WithSubscriber;
 
 use std::path::PathBuf;
 use structopt::StructOpt;
 
-use tracing::{debug, error, info, warn, Level};
+use tracing::{error, info, Level};
 
 use crate::api::http::init_kook_client;
 use crate::conf::Config;
@@ -15,12 +14,16 @@ mod api;
 mod conf;
 mod data;
 mod db;
+mod digest;
 mod fetch;
 mod network_frame;
 mod network_runtime;
+mod opml;
 mod push;
 mod runtime;
+mod schedule;
 mod utils;
+mod websub;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "ksbot", about = "A simple Kook RSS bot.")]
@@ -45,10 +48,10 @@ async fn main1() -> Result<(), anyhow::Error> {
 
     let conf = parse_conf()?;
 
-    init_rss_client(None);
+    init_rss_client(conf.fetch_conf());
     init_kook_client(conf.bot_conf());
 
-    let mut ksbot_runtime = runtime::KsbotRuntime::new();
+    let mut ksbot_runtime = runtime::KsbotRuntime::new(conf.runtime_conf());
     let mut network_runtime = BotNetworkRuntime::init(conf.bot_conf());
 
     info!("ksbot starting ...");
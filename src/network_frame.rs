@@ -16,14 +16,14 @@ pub const WS_RESUME: u8 = 4;
 pub const WS_RECONNECT: u8 = 5;
 pub const WS_RESUME_ACK: u8 = 6;
 
-//// 数据中的状态代码
+// 数据中的状态代码
 //pub const WS_DATA_CODE_OK: u64 = 0;
 //pub const WS_DATA_CODE_MISS_PARAM: u64 = 40100;
 //pub const WS_DATA_CODE_INVAILD_TOKEN: u64 = 40101;
 //pub const WS_DATA_CODE_TOKEN_VALID_FAIL: u64 = 40102;
 //pub const WS_DATA_CODE_TOKEN_EXPIRE: u64 = 40103;
 
-//// 数据字段
+// 数据字段
 //pub const WS_DATA_CODE_FIELD: &str = "code";
 //pub const WS_DATA_HELLO_SESSION_ID_FIELD: &str = "session_id";
 
@@ -174,7 +174,7 @@ impl<T> PartialEq for KookWSFrame<T> {
 impl<T> PartialOrd for KookWSFrame<T> {
     #[inline]
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.sn.cmp(&other.sn))
+        Some(self.cmp(other))
     }
 }
 
@@ -25,6 +25,7 @@ use tokio::time::timeout;
 use tokio::{net::TcpStream, time::sleep};
 use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub enum BotNetworkEvent {
     Connect(),
@@ -54,6 +55,7 @@ enum BotState {
 // 机器人运行时，基础设施
 pub struct BotNetworkRuntime {
     state: BotState,
+    #[allow(dead_code)]
     conf: BotConfig,
     store_f: File,
 
@@ -87,6 +89,7 @@ impl BotNetworkRuntime {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(&conf.store_path)
             .expect("文件(创建/打开)出错");
         let mut f_content = "".to_owned();
@@ -97,7 +100,7 @@ impl BotNetworkRuntime {
         let mut sn = 0;
         let mut gateway_url: Option<String> = None;
         let mut state = BotState::GetGateway;
-        if f_content.trim().is_empty() == false {
+        if !f_content.trim().is_empty() {
             let store = serde_json::from_str::<BotStore>(&f_content).unwrap_or_else(|_| panic!("序列化机器人持久化文件错误, 如果反复出现该错误可删除该文件。(默认的持久化文件名：{})", BOT_STORE_FILE_PATH));
             // 从文件中读取
             session_id = Some(store.session_id);
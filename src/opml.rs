@@ -0,0 +1,263 @@
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use thiserror::Error;
+
+use crate::data::SubscribeFeed;
+use crate::fetch;
+
+// 一次最多导入多少个订阅源
+pub const MAX_OUTLINES: usize = 200;
+
+#[derive(Error, Debug)]
+pub enum OpmlError {
+    #[error("OPML 格式错误 (第 {position} 字节): {source}")]
+    Malformed {
+        position: usize,
+        source: quick_xml::Error,
+    },
+    #[error("不是 OPML 文件, 根元素是 <{0}>")]
+    NotOpml(String),
+    #[error("OPML 里没有订阅源")]
+    Empty,
+    #[error("OPML 里的订阅源太多了, 一次最多导入 {0} 个")]
+    TooMany(usize),
+}
+
+/// OPML 里的一个订阅源: `<outline xmlUrl="...">`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outline {
+    pub title: Option<String>,
+    pub xml_url: String,
+}
+
+/// 解析 OPML, 分类 (嵌套的 outline) 会被展开, 没有 `xmlUrl` 的 outline 会被跳过.
+///
+/// Arguments:
+///
+/// * `body`: OPML 文件内容
+///
+/// Returns:
+///
+/// 所有订阅源, 按文件里的顺序, 重复的只保留第一个
+pub fn parse(body: &[u8]) -> Result<Vec<Outline>, OpmlError> {
+    let mut reader = Reader::from_reader(body);
+    reader.trim_text(true);
+    let mut buf = vec![];
+
+    let malformed = |reader: &Reader<&[u8]>, source| OpmlError::Malformed {
+        position: reader.buffer_position(),
+        source,
+    };
+
+    let mut outlines: Vec<Outline> = vec![];
+    let mut has_root = false;
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(ref e) | Event::Empty(ref e)) => {
+                if !has_root {
+                    let name = reader.decode(e.name()).map_err(|e| malformed(&reader, e))?;
+                    if name != "opml" {
+                        return Err(OpmlError::NotOpml(name.to_owned()));
+                    }
+                    has_root = true;
+                    continue;
+                }
+                if e.name() != b"outline" {
+                    continue;
+                }
+
+                let (mut text, mut title, mut xml_url) = (None, None, None);
+                for attr in e.attributes() {
+                    let attr =
+                        attr.map_err(|e| malformed(&reader, quick_xml::Error::InvalidAttr(e)))?;
+                    let value = attr
+                        .unescape_and_decode_value(&reader)
+                        .map_err(|e| malformed(&reader, e))?;
+                    let value = Some(value.trim().to_owned()).filter(|v| !v.is_empty());
+                    match attr.key {
+                        b"text" => text = value,
+                        b"title" => title = value,
+                        b"xmlUrl" => xml_url = value,
+                        _ => (),
+                    }
+                }
+
+                if let Some(xml_url) = xml_url {
+                    if !outlines.iter().any(|o| o.xml_url == xml_url) {
+                        outlines.push(Outline {
+                            title: title.or(text),
+                            xml_url,
+                        });
+                    }
+                }
+            }
+            Ok(Event::Eof) => break,
+            Ok(Event::Text(_)) if !has_root => return Err(OpmlError::NotOpml("#text".to_owned())),
+            Ok(_) => (),
+            Err(e) => return Err(malformed(&reader, e)),
+        }
+        buf.clear();
+    }
+
+    if !has_root {
+        return Err(OpmlError::NotOpml("".to_owned()));
+    }
+    match outlines.len() {
+        0 => Err(OpmlError::Empty),
+        n if n > MAX_OUTLINES => Err(OpmlError::TooMany(MAX_OUTLINES)),
+        _ => Ok(outlines),
+    }
+}
+
+/// 把订阅源导出成 OPML 2.0, RSSHub 路由导出成实例上的地址 (不带访问密钥),
+/// 本地文件, 命令和网页监控别的阅读器拉取不了, 不会导出.
+///
+/// Arguments:
+///
+/// * `title`: OPML 的标题
+/// * `feeds`: 订阅源
+///
+/// Returns:
+///
+/// OPML 文件内容, 以及没有导出的订阅源
+pub fn export<'a>(title: &str, feeds: &'a [SubscribeFeed]) -> (String, Vec<&'a SubscribeFeed>) {
+    let mut opml =
+        String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<opml version=\"2.0\">\n");
+    opml.push_str(&format!(
+        "  <head>\n    <title>{}</title>\n    <dateCreated>{}</dateCreated>\n  </head>\n  <body>\n",
+        escape(title),
+        chrono::Utc::now().to_rfc2822()
+    ));
+
+    let mut skipped = vec![];
+    for feed in feeds {
+        let xml_url = match fetch::shareable_url(&feed.subscribe_url) {
+            Some(url) => url,
+            None => {
+                skipped.push(feed);
+                continue;
+            }
+        };
+        let title = escape(&feed.title);
+        opml.push_str(&format!(
+            "    <outline type=\"rss\" text=\"{}\" title=\"{}\" xmlUrl=\"{}\"",
+            title,
+            title,
+            escape(&xml_url)
+        ));
+        if !feed.link.is_empty() {
+            opml.push_str(&format!(" htmlUrl=\"{}\"", escape(&feed.link)));
+        }
+        opml.push_str("/>\n");
+    }
+
+    opml.push_str("  </body>\n</opml>\n");
+    (opml, skipped)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let s: &[u8] = include_bytes!("../test/data/subscriptions.opml");
+        let outlines = parse(s).unwrap();
+        assert_eq!(
+            outlines,
+            vec![
+                Outline {
+                    title: Some("Rust Blog".to_owned()),
+                    xml_url: "https://blog.rust-lang.org/feed.xml".to_owned(),
+                },
+                Outline {
+                    title: Some("只有 text".to_owned()),
+                    xml_url: "https://example.com/feed?a=1&b=2".to_owned(),
+                },
+                Outline {
+                    title: None,
+                    xml_url: "https://example.org/atom.xml".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_error() {
+        assert!(matches!(
+            parse(include_bytes!("../test/data/rss_2.0.xml")),
+            Err(OpmlError::NotOpml(root)) if root == "rss"
+        ));
+        assert!(matches!(
+            parse(b"<opml><body></body></opml>"),
+            Err(OpmlError::Empty)
+        ));
+        assert!(matches!(
+            parse(b"<opml><body><outline xmlUrl=\"a\"></body></opml>"),
+            Err(OpmlError::Malformed { .. })
+        ));
+        assert!(matches!(parse(b"hello"), Err(OpmlError::NotOpml(_))));
+        assert!(matches!(parse(b""), Err(OpmlError::NotOpml(_))));
+    }
+
+    #[test]
+    fn test_export() {
+        let feeds = vec![
+            SubscribeFeed {
+                title: "A & \"B\"".to_owned(),
+                link: "https://example.com/".to_owned(),
+                subscribe_url: "https://example.com/feed?a=1&b=2".to_owned(),
+                ..Default::default()
+            },
+            SubscribeFeed {
+                title: "没有主页".to_owned(),
+                subscribe_url: "https://example.org/atom.xml".to_owned(),
+                ..Default::default()
+            },
+            SubscribeFeed {
+                title: "RSSHub".to_owned(),
+                subscribe_url: "rsshub:/3dm/news".to_owned(),
+                ..Default::default()
+            },
+            SubscribeFeed {
+                title: "本地文件".to_owned(),
+                subscribe_url: "file:///srv/feeds/a.xml".to_owned(),
+                ..Default::default()
+            },
+            SubscribeFeed {
+                title: "网页监控".to_owned(),
+                subscribe_url: "watch:https://example.com/#css=li".to_owned(),
+                ..Default::default()
+            },
+        ];
+
+        let (opml, skipped) = export("ksbot <测试>", &feeds);
+        assert!(opml.contains("<title>ksbot &lt;测试&gt;</title>"));
+        assert!(opml.contains("xmlUrl=\"https://example.com/feed?a=1&amp;b=2\""));
+        assert!(opml.contains("htmlUrl=\"https://example.com/\""));
+
+        // 导出的文件能再导入回来
+        let outlines = parse(opml.as_bytes()).unwrap();
+        assert_eq!(outlines.len(), 3);
+        assert_eq!(outlines[0].title.as_deref(), Some("A & \"B\""));
+        assert_eq!(outlines[0].xml_url, "https://example.com/feed?a=1&b=2");
+        assert_eq!(outlines[1].title.as_deref(), Some("没有主页"));
+        // RSSHub 路由导出成实例上的地址
+        assert_eq!(outlines[2].xml_url, "https://rsshub.app/3dm/news");
+
+        // 别的阅读器拉取不了的不导出
+        let skipped = skipped
+            .iter()
+            .map(|f| f.title.as_str())
+            .collect::<Vec<&str>>();
+        assert_eq!(skipped, vec!["本地文件", "网页监控"]);
+    }
+}
@@ -1,70 +1,206 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     sync::{Arc, Mutex},
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
-    api::http, fetch::item::FeedPost, network_frame::KookEventMessage, runtime::KsbotError, utils,
+    api::{http, ApiError},
+    conf::RuntimeConfig,
+    fetch::date,
+    fetch::item::FeedPost,
+    network_frame::KookEventMessage,
+    runtime::KsbotError,
+    schedule, utils,
+    utils::ExponentRegress,
+    websub,
 };
 use anyhow::bail;
 use once_cell::sync::Lazy;
 use regex::Regex;
 use tracing::*;
 
+use tokio::sync::Notify;
+
 use crate::{
-    data::SubscribeFeed,
+    data::{self, DigestEntry, OutboxMessage, SubscribeFeed},
     db::Database,
-    fetch::{self, pull_feed},
+    fetch::{self, feed::Feed, pull_feed_if_modified, CacheValidator, FeedResponse},
 };
 
+// 推送消息里摘要的最大字数
+const SUMMARY_MAX_CHARS: usize = 120;
+
+// 推送失败之后第一次等多久重试, 秒
+const DELIVERY_RETRY_BASE: u64 = 5;
+// 重试最多等多久, 秒
+const DELIVERY_RETRY_MAX: u64 = 60 * 60;
+const MAX_RETRY_EXPONENT: u32 = 16;
+// 推送失败多少次之后放进死信, 大约是三个多小时
+const MAX_DELIVERY_ATTEMPTS: u32 = 12;
+// 没有新消息的时候多久检查一次等待重试的消息
+const DELIVERY_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+// 有新消息进入推送队列时叫醒投递任务
+static DELIVERY_WAKEUP: Lazy<Notify> = Lazy::new(Notify::new);
+
+// 每个订阅源一把锁, 同一个订阅源的推送一个接一个来
+static FEED_LOCKS: Lazy<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
 // 存放已经编译好的正则表达式
 static REGEX_FILTER_MAP: Lazy<Mutex<HashMap<String, Regex>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
 
-#[tracing::instrument(skip(db))]
-pub async fn push_update(db: Arc<Database>, feed: SubscribeFeed) -> Result<(), anyhow::Error> {
+#[tracing::instrument(skip(db, conf))]
+pub async fn push_update(
+    db: Arc<Database>,
+    conf: Arc<RuntimeConfig>,
+    feed: SubscribeFeed,
+) -> Result<(), anyhow::Error> {
     info!("pull {}", &feed.subscribe_url);
-    let new_rss = match pull_feed(&feed.subscribe_url).await {
-        Ok(f) => f,
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+
+    let (resp, moved_to) = match pull_feed_if_modified(
+        &feed.subscribe_url,
+        &feed.cache_validator(),
+        &feed.fetch_options,
+    )
+    .await
+    {
+        Ok(r) => r,
+        // 服务端要求慢一点, 等到它说的时间再拉取, 不算拉取失败
+        Err(fetch::FeedError::RateLimited(limited)) => {
+            let retry_after = limited.retry_after.as_secs().max(1);
+            info!(
+                "订阅源被限流, {} 秒后再拉取: {}",
+                retry_after, feed.subscribe_url
+            );
+            db.update_feed_next_fetch(&feed.subscribe_url, now + retry_after)?;
+            return Ok(());
+        }
         Err(e) => {
-            let now = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards")
-                .as_secs();
-            let expired = feed.down_time + 60 * 60 * 24 * 7; // 一周都不可用就是过期了.
-            if expired < now {
-                // TODO: 提醒该删除订阅源了
-                warn!(
-                    "该订阅源已经超过一周不可用了，建议删除: {}",
-                    feed.subscribe_url
-                );
+            let failed =
+                db.update_feed_failure(&feed.subscribe_url, e.to_string(), &conf.schedule, now)?;
+            let notified = if failed.suspended_at.is_some() && feed.suspended_at.is_none() {
+                warn!("订阅源太久没有拉取成功, 暂停拉取: {}", feed.subscribe_url);
+                push_failure(&db, &failed, true, now)
+            } else if failed.failure_count == conf.schedule.failure_notify {
+                push_failure(&db, &failed, false, now)
+            } else {
+                Ok(())
+            };
+            // 通知发不出去也不能盖住拉取失败的原因
+            if let Err(ne) = notified {
+                error!("拉取失败的通知放进推送队列失败: {}", ne);
             }
-            bail!("Failed to pull feed: {:?}", e)
+            bail!("Failed to pull feed {}: {}", feed.subscribe_url, e)
         }
     };
 
-    let new_feed = SubscribeFeed::from_old(&feed, &new_rss);
-    let old_feed = db.update_or_create_feed(&new_feed)?.unwrap(); // 更新
+    let (new_rss, validator) = match resp {
+        FeedResponse::Modified(f, v) => (f, v),
+        FeedResponse::NotModified => {
+            info!("订阅源未修改(304): {}", feed.subscribe_url);
+            let feed = migrate_if_moved(&db, &conf, feed, moved_to.as_deref(), None)?;
+            db.update_feed_down_time(&feed.subscribe_url, now)?;
+            db.update_feed_schedule(&feed.subscribe_url, 0, &conf.schedule, now)?;
+            return Ok(());
+        }
+    };
 
-    // 取出新的文章index
-    let new_indexs = &mut new_feed.diff_post_index(&old_feed);
-    if new_indexs.is_empty() {
-        info!("订阅源无更新: {}", new_feed.subscribe_url);
-        return Ok(());
+    let feed = migrate_if_moved(
+        &db,
+        &conf,
+        feed,
+        moved_to.as_deref(),
+        new_rss.atom_link.as_deref(),
+    )?;
+    push_new_posts(db, conf, &feed, &new_rss, validator).await
+}
+
+// 订阅源搬家了 (永久重定向, 或者声明了规范的 self 地址) 就把数据库里的记录搬到新地址
+fn migrate_if_moved(
+    db: &Database,
+    conf: &RuntimeConfig,
+    feed: SubscribeFeed,
+    moved_to: Option<&str>,
+    self_link: Option<&str>,
+) -> Result<SubscribeFeed, anyhow::Error> {
+    match data::canonical_url(&feed.subscribe_url, moved_to, self_link) {
+        // 凭据是给原来的站点的, 不能跟着搬到别的站点, 继续用旧地址拉取 (重定向的时候不会带上凭据)
+        Some(url)
+            if feed.fetch_options.has_credentials()
+                && !utils::is_same_origin(&feed.subscribe_url, &url) =>
+        {
+            warn!(
+                "订阅源搬到了别的站点, 带着凭据不搬家: {} => {}",
+                feed.subscribe_url, url
+            );
+            Ok(feed)
+        }
+        Some(url) => {
+            info!("订阅源搬家: {} => {}", feed.subscribe_url, url);
+            let (migrated, dropped) = db.migrate_feed(&feed.subscribe_url, &url)?;
+            // 旧地址在 hub 那边的订阅用不上了, 退订掉
+            if let (Some(lease), Some(websub_conf)) = (dropped, conf.websub.to_owned()) {
+                let old_url = feed.subscribe_url;
+                tokio::spawn(async move {
+                    if let Err(e) = websub::unsubscribe(&websub_conf, &old_url, &lease).await {
+                        error!("{}", e);
+                    }
+                });
+            }
+            Ok(migrated)
+        }
+        None => Ok(feed),
     }
+}
 
-    info!("new: {:?},old: {:?}", new_feed, old_feed);
+/// 对比推送记录, 把订阅源里的新文章推送到订阅了它的频道.
+/// 轮询拉取到的和 WebSub hub 推过来的订阅源都走这里.
+///
+/// Arguments:
+///
+/// * `feed`: 数据库里的订阅源
+/// * `new_rss`: 刚拿到的订阅源内容
+/// * `validator`: 下次条件请求用的校验信息
+pub async fn push_new_posts(
+    db: Arc<Database>,
+    conf: Arc<RuntimeConfig>,
+    feed: &SubscribeFeed,
+    new_rss: &Feed,
+    validator: CacheValidator,
+) -> Result<(), anyhow::Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
 
-    let chans = db.feed_channel_list(&new_feed.subscribe_url)?;
-    for ch in chans {
-        let regex_str_op = ch.feed_regex.get(&utils::hash(&old_feed.subscribe_url));
+    // 轮询和 WebSub 可能同时推送同一个订阅源, 对比推送记录到写入之间不能插进别的推送
+    let lock = feed_lock(&feed.subscribe_url);
+    let _guard = lock.lock().await;
 
-        // 倒序一下，让新文章排在后面
-        new_indexs.reverse();
-        for idx in &*new_indexs {
-            let post = &new_rss.posts[*idx];
+    // 取出新的文章index
+    let post_hashes = data::post_hashes(new_rss);
+    let new_indexs = match feed.record_only {
+        true => {
+            info!("订阅源还没有推送记录, 这次只记录: {}", feed.subscribe_url);
+            vec![]
+        }
+        false => db.new_post_indexs(&feed.subscribe_url, &post_hashes)?,
+    };
+
+    let new_posts = sort_new_posts(&new_rss.posts, &new_indexs, conf.max_post_age);
+    let mut messages = vec![];
+    let mut digests = vec![];
+    for ch in db.feed_channel_list(&feed.subscribe_url)? {
+        let regex_str_op = ch.feed_regex.get(&utils::hash(&feed.subscribe_url));
 
+        for post in &new_posts {
             // 是否需要过滤
             if let Some(reg_str) = regex_str_op {
                 if !reg_str.trim().is_empty() && is_filter_post(post, reg_str) {
@@ -73,27 +209,273 @@ pub async fn push_update(db: Arc<Database>, feed: SubscribeFeed) -> Result<(), a
                 }
             }
 
-            info!("推送: {:?} => {}", post.title, &ch.id);
-            push_post(&ch.id, post).await?;
+            // 摘要模式的频道先攒着, 到点再合成一条发出去
+            if ch.digest.is_some() {
+                if let Some(ref link) = post.link {
+                    info!("攒进摘要: {:?} => {}", post.title, &ch.id);
+                    digests.push(DigestEntry {
+                        channel_id: ch.id.to_owned(),
+                        subscribe_url: feed.subscribe_url.to_owned(),
+                        feed_title: new_rss.title.to_owned(),
+                        title: post.title.to_owned(),
+                        link: link.to_owned(),
+                        created_at: now,
+                    });
+                }
+                continue;
+            }
+
+            if let Some(content) = render_post(post) {
+                info!("推送: {:?} => {}", post.title, &ch.id);
+                messages.push(OutboxMessage::new(
+                    &ch.id,
+                    &feed.subscribe_url,
+                    content,
+                    now,
+                ));
+            }
+        }
+    }
+
+    // 订阅源, 推送记录和推送队列一起写入, 推送交给 `deliver_outbox`
+    let update = |current: &mut SubscribeFeed| {
+        *current = SubscribeFeed::from_old(current, new_rss, validator.to_owned());
+        schedule::reschedule(current, new_indexs.len(), &conf.schedule, now);
+    };
+    if !db.commit_posts(
+        &feed.subscribe_url,
+        update,
+        &post_hashes,
+        now,
+        &messages,
+        &digests,
+    )? {
+        info!("订阅源在拉取期间被删除了: {}", feed.subscribe_url);
+        return Ok(());
+    }
+    if !messages.is_empty() {
+        wake_delivery();
+    }
+
+    let purged =
+        db.purge_seen_posts(&feed.subscribe_url, now.saturating_sub(conf.seen_retention))?;
+    if purged > 0 {
+        info!("清理了 {} 条过期的推送记录: {}", purged, feed.subscribe_url);
+    }
+
+    if new_indexs.is_empty() {
+        info!("订阅源无更新: {}", feed.subscribe_url);
+    }
+    Ok(())
+}
+
+// 取订阅源的锁, 顺便清理没人拿着的锁
+fn feed_lock(subscribe_url: &str) -> Arc<tokio::sync::Mutex<()>> {
+    let mut locks = FEED_LOCKS.lock().unwrap();
+    locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+    locks.entry(subscribe_url.to_owned()).or_default().clone()
+}
+
+/// 推送队列的投递任务, 启动时先把上次没推送完的消息发出去.
+/// 同一个频道的消息按顺序推送, 失败的消息按次数退避重试, 推送成功之后才会从队列里删掉.
+pub async fn deliver_outbox(db: Arc<Database>) {
+    loop {
+        if let Err(e) = deliver_due(&db).await {
+            error!("推送队列出错: {}", e);
+        }
+
+        tokio::select! {
+            _ = DELIVERY_WAKEUP.notified() => {}
+            _ = tokio::time::sleep(DELIVERY_POLL_INTERVAL) => {}
+        }
+    }
+}
+
+/// 记下一次推送失败. 重试也不会成功的错误, 或者重试太多次了, 就放进死信交给管理员处理,
+/// 其他错误按失败次数推迟重试.
+///
+/// Returns:
+///
+/// 消息是不是放进了死信
+fn delivery_failed(
+    db: &Database,
+    key: &str,
+    msg: &mut OutboxMessage,
+    e: &anyhow::Error,
+    now: u64,
+) -> Result<bool, anyhow::Error> {
+    msg.attempts += 1;
+    msg.last_error = Some(e.to_string());
+
+    let api_error = e.downcast_ref::<ApiError>();
+    if api_error.is_some_and(|e| e.is_permanent()) || msg.attempts >= MAX_DELIVERY_ATTEMPTS {
+        let id = db.move_to_dead(key, msg)?;
+        error!(
+            "推送到 {} 失败 {} 次 (错误码 {:?}), 放进死信 #{}: {}",
+            msg.channel_id,
+            msg.attempts,
+            api_error.map(|e| e.code),
+            id,
+            e
+        );
+        return Ok(true);
+    }
+
+    msg.next_attempt = now + retry_delay(msg.attempts);
+    warn!(
+        "推送到 {} 失败 {} 次, {} 秒后重试: {}",
+        msg.channel_id,
+        msg.attempts,
+        msg.next_attempt - now,
+        e
+    );
+    db.update_outbox(key, msg)?;
+    Ok(false)
+}
+
+// 推送到点的消息, 一个频道里有消息在等待重试的话, 这个频道后面的消息也要等
+async fn deliver_due(db: &Database) -> Result<(), anyhow::Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+
+    let mut blocked = HashSet::new();
+    // 频道 => (进入死信的数量, 最后的错误)
+    let mut dead: HashMap<String, (usize, String)> = HashMap::new();
+    for (key, mut msg) in db.outbox_list()? {
+        if blocked.contains(&msg.channel_id) {
+            continue;
+        }
+        if msg.next_attempt > now {
+            blocked.insert(msg.channel_id);
+            continue;
+        }
+
+        match http::message_create(
+            msg.content.to_owned(),
+            msg.channel_id.to_owned(),
+            None,
+            None,
+        )
+        .await
+        {
+            Ok(()) => db.remove_outbox(&key)?,
+            Err(e) => match delivery_failed(db, &key, &mut msg, &e, now)? {
+                true => {
+                    let entry = dead.entry(msg.channel_id).or_insert((0, String::new()));
+                    entry.0 += 1;
+                    entry.1 = e.to_string();
+                }
+                false => {
+                    blocked.insert(msg.channel_id);
+                }
+            },
         }
     }
 
+    // 告诉频道有文章没推送出去, 频道没了或者没有权限的话这条通知也发不出去, 只记日志
+    for (channel_id, (count, error)) in dead {
+        let content = format!(
+            "有 {} 篇文章没能推送到这个频道: {}\n已经交给管理员处理",
+            count, error
+        );
+        if let Err(e) = http::message_create(content, channel_id.to_owned(), None, None).await {
+            warn!("通知频道 {} 推送失败: {}", channel_id, e);
+        }
+    }
     Ok(())
 }
 
+/// 叫醒投递任务, 比如死信放回推送队列之后
+pub fn wake_delivery() {
+    DELIVERY_WAKEUP.notify_one();
+}
+
+// 第一次失败等 5 秒, 之后每次翻倍, 最多一个小时
+fn retry_delay(attempts: u32) -> u64 {
+    let eg = ExponentRegress::from_base(2);
+    eg.forward((attempts.clamp(1, MAX_RETRY_EXPONENT) - 1) as usize);
+    (DELIVERY_RETRY_BASE * eg.get() as u64 / 2).min(DELIVERY_RETRY_MAX)
+}
+
 pub async fn push_post(chan_id: &str, item: &fetch::item::FeedPost) -> Result<(), anyhow::Error> {
-    if item.link.is_none() {
-        return Ok(());
+    if let Some(content) = render_post(item) {
+        http::message_create(content, chan_id.to_owned(), None, None).await?;
     }
+    Ok(())
+}
+
+/// 文章渲染成 KMarkdown 消息, 没有链接的文章不推送
+fn render_post(item: &fetch::item::FeedPost) -> Option<String> {
+    item.link.as_ref()?;
 
-    let content = format!(
+    let mut content = format!(
         "**{}** \n > {}",
         item.title.as_ref().unwrap_or(&"".to_owned()),
         item.link.as_ref().unwrap()
     );
 
-    http::message_create(content, chan_id.to_owned(), None, None).await?;
+    // 解析得了就统一格式, 解析不了原样显示
+    let date = match item.date_time() {
+        Some(d) => Some(d.format("%Y-%m-%d %H:%M %:z").to_string()),
+        None => item.date().map(|d| d.to_owned()),
+    };
+    if let Some(date) = date {
+        content.push_str(&format!("\n(font){}(font)[secondary]", date));
+    }
+
+    if let Some(text) = item.abstract_text() {
+        let summary = utils::html_to_plain_text(text, SUMMARY_MAX_CHARS);
+        if !summary.is_empty() {
+            content.push('\n');
+            content.push_str(&summary);
+        }
+    }
+
+    if let Some(media) = item.media() {
+        let name = match media.media_type() {
+            Some("video") => "视频",
+            _ => "音频",
+        };
+        content.push_str(&format!("\n[{}]({})", name, media.url));
+    }
+
+    if let Some(thumbnail) = item.thumbnail() {
+        content.push_str(&format!("\n[图片]({})", thumbnail.url));
+    }
 
+    Some(content)
+}
+
+/// 通知订阅了这个订阅源的频道: 订阅源连续拉取失败, 或者已经被暂停.
+/// 通知和文章一样走推送队列, 一个频道发不出去不影响别的频道.
+fn push_failure(
+    db: &Database,
+    feed: &SubscribeFeed,
+    suspended: bool,
+    now: u64,
+) -> Result<(), anyhow::Error> {
+    let error = feed.last_error.as_deref().unwrap_or_default();
+    let content = if suspended {
+        format!(
+            "订阅源 [{}] {} 太久没有拉取成功, 已暂停拉取: {}\n修复之后发送 `resume {}` 恢复, 不需要的话可以退订",
+            feed.title, feed.subscribe_url, error, feed.subscribe_url
+        )
+    } else {
+        format!(
+            "订阅源 [{}] {} 已经连续 {} 次拉取失败: {}",
+            feed.title, feed.subscribe_url, feed.failure_count, error
+        )
+    };
+
+    let messages = db
+        .feed_channel_list(&feed.subscribe_url)?
+        .into_iter()
+        .map(|ch| OutboxMessage::new(&ch.id, &feed.subscribe_url, content.to_owned(), now))
+        .collect::<Vec<OutboxMessage>>();
+    db.enqueue_outbox(&messages)?;
+    wake_delivery();
     Ok(())
 }
 
@@ -111,11 +493,47 @@ pub async fn push_error(
     chan_id: String,
     quote: Option<String>,
 ) -> Result<(), anyhow::Error> {
-    http::message_create(format!("{:?}", err), chan_id, None, quote).await?;
+    http::message_create(err.to_string(), chan_id, None, quote).await?;
 
     Ok(())
 }
 
+/// 按发布时间从旧到新排列新文章, 并去掉太旧的文章.
+///
+/// 没有日期 (或者日期解析不了) 的文章保留, 按订阅源里的倒序排在最前面.
+///
+/// Arguments:
+///
+/// * `posts`: 订阅源的全部文章
+/// * `indexs`: 新文章的下标
+/// * `max_age`: 文章的最大年龄, 秒
+///
+/// Returns:
+///
+/// 需要推送的文章
+fn sort_new_posts<'a>(
+    posts: &'a [FeedPost],
+    indexs: &[usize],
+    max_age: Option<u64>,
+) -> Vec<&'a FeedPost> {
+    let mut dated = indexs
+        .iter()
+        .rev()
+        .map(|idx| (&posts[*idx], posts[*idx].date_time()))
+        .filter(|(post, date)| match (date, max_age) {
+            (Some(d), Some(max_age)) if date::age_secs(d) > max_age => {
+                info!("文章太旧了, 不推送: {:?} {}", post.title, d);
+                false
+            }
+            _ => true,
+        })
+        .collect::<Vec<_>>();
+
+    // 稳定排序, 日期相同的保持原来的顺序
+    dated.sort_by_key(|(_, date)| *date);
+    dated.into_iter().map(|(post, _)| post).collect()
+}
+
 fn is_filter_post(t: &FeedPost, reg_str: &str) -> bool {
     let mut filter_map = REGEX_FILTER_MAP.lock().unwrap();
     let reg = match filter_map.get(reg_str) {
@@ -130,3 +548,371 @@ fn is_filter_post(t: &FeedPost, reg_str: &str) -> bool {
     let title = t.title.to_owned().unwrap_or_default();
     reg.is_match(&title)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1), 5);
+        assert_eq!(retry_delay(2), 10);
+        assert_eq!(retry_delay(3), 20);
+        assert_eq!(retry_delay(10), 2560);
+        assert_eq!(retry_delay(11), DELIVERY_RETRY_MAX);
+        assert_eq!(retry_delay(u32::MAX), DELIVERY_RETRY_MAX);
+    }
+
+    #[test]
+    fn test_delivery_failed() {
+        let db = Database::temporary();
+        let url = "http://example.com/feed";
+        let feed = SubscribeFeed {
+            subscribe_url: url.to_owned(),
+            ..Default::default()
+        };
+        db.channel_subscribed("chan", feed).unwrap();
+        let messages = ["a", "b"]
+            .iter()
+            .map(|c| OutboxMessage::new("chan", url, c.to_string(), 100))
+            .collect::<Vec<OutboxMessage>>();
+        db.commit_posts(url, |_| (), &[], 100, &messages, &[])
+            .unwrap();
+        let outbox = db.outbox_list().unwrap();
+
+        // 暂时的接口错误按失败次数推迟重试
+        let (key, mut msg) = outbox[0].to_owned();
+        let e = anyhow::Error::from(ApiError {
+            code: 50000,
+            message: "服务器错误".to_owned(),
+        });
+        assert!(!delivery_failed(&db, &key, &mut msg, &e, 1000).unwrap());
+        let (_, retry) = db.outbox_list().unwrap().remove(0);
+        assert_eq!(retry.attempts, 1);
+        assert_eq!(retry.next_attempt, 1000 + DELIVERY_RETRY_BASE);
+        assert_eq!(retry.last_error.as_deref(), Some("服务器错误"));
+        assert!(db.dead_letters().unwrap().is_empty());
+
+        // 没有权限, 重试也没用
+        let (key, mut msg) = outbox[1].to_owned();
+        let e = anyhow::Error::from(ApiError {
+            code: 40300,
+            message: "没有权限".to_owned(),
+        });
+        assert!(delivery_failed(&db, &key, &mut msg, &e, 1000).unwrap());
+        assert_eq!(1, db.outbox_list().unwrap().len());
+        assert_eq!("b", db.dead_letters().unwrap()[0].1.content);
+    }
+
+    // 多少小时之前发布的文章
+    fn post_hours_ago(title: &str, hours: Option<i64>) -> FeedPost {
+        FeedPost {
+            title: Some(title.to_owned()),
+            published: hours
+                .map(|h| (chrono::Utc::now() - chrono::Duration::hours(h)).to_rfc3339()),
+            ..Default::default()
+        }
+    }
+
+    fn titles(posts: Vec<&FeedPost>) -> Vec<&str> {
+        posts
+            .into_iter()
+            .map(|p| p.title.as_deref().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_sort_new_posts() {
+        // 订阅源里一般是新的在前面
+        let posts = vec![
+            post_hours_ago("new", Some(1)),
+            post_hours_ago("old", Some(2)),
+            post_hours_ago("undated", None),
+            post_hours_ago("ancient", Some(24 * 365)),
+            post_hours_ago("seen", Some(3)),
+        ];
+        let week = Some(60 * 60 * 24 * 7);
+
+        // 旧的先推送, 没有日期的排在最前面, 太旧的不推送
+        assert_eq!(
+            vec!["undated", "old", "new"],
+            titles(sort_new_posts(&posts, &[0, 1, 2, 3], week))
+        );
+        // 不限制年龄的话都推送
+        assert_eq!(
+            vec!["undated", "ancient", "old", "new"],
+            titles(sort_new_posts(&posts, &[0, 1, 2, 3], None))
+        );
+        // 只推送新文章
+        assert_eq!(
+            vec!["seen", "new"],
+            titles(sort_new_posts(&posts, &[0, 4], week))
+        );
+        assert!(sort_new_posts(&posts, &[], week).is_empty());
+
+        // 日期相同或者都没有日期的, 按订阅源里从后往前的顺序
+        let posts = vec![post_hours_ago("b", None), post_hours_ago("a", None)];
+        assert_eq!(
+            vec!["a", "b"],
+            titles(sort_new_posts(&posts, &[0, 1], week))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_push_update_rate_limited() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        fetch::init_test_client();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut req = vec![0; 4096];
+                let _ = socket.read(&mut req).await.unwrap();
+                socket
+                    .write_all(b"HTTP/1.1 429 Too Many Requests\r\nRetry-After: 120\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                    .await
+                    .unwrap();
+                socket.shutdown().await.unwrap();
+            }
+        });
+
+        let db = Arc::new(Database::temporary());
+        let conf = Arc::new(RuntimeConfig::default());
+        let feed = SubscribeFeed {
+            subscribe_url: format!("http://{}/feed.xml", addr),
+            failure_count: 2,
+            ..Default::default()
+        };
+        db.channel_subscribed("chan", feed.to_owned()).unwrap();
+
+        let now = crate::runtime::unix_now();
+        push_update(db.clone(), conf, feed.to_owned())
+            .await
+            .unwrap();
+
+        // 按 Retry-After 推迟, 失败次数不变
+        let limited = db
+            .feed_by_hash(&utils::hash(&feed.subscribe_url))
+            .unwrap()
+            .unwrap();
+        assert!((now + 120..=now + 121).contains(&limited.next_fetch));
+        assert_eq!(limited.failure_count, 2);
+        assert_eq!(limited.last_error, None);
+    }
+
+    #[tokio::test]
+    async fn test_push_failure() {
+        fetch::init_test_client();
+        let db = Arc::new(Database::temporary());
+        let conf = Arc::new(RuntimeConfig::default());
+        let feed = SubscribeFeed {
+            title: "内网".to_owned(),
+            subscribe_url: "http://169.254.169.254/feed.xml".to_owned(),
+            failure_count: conf.schedule.failure_notify - 1,
+            down_time: crate::runtime::unix_now(),
+            ..Default::default()
+        };
+        db.channel_subscribed("chan", feed.to_owned()).unwrap();
+        db.channel_subscribed("chan2", feed.to_owned()).unwrap();
+
+        // 拉取失败的原因原样返回, 通知放进每个频道的推送队列
+        let err = push_update(db.clone(), conf, feed.to_owned())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains(&feed.subscribe_url), "{}", err);
+        let outbox = db.outbox_list().unwrap();
+        let mut channels = outbox
+            .iter()
+            .map(|(_, m)| m.channel_id.as_str())
+            .collect::<Vec<&str>>();
+        channels.sort();
+        assert_eq!(channels, vec!["chan", "chan2"]);
+        let notice = format!("连续 {} 次拉取失败", feed.failure_count + 1);
+        assert!(outbox
+            .iter()
+            .all(|(_, m)| m.subscribe_url == feed.subscribe_url && m.content.contains(&notice)));
+    }
+
+    #[tokio::test]
+    async fn test_push_serialized() {
+        let db = Arc::new(Database::temporary());
+        let conf = Arc::new(RuntimeConfig::default());
+        let feed = SubscribeFeed {
+            subscribe_url: "http://example.com/feed.xml".to_owned(),
+            ..Default::default()
+        };
+        db.channel_subscribed("chan", feed.to_owned()).unwrap();
+        db.mark_posts_seen(&feed.subscribe_url, [utils::hash("old")].iter(), 0)
+            .unwrap();
+        let rss =
+            crate::fetch::parse_feed(None, include_bytes!("../test/data/atom_1.0.xml").to_vec())
+                .unwrap();
+
+        // 别的推送拿着锁的时候只能等着
+        let lock = feed_lock(&feed.subscribe_url);
+        let guard = lock.lock().await;
+        let pushes = (0..2)
+            .map(|_| {
+                let (db, conf, feed, rss) =
+                    (db.clone(), conf.clone(), feed.to_owned(), rss.clone());
+                tokio::spawn(async move {
+                    push_new_posts(db, conf, &feed, &rss, CacheValidator::default()).await
+                })
+            })
+            .collect::<Vec<_>>();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(db.outbox_list().unwrap().is_empty());
+
+        // 一个接一个推送, 第二次已经都是见过的文章了
+        drop(guard);
+        for push in pushes {
+            push.await.unwrap().unwrap();
+        }
+        assert_eq!(2, db.outbox_list().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_push_record_only() {
+        let db = Arc::new(Database::temporary());
+        let conf = Arc::new(RuntimeConfig::default());
+        let rss =
+            crate::fetch::parse_feed(None, include_bytes!("../test/data/atom_1.0.xml").to_vec())
+                .unwrap();
+
+        // 旧版本存下来的订阅源没有这个字段, 第一次拉取只记录
+        let legacy: SubscribeFeed = serde_json::from_str(
+            r#"{"subscribe_url":"http://example.com/legacy.xml","link":"","title":"","down_time":0,"ttl":null,"channel_ids":[],"etag":null,"last_modified":null,"last_error":null,"suspended_at":null,"self_link":null,"hub":null,"websub":null,"post_interval":null,"last_post_time":null,"update_period":null}"#,
+        )
+        .unwrap();
+        assert!(legacy.record_only);
+        db.channel_subscribed("chan", legacy.to_owned()).unwrap();
+        push_new_posts(
+            db.clone(),
+            conf.clone(),
+            &legacy,
+            &rss,
+            CacheValidator::default(),
+        )
+        .await
+        .unwrap();
+        assert!(db.outbox_list().unwrap().is_empty());
+        let legacy = db
+            .feed_by_hash(&utils::hash(&legacy.subscribe_url))
+            .unwrap()
+            .unwrap();
+        assert!(!legacy.record_only);
+
+        // 订阅的时候还是空的订阅源, 第一批文章照常推送
+        let empty = SubscribeFeed {
+            subscribe_url: "http://example.com/empty.xml".to_owned(),
+            ..Default::default()
+        };
+        db.channel_subscribed("chan", empty.to_owned()).unwrap();
+        push_new_posts(db.clone(), conf, &empty, &rss, CacheValidator::default())
+            .await
+            .unwrap();
+        assert_eq!(2, db.outbox_list().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_push_watch_feed() {
+        let db = Arc::new(Database::temporary());
+        let conf = Arc::new(RuntimeConfig::default());
+
+        let page = url::Url::parse("https://example.com/news").unwrap();
+        let watch =
+            fetch::PageWatch::new(page.as_str(), fetch::Extractor::Css("li".to_owned())).unwrap();
+        let html = r#"<ul><li><a href="/news/1">维护公告</a></li><li>没有链接的条目</li></ul>"#;
+        let rss = watch.extract(html, &page).unwrap();
+
+        let feed = SubscribeFeed {
+            subscribe_url: watch.to_url(),
+            ..Default::default()
+        };
+        db.channel_subscribed("chan", feed.to_owned()).unwrap();
+        // 订阅的时候已经拉取过一次了
+        db.mark_posts_seen(&feed.subscribe_url, [utils::hash("old")].iter(), 0)
+            .unwrap();
+
+        push_new_posts(db.clone(), conf, &feed, &rss, CacheValidator::default())
+            .await
+            .unwrap();
+
+        // 没有链接的条目链接到网页本身, 也会推送. 网页上靠前的当成新的, 后推送
+        let contents = db
+            .outbox_list()
+            .unwrap()
+            .into_iter()
+            .map(|(_, msg)| msg.content)
+            .collect::<Vec<String>>();
+        assert_eq!(
+            contents,
+            vec![
+                "**没有链接的条目** \n > https://example.com/news\n没有链接的条目",
+                "**维护公告** \n > https://example.com/news/1\n维护公告",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_migrate_with_credentials() {
+        let db = Database::temporary();
+        let conf = RuntimeConfig::default();
+        let mut feed = SubscribeFeed {
+            subscribe_url: "https://example.com/feed.xml".to_owned(),
+            ..Default::default()
+        };
+        feed.fetch_options.set("cookie", Some("session=1")).unwrap();
+        db.channel_subscribed("chan", feed.to_owned()).unwrap();
+
+        // 带着凭据不能搬到别的站点
+        let moved = "https://other.example.org/feed.xml";
+        let feed = migrate_if_moved(&db, &conf, feed, Some(moved), None).unwrap();
+        assert_eq!(feed.subscribe_url, "https://example.com/feed.xml");
+        assert!(db.feed_by_hash(&utils::hash(moved)).unwrap().is_none());
+
+        // 同一个站点里可以搬
+        let moved = "https://example.com/atom.xml";
+        let feed = migrate_if_moved(&db, &conf, feed, Some(moved), None).unwrap();
+        assert_eq!(feed.subscribe_url, moved);
+        assert_eq!(feed.fetch_options.cookie.as_deref(), Some("session=1"));
+        assert!(db.feed_by_hash(&utils::hash(moved)).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_migrate_watch() {
+        let db = Database::temporary();
+        let conf = RuntimeConfig::default();
+        let feed = SubscribeFeed {
+            subscribe_url: "watch:https://example.com/old#css=li".to_owned(),
+            ..Default::default()
+        };
+        db.channel_subscribed("chan", feed.to_owned()).unwrap();
+
+        // 被监控的网页永久重定向了, 订阅搬到新网页, 选择器不变
+        let moved = "watch:https://example.com/news#css=li";
+        let feed = migrate_if_moved(&db, &conf, feed, Some(moved), None).unwrap();
+        assert_eq!(feed.subscribe_url, moved);
+        assert_eq!(
+            db.channel_feed_list("chan").unwrap()[0].subscribe_url,
+            moved
+        );
+    }
+
+    #[test]
+    fn test_render_post() {
+        let mut post = FeedPost {
+            title: Some("标题".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(render_post(&post), None);
+
+        post.link = Some("https://example.com/1".to_owned());
+        assert_eq!(
+            render_post(&post).as_deref(),
+            Some("**标题** \n > https://example.com/1")
+        );
+    }
+}
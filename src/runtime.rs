@@ -1,37 +1,63 @@
-use crate::api::http::{user_me, UserMe};
-use crate::data::SubscribeFeed;
+use crate::api::http::{self as kook, user_me, UserMe};
+use crate::conf::RuntimeConfig;
+use crate::data::{self, SubscribeFeed};
 use crate::db::{self, Database};
+use crate::fetch::FetchOptions;
 use crate::network_frame::KookEventMessage;
 use crate::network_runtime::BotNetworkEvent;
 use crate::push::{push_info, push_post};
-use crate::utils::{find_http_url, Throttle};
-use crate::{fetch, push};
+use crate::utils::{self, parse_feed_url, parse_http_url};
+use crate::{digest, fetch, opml, push, websub};
 use anyhow::anyhow;
 use futures_util::FutureExt;
 use futures_util::StreamExt;
 use once_cell::sync::Lazy;
 use regex::Regex;
-use std::cmp;
-use std::collections::HashMap;
-use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use thiserror::Error;
 use tokio::sync::{broadcast, Notify};
 use tokio_util::time::DelayQueue;
-use tracing::{debug, error, info, warn};
+use tracing::{error, info};
 
 const COMMAND_RSS: &str = "rss";
 const COMMAND_SUB: &str = "sub";
 const COMMAND_UNSUB: &str = "unsub";
 const COMMAND_REG: &str = "reg";
+const COMMAND_OPT: &str = "opt";
+const COMMAND_OPML: &str = "opml";
+const COMMAND_WATCH: &str = "watch";
+const COMMAND_RESUME: &str = "resume";
+const COMMAND_DLQ: &str = "dlq";
+const COMMAND_DIGEST: &str = "digest";
+
+const OPT_RESET: &str = "reset";
+const OPML_EXPORT: &str = "export";
+const OPML_IMPORT: &str = "import";
+const WATCH_REGEX: &str = "regex";
+const DLQ_LIST: &str = "list";
+const DLQ_RETRY: &str = "retry";
+const DLQ_DISCARD: &str = "discard";
+const DLQ_ALL: &str = "all";
+const DIGEST_OFF: &str = "off";
+
+// `dlq list` 最多列出多少条
+const DLQ_LIST_LIMIT: usize = 20;
+// 列出死信时消息内容最多显示多少字
+const DLQ_PREVIEW_CHARS: usize = 40;
+
+// 发了 `opml import` 之后, 多久之内上传的文件会被当成 OPML 导入
+const OPML_UPLOAD_TIMEOUT: Duration = Duration::from_secs(60 * 5);
 
 const SPACE: &str = " ";
 
+// 多久从数据库里找一次快到点的订阅源, 每个订阅源什么时候拉取见 `schedule`
 #[cfg(not(debug_assertions))]
-const FEED_REFRESH_INTERVAL: u32 = 60 * 3; // 默认3分钟拉一次
+const FEED_SCAN_INTERVAL: u64 = 60;
 
 #[cfg(debug_assertions)]
-const FEED_REFRESH_INTERVAL: u32 = 60 / 5; // 调试拉的快一点，12秒一次
+const FEED_SCAN_INTERVAL: u64 = 60 / 5; // 调试找的快一点，12秒一次
 
 #[derive(Error, Debug)]
 pub enum KsbotError {
@@ -43,50 +69,111 @@ pub enum KsbotError {
     Anyhow(#[from] anyhow::Error),
     #[error("订阅错误: {0}")]
     Feed(#[from] fetch::FeedError),
+    #[allow(dead_code)]
     #[error("平台消息错误: {0}")]
     KookMessage(String),
     #[error("数据库错误: {0}")]
     Database(#[from] db::StoreError),
+    #[error("选项错误: {0}")]
+    FetchOption(#[from] fetch::FetchOptionError),
+    #[error("{0}")]
+    Opml(#[from] opml::OpmlError),
+    #[error("网页里有多个订阅源, 请直接订阅其中一个")]
+    MultipleFeeds,
+    #[error("网页监控错误: {0}")]
+    Watch(#[from] fetch::WatchError),
+    #[error("摘要设置错误: {0}")]
+    Digest(#[from] digest::DigestError),
+    #[error("只有管理员可以使用这个命令")]
+    NotAdmin,
+    #[error("只有管理员可以设置选项: {0}")]
+    AdminOnlyOption(String),
+    #[error("死信编号应该是数字或者 all: {0}")]
+    InvalidDeadLetter(String),
 }
 
 pub struct KsbotRuntime {
     me_info: Option<UserMe>,
     db: Lazy<Arc<Database>>,
+    conf: Arc<RuntimeConfig>,
+    // 等待上传 OPML 文件的用户: (频道, 用户) => 截止时间
+    pending_imports: Mutex<HashMap<(String, String), Instant>>,
 }
 
 impl KsbotRuntime {
-    pub fn new() -> Self {
+    pub fn new(conf: RuntimeConfig) -> Self {
         Self {
             me_info: None,
             db: Lazy::new(|| Arc::new(Database::from_path(None))),
+            conf: Arc::new(conf),
+            pending_imports: Mutex::new(HashMap::new()),
         }
     }
 
     #[tracing::instrument(skip(self))]
     fn help(&self) -> String {
         "rss        - 显示当前订阅的 RSS 列表
-sub        - 订阅一个 RSS: /sub http://example.com/feed.xml
+sub        - 订阅一个 RSS: /sub http://example.com/feed.xml (也可以是网页地址, RSSHub 路由 rsshub:/3dm/news, 或者管理员开放的 file:// 和 exec: 订阅源)
 unsub      - 退订一个 RSS: /unsub http://example.com/feed.xml
 reg        - 设置过滤正则: /reg http://example.com/feed.xml (华为|蒂法)
+opt        - 设置拉取选项: /opt http://example.com/feed.xml cookie a=1; b=2
+             选项: header cookie bearer basic ua proxy insecure, 不填值就是清除, reset 清除全部
+             除了 ua 都只有管理员可以查看和设置
+             订阅时也可以带上选项: /sub http://example.com/feed.xml bearer=token ua=ksbot
+watch      - 监控网页变化: /watch https://example.com/news ul.news li (CSS 选择器)
+             也可以用正则: /watch https://example.com/news regex <h2>(?P<title>.*?)</h2>
+digest     - 新文章攒成摘要定时发送: /digest every 2 (小时), /digest daily 09:00 +08:00
+             /digest off 改回一篇一篇推送, /digest 查看当前设置
+resume     - 恢复因为太久拉取失败被暂停的订阅源: /resume http://example.com/feed.xml
+dlq        - 管理员处理推送失败的消息: /dlq list, /dlq retry <编号|all>, /dlq discard <编号|all>
+opml       - 导出订阅: /opml export
+             导入订阅: /opml import http://example.com/subscriptions.opml
+             不带地址的话, 5 分钟内在频道里上传 OPML 文件就会导入
 "
         .to_owned()
     }
 
     // 订阅
-    #[tracing::instrument(skip(self))]
+    #[tracing::instrument(skip(self, args))]
     async fn command_sub(&self, msg: &KookEventMessage, args: &[&str]) -> Result<(), KsbotError> {
         let url = args[0];
 
-        let subscribe_url = match find_http_url(url) {
+        let subscribe_url = match parse_feed_url(url) {
             Some(u) => u,
             None => return Err(KsbotError::NotUrl(url.to_owned())),
         };
+        let subscribe_url = subscribe_url.as_str();
+
+        // 订阅时带的选项: key=value
+        let mut options = FetchOptions::default();
+        for opt in &args[1..] {
+            let (key, value) = opt.split_once('=').unwrap_or((opt, ""));
+            if fetch::is_admin_only(key) && !self.is_admin(msg) {
+                return Err(KsbotError::AdminOnlyOption(key.to_owned()));
+            }
+            options.set(key, Some(value))?;
+        }
 
         let channel = msg.target_id.to_owned().unwrap();
-        let rss = fetch::pull_feed(subscribe_url).await?;
-        info!("{} 订阅了 {}", channel, subscribe_url);
-        let feed = SubscribeFeed::from(subscribe_url, &rss);
-        self.db.channel_subscribed(&channel, feed)?;
+        let discovered = subscribe_feed(&self.db, &channel, subscribe_url, options).await?;
+        let (subscribe_url, rss) = match discovered {
+            fetch::Discovered::Feed(url, rss) => (url, rss),
+            fetch::Discovered::Candidates(links) => {
+                let list = links
+                    .iter()
+                    .enumerate()
+                    .map(|(i, l)| match l.title {
+                        Some(ref title) => format!("{}. [{}] {}", i + 1, title, l.url),
+                        None => format!("{}. {}", i + 1, l.url),
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n");
+                let reply = format!("这个网页有多个订阅源, 请选择一个订阅:\n{}", list);
+                push_info(&reply, msg).await?;
+                return Ok(());
+            }
+        };
+
         push_info(&format!("已订阅: {}", subscribe_url), msg).await?;
         if !&rss.posts.is_empty() {
             push_post(&channel, &rss.posts[0]).await?;
@@ -99,14 +186,29 @@ reg        - 设置过滤正则: /reg http://example.com/feed.xml (华为|蒂法
     async fn command_unsub(&self, msg: &KookEventMessage, args: &[&str]) -> Result<(), KsbotError> {
         let url = args[0];
 
-        let subscribe_url = match find_http_url(url) {
+        let subscribe_url = match parse_feed_url(url) {
             Some(u) => u,
             None => return Err(KsbotError::NotUrl(url.to_owned())),
         };
+        let subscribe_url = subscribe_url.as_str();
 
         let channel = msg.target_id.to_owned().unwrap();
+        let lease = self
+            .db
+            .feed_by_hash(&utils::hash(subscribe_url))?
+            .and_then(|f| f.websub);
         self.db.channel_unsubscribed(&channel, subscribe_url)?;
-        self.db.try_remove_feed(subscribe_url)?;
+        if self.db.try_remove_feed(subscribe_url)? {
+            // 订阅源没人订阅了, 也不需要 hub 再推送
+            if let (Some(lease), Some(conf)) = (lease, self.conf.websub.to_owned()) {
+                let subscribe_url = subscribe_url.to_owned();
+                tokio::spawn(async move {
+                    if let Err(e) = websub::unsubscribe(&conf, &subscribe_url, &lease).await {
+                        error!("{}", e);
+                    }
+                });
+            }
+        }
         push_info(&format!("已取消订阅: {}", subscribe_url), msg).await?;
         Ok(())
     }
@@ -116,10 +218,11 @@ reg        - 设置过滤正则: /reg http://example.com/feed.xml (华为|蒂法
         let url = args[0];
         let reg = args[1];
 
-        let subscribe_url = match find_http_url(url) {
+        let subscribe_url = match parse_feed_url(url) {
             Some(u) => u,
             None => return Err(KsbotError::NotUrl(url.to_owned())),
         };
+        let subscribe_url = subscribe_url.as_str();
 
         if let Err(e) = Regex::new(reg) {
             return Err(KsbotError::NotRegex(e));
@@ -128,10 +231,318 @@ reg        - 设置过滤正则: /reg http://example.com/feed.xml (华为|蒂法
         self.db
             .update_channel_feed_regex(&channel_id, subscribe_url, reg)?;
 
-        push_info(&format!("正则编译完成, 已启用."), msg).await?;
+        push_info("正则编译完成, 已启用.", msg).await?;
+        Ok(())
+    }
+
+    // 设置订阅源的拉取选项
+    #[tracing::instrument(skip(self, args))]
+    async fn command_opt(&self, msg: &KookEventMessage, args: &[&str]) -> Result<(), KsbotError> {
+        let url = args[0];
+
+        let subscribe_url = match parse_feed_url(url) {
+            Some(u) => u,
+            None => return Err(KsbotError::NotUrl(url.to_owned())),
+        };
+        let subscribe_url = subscribe_url.as_str();
+
+        let channel_id = msg.target_id.to_owned().unwrap();
+        let mut options = match self.db.feed_by_hash(&utils::hash(subscribe_url))? {
+            Some(feed) if feed.channel_ids.contains(&channel_id) => feed.fetch_options,
+            _ => return Err(db::StoreError::NotFoundFeed(subscribe_url.to_owned()).into()),
+        };
+
+        // 选项是订阅了这个订阅源的频道共用的, 凭据和代理只有管理员能看能改
+        let is_admin = self.is_admin(msg);
+
+        // 只有 URL 的时候就是查看选项
+        if let Some(key) = args.get(1) {
+            if *key == OPT_RESET {
+                if options.has_admin_only() && !is_admin {
+                    return Err(KsbotError::AdminOnlyOption(OPT_RESET.to_owned()));
+                }
+                options = FetchOptions::default();
+            } else {
+                if fetch::is_admin_only(key) && !is_admin {
+                    return Err(KsbotError::AdminOnlyOption(key.to_string()));
+                }
+                options.set(key, Some(&args[2..].join(SPACE)))?;
+            }
+            self.db
+                .update_feed_fetch_options(subscribe_url, options.to_owned())?;
+        }
+
+        let shown = match is_admin {
+            true => options.to_owned(),
+            false => options.public(),
+        };
+        let mut lines = shown.describe();
+        if shown != options {
+            lines.push("还有其他选项, 只有管理员可以查看".to_owned());
+        }
+        let reply = if lines.is_empty() {
+            format!("{} 没有设置任何选项", subscribe_url)
+        } else {
+            format!("{} 的选项:\n{}", subscribe_url, lines.join("\n"))
+        };
+        push_info(&reply, msg).await?;
+        Ok(())
+    }
+
+    // 恢复被暂停的订阅源, 也会清掉失败退避马上重新拉取
+    #[tracing::instrument(skip(self))]
+    async fn command_resume(
+        &self,
+        msg: &KookEventMessage,
+        args: &[&str],
+    ) -> Result<(), KsbotError> {
+        let url = args[0];
+
+        let subscribe_url = match parse_feed_url(url) {
+            Some(u) => u,
+            None => return Err(KsbotError::NotUrl(url.to_owned())),
+        };
+        let subscribe_url = subscribe_url.as_str();
+
+        let channel_id = msg.target_id.to_owned().unwrap();
+        let feed = match self.db.feed_by_hash(&utils::hash(subscribe_url))? {
+            Some(feed) if feed.channel_ids.contains(&channel_id) => feed,
+            _ => return Err(db::StoreError::NotFoundFeed(subscribe_url.to_owned()).into()),
+        };
+        self.db.resume_feed(subscribe_url)?;
+
+        let reply = if feed.suspended_at.is_some() {
+            format!("已恢复拉取: {}", subscribe_url)
+        } else {
+            format!("{} 没有被暂停, 马上重新拉取", subscribe_url)
+        };
+        push_info(&reply, msg).await?;
+        Ok(())
+    }
+
+    // 设置频道的摘要推送
+    #[tracing::instrument(skip(self))]
+    async fn command_digest(
+        &self,
+        msg: &KookEventMessage,
+        args: &[&str],
+    ) -> Result<(), KsbotError> {
+        let channel_id = msg.target_id.to_owned().unwrap();
+        let now = unix_now();
+
+        let reply = match args {
+            [] => {
+                let chan = self
+                    .db
+                    .channel_list()?
+                    .into_iter()
+                    .find(|c| c.id == channel_id);
+                match chan.and_then(|c| c.digest) {
+                    Some(d) => format!(
+                        "新文章攒成摘要发送: {}, 现在攒了 {} 篇",
+                        digest::describe(&d),
+                        self.db.digest_entries(&channel_id)?.len()
+                    ),
+                    None => "新文章会一篇一篇推送".to_owned(),
+                }
+            }
+            [DIGEST_OFF] => {
+                let count = digest::flush(&self.db, &channel_id, None, now)?;
+                format!("已改回一篇一篇推送, 攒着的 {} 篇文章马上发出", count)
+            }
+            _ => {
+                let schedule = digest::parse_schedule(args, self.conf.digest_utc_offset, now)?;
+                self.db.update_channel_digest(&channel_id, Some(schedule))?;
+                format!("之后新文章会攒成摘要发送: {}", digest::describe(&schedule))
+            }
+        };
+        push_info(&reply, msg).await?;
+        Ok(())
+    }
+
+    // 管理员查看, 重试或者丢弃推送失败的消息
+    #[tracing::instrument(skip(self))]
+    async fn command_dlq(&self, msg: &KookEventMessage, args: &[&str]) -> Result<(), KsbotError> {
+        if !self.is_admin(msg) {
+            return Err(KsbotError::NotAdmin);
+        }
+
+        let id = match args.get(1) {
+            None | Some(&DLQ_ALL) => None,
+            Some(id) => Some(
+                id.trim_start_matches('#')
+                    .parse::<u64>()
+                    .map_err(|_| KsbotError::InvalidDeadLetter(id.to_string()))?,
+            ),
+        };
+
+        let reply = match args.first().copied().unwrap_or(DLQ_LIST) {
+            DLQ_RETRY if args.len() == 2 => {
+                let count = self.db.retry_dead(id)?;
+                push::wake_delivery();
+                format!("{} 条消息已放回推送队列", count)
+            }
+            DLQ_DISCARD if args.len() == 2 => {
+                format!("丢弃了 {} 条消息", self.db.discard_dead(id)?)
+            }
+            _ => {
+                let letters = self.db.dead_letters()?;
+                if letters.is_empty() {
+                    "没有推送失败的消息".to_owned()
+                } else {
+                    let mut lines = letters
+                        .iter()
+                        .take(DLQ_LIST_LIMIT)
+                        .map(|(id, m)| {
+                            let preview = utils::strip_kmarkdown(&m.content);
+                            let preview = match preview.char_indices().nth(DLQ_PREVIEW_CHARS) {
+                                Some((idx, _)) => format!("{}…", &preview[..idx]),
+                                None => preview,
+                            };
+                            format!(
+                                "#{} 频道 {} 失败 {} 次: {}\n  {}",
+                                id,
+                                m.channel_id,
+                                m.attempts,
+                                m.last_error.as_deref().unwrap_or_default(),
+                                preview.replace('\n', " ")
+                            )
+                        })
+                        .collect::<Vec<String>>();
+                    if letters.len() > DLQ_LIST_LIMIT {
+                        lines.push(format!("还有 {} 条", letters.len() - DLQ_LIST_LIMIT));
+                    }
+                    lines.join("\n")
+                }
+            }
+        };
+        push_info(&reply, msg).await?;
+        Ok(())
+    }
+
+    // 监控没有订阅源的网页, 选择器 (或者正则) 匹配到的元素当成文章推送
+    #[tracing::instrument(skip(self, args))]
+    async fn command_watch(&self, msg: &KookEventMessage, args: &[&str]) -> Result<(), KsbotError> {
+        let url = args[0];
+
+        let page_url = match parse_http_url(url) {
+            Some(u) => u,
+            None => return Err(KsbotError::NotUrl(url.to_owned())),
+        };
+
+        // 选择器和正则里可以有空格
+        let extractor = match args[1] {
+            WATCH_REGEX if args.len() > 2 => {
+                fetch::Extractor::Regex(utils::strip_kmarkdown(&args[2..].join(SPACE)))
+            }
+            _ => fetch::Extractor::Css(utils::strip_kmarkdown(&args[1..].join(SPACE))),
+        };
+        let watch = fetch::PageWatch::new(&page_url, extractor)?;
+        self.command_sub(msg, &[&watch.to_url()]).await
+    }
+
+    // 导出导入订阅
+    #[tracing::instrument(skip(self, args))]
+    async fn command_opml(&self, msg: &KookEventMessage, args: &[&str]) -> Result<(), KsbotError> {
+        let channel_id = msg.target_id.to_owned().unwrap();
+
+        match (args[0], args.get(1)) {
+            (OPML_EXPORT, None) => {
+                let feeds = self.db.channel_feed_list(&channel_id)?;
+                if feeds.is_empty() {
+                    push_info("当前没有任何订阅, 没什么可导出的.", msg).await?;
+                    return Ok(());
+                }
+
+                let title = format!(
+                    "ksbot: {}",
+                    msg.channel_name.as_deref().unwrap_or(&channel_id)
+                );
+                let (body, skipped) = opml::export(&title, &feeds);
+                if skipped.len() < feeds.len() {
+                    let name = format!("ksbot-{}.opml", channel_id);
+                    let url = kook::asset_create(name, body.into_bytes()).await?;
+                    kook::message_create(
+                        url,
+                        channel_id,
+                        Some(kook::MESSAGE_TYPE_FILE),
+                        msg.msg_id.to_owned(),
+                    )
+                    .await?;
+                }
+
+                // 本地文件, 命令和网页监控别的阅读器拉取不了
+                if !skipped.is_empty() {
+                    let urls = skipped
+                        .iter()
+                        .map(|f| f.subscribe_url.as_str())
+                        .collect::<Vec<&str>>()
+                        .join("\n");
+                    push_info(
+                        &format!("这些订阅源只有 ksbot 能拉取, 没有导出:\n{}", urls),
+                        msg,
+                    )
+                    .await?;
+                }
+            }
+            (OPML_IMPORT, Some(url)) => {
+                let url = match parse_http_url(url) {
+                    Some(u) => u,
+                    None => return Err(KsbotError::NotUrl(url.to_string())),
+                };
+                self.spawn_import(msg, url);
+            }
+            (OPML_IMPORT, None) => {
+                let author_id = msg.author_id.to_owned().unwrap_or_default();
+                self.pending_imports.lock().unwrap().insert(
+                    (channel_id, author_id),
+                    Instant::now() + OPML_UPLOAD_TIMEOUT,
+                );
+                push_info("请在 5 分钟内在这个频道上传 OPML 文件.", msg).await?;
+            }
+            _ => (),
+        }
         Ok(())
     }
 
+    // 用户发了 `opml import` 之后上传的文件
+    fn take_pending_import(&self, msg: &KookEventMessage) -> bool {
+        if msg.typ != Some(kook::MESSAGE_TYPE_FILE as u64) {
+            return false;
+        }
+        let key = (
+            msg.target_id.to_owned().unwrap_or_default(),
+            msg.author_id.to_owned().unwrap_or_default(),
+        );
+
+        let mut pending = self.pending_imports.lock().unwrap();
+        let now = Instant::now();
+        pending.retain(|_, deadline| *deadline > now);
+        pending.remove(&key).is_some()
+    }
+
+    // 订阅源一个一个拉取比较慢, 放到后台导入, 完成之后回复结果
+    fn spawn_import(&self, msg: &KookEventMessage, url: String) {
+        let db = self.db.clone();
+        let msg = msg.to_owned();
+        tokio::spawn(async move {
+            let channel_id = msg.target_id.to_owned().unwrap();
+            let result = match import_opml(&db, &channel_id, &url).await {
+                Ok(report) => push_info(&report, &msg).await,
+                Err(e) => push::push_error(e, channel_id, msg.msg_id.to_owned()).await,
+            };
+            if let Err(e) = result {
+                error!("{}", e);
+            }
+        });
+    }
+
+    // 发消息的人是不是配置文件里的管理员
+    fn is_admin(&self, msg: &KookEventMessage) -> bool {
+        let author = msg.author_id.as_deref().unwrap_or_default();
+        self.conf.admins.iter().any(|a| a == author)
+    }
+
     #[tracing::instrument(skip(self))]
     async fn met_me(&self, msg: &KookEventMessage) -> Result<(), KsbotError> {
         push_info(&self.help(), msg).await?;
@@ -147,7 +558,14 @@ reg        - 设置过滤正则: /reg http://example.com/feed.xml (华为|蒂法
         if !feeds.is_empty() {
             let show_feeds = feeds
                 .iter()
-                .map(|s| format!("- [{}] {}", s.title, s.subscribe_url))
+                .map(|s| match (s.suspended_at, &s.last_error) {
+                    (Some(_), _) => format!("- [{}] {} (已暂停)", s.title, s.subscribe_url),
+                    (None, Some(e)) => format!(
+                        "- [{}] {} (连续 {} 次拉取失败: {})",
+                        s.title, s.subscribe_url, s.failure_count, e
+                    ),
+                    (None, None) => format!("- [{}] {}", s.title, s.subscribe_url),
+                })
                 .collect::<Vec<String>>();
             reply = show_feeds.join("\n");
         }
@@ -160,20 +578,43 @@ reg        - 设置过滤正则: /reg http://example.com/feed.xml (华为|蒂法
         &mut self,
         mut net_rece: broadcast::Receiver<BotNetworkEvent>,
     ) -> Result<(), KsbotError> {
-        let mut feed_interval =
-            tokio::time::interval(Duration::from_secs(FEED_REFRESH_INTERVAL as u64));
+        let mut feed_interval = tokio::time::interval(Duration::from_secs(FEED_SCAN_INTERVAL));
         let mut queue = FetchQueue::new();
-        let throttle = Throttle::new(FEED_REFRESH_INTERVAL as usize, None);
+
+        // 早期版本存的订阅地址没有规范化, 先搬到规范化之后的地址上
+        let normalized = self.db.normalize_feed_urls()?;
+        if normalized > 0 {
+            info!("规范化了 {} 个订阅源的地址", normalized);
+        }
+
+        // 推送队列里的消息由它发出去, 包括上次退出时没推送完的
+        tokio::spawn(push::deliver_outbox(self.db.clone()));
+        // 到点的摘要交给推送队列
+        tokio::spawn(digest::send_digests(self.db.clone()));
+
+        if self.conf.websub.is_some() {
+            let db = self.db.clone();
+            let conf = self.conf.clone();
+            tokio::spawn(async move {
+                if let Err(e) = websub::serve(db, conf).await {
+                    error!("WebSub 回调服务意外退出: {}", e);
+                }
+            });
+        }
 
         loop {
             tokio::select! {
-                feed = queue.next().fuse() => {
-                    let ifeed = feed.expect("unreachable");
-                    let opportunity = throttle.acquire();
+                (url, fetching) = queue.next().fuse() => {
+                    // 排队的时候订阅源可能被删掉了, 也可能被 WebSub 推送更新过改了拉取时间
+                    let ifeed = match self.db.feed_by_hash(&utils::hash(&url))? {
+                        Some(feed) if feed.next_fetch <= unix_now() && feed.suspended_at.is_none() => feed,
+                        _ => continue,
+                    };
                     let db = self.db.clone();
+                    let conf = self.conf.clone();
                     tokio::spawn(async move {
-                        opportunity.wait().await;
-                        if let Err(e) = push::push_update(db, ifeed).await {
+                        let _fetching = fetching;
+                        if let Err(e) = push::push_update(db, conf, ifeed).await {
                             error!("{}", e);
                         }
                     });
@@ -182,12 +623,35 @@ reg        - 设置过滤正则: /reg http://example.com/feed.xml (华为|蒂法
                 _ = feed_interval.tick() => {
                     info!("feed interval tick..");
                     let feeds = self.db.feed_list()?;
+                    let now = unix_now();
                     for feed in feeds {
-                        let feed_interval = cmp::max(
-                            feed.ttl.map(|ttl| ttl * 60).unwrap_or_default(),
-                            FEED_REFRESH_INTERVAL,
-                        ) as u64;
-                        queue.enqueue(feed, Duration::from_secs(feed_interval));
+                        if feed.suspended_at.is_some() {
+                            continue;
+                        }
+
+                        if let Some(ref conf) = self.conf.websub {
+                            if websub::needs_subscribe(&feed, now) {
+                                let db = self.db.clone();
+                                let conf = conf.to_owned();
+                                let feed = feed.to_owned();
+                                tokio::spawn(async move {
+                                    if let Err(e) = websub::subscribe(&db, &conf, &feed).await {
+                                        error!("{}", e);
+                                    }
+                                });
+                            }
+
+                            // hub 会推送更新, 不用轮询
+                            if websub::is_pushed_by_hub(&feed, now) {
+                                continue;
+                            }
+                        }
+
+                        // 只排下一轮扫描之前到点的, 拉取时间变了的话下一轮再排
+                        if feed.next_fetch < now + FEED_SCAN_INTERVAL {
+                            let delay = feed.next_fetch.saturating_sub(now);
+                            queue.enqueue(&feed.subscribe_url, Duration::from_secs(delay));
+                        }
                     }
                 },
 
@@ -242,6 +706,11 @@ reg        - 设置过滤正则: /reg http://example.com/feed.xml (华为|蒂法
 
         let content = msg.content.to_owned().unwrap_or_else(|| "".into());
 
+        if self.take_pending_import(msg) {
+            self.spawn_import(msg, content);
+            return Ok(());
+        }
+
         let channel_id = match msg.target_id {
             None => return Err(KsbotError::Anyhow(anyhow!("not have a channel id?"))),
             Some(ref s) => s,
@@ -267,25 +736,35 @@ reg        - 设置过滤正则: /reg http://example.com/feed.xml (华为|蒂法
         }
 
         match args[0] {
-            COMMAND_SUB => {
-                if args.len() == 2 && !channel_id.is_empty() {
-                    self.command_sub(msg, &args[1..]).await?;
-                }
+            COMMAND_SUB if args.len() >= 2 && !channel_id.is_empty() => {
+                self.command_sub(msg, &args[1..]).await?;
             }
-            COMMAND_UNSUB => {
-                if args.len() == 2 && !channel_id.is_empty() {
-                    self.command_unsub(msg, &args[1..]).await?;
-                }
+            COMMAND_UNSUB if args.len() == 2 && !channel_id.is_empty() => {
+                self.command_unsub(msg, &args[1..]).await?;
             }
-            COMMAND_REG => {
-                if args.len() == 3 && !channel_id.is_empty() {
-                    self.command_reg(msg, &args[1..]).await?;
-                }
+            COMMAND_REG if args.len() == 3 && !channel_id.is_empty() => {
+                self.command_reg(msg, &args[1..]).await?;
             }
-            COMMAND_RSS => {
-                if args.len() == 1 && !channel_id.is_empty() {
-                    self.command_rss(msg).await?;
-                }
+            COMMAND_OPT if args.len() >= 2 && !channel_id.is_empty() => {
+                self.command_opt(msg, &args[1..]).await?;
+            }
+            COMMAND_WATCH if args.len() >= 3 && !channel_id.is_empty() => {
+                self.command_watch(msg, &args[1..]).await?;
+            }
+            COMMAND_RESUME if args.len() == 2 && !channel_id.is_empty() => {
+                self.command_resume(msg, &args[1..]).await?;
+            }
+            COMMAND_DIGEST if args.len() <= 4 && !channel_id.is_empty() => {
+                self.command_digest(msg, &args[1..]).await?;
+            }
+            COMMAND_DLQ if args.len() <= 3 => {
+                self.command_dlq(msg, &args[1..]).await?;
+            }
+            COMMAND_OPML if (2..=3).contains(&args.len()) && !channel_id.is_empty() => {
+                self.command_opml(msg, &args[1..]).await?;
+            }
+            COMMAND_RSS if args.len() == 1 && !channel_id.is_empty() => {
+                self.command_rss(msg).await?;
             }
             _ => (),
         }
@@ -294,11 +773,111 @@ reg        - 设置过滤正则: /reg http://example.com/feed.xml (华为|蒂法
     }
 }
 
+/// 频道订阅一个订阅源, `sub` 命令和导入 OPML 都走这里.
+///
+/// Arguments:
+///
+/// * `channel`: 频道 ID
+/// * `url`: 订阅源或者网页的 URL
+/// * `options`: 拉取选项
+///
+/// Returns:
+///
+/// 订阅好的订阅源 (地址是最终存下来的地址), 或者网页里找到的多个订阅源 (这时候不会订阅)
+async fn subscribe_feed(
+    db: &Database,
+    channel: &str,
+    url: &str,
+    options: FetchOptions,
+) -> Result<fetch::Discovered, KsbotError> {
+    let (subscribe_url, rss) = match fetch::discover_feed(url, &options).await? {
+        fetch::Discovered::Feed(url, rss) => (url, rss),
+        candidates => return Ok(candidates),
+    };
+    // 用订阅源声明的 self 地址, 免得同一个订阅源的不同写法存成好几份
+    let subscribe_url = data::canonical_url(&subscribe_url, None, rss.atom_link.as_deref())
+        .unwrap_or(subscribe_url);
+
+    info!("{} 订阅了 {}", channel, subscribe_url);
+    // 新的订阅源, 现有的文章都算推送过了
+    if !db.contains_feed(&subscribe_url)? {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+        let hashes = data::post_hashes(&rss);
+        db.mark_posts_seen(&subscribe_url, hashes.iter().flatten(), now)?;
+    }
+    let mut feed = SubscribeFeed::from(&subscribe_url, &rss);
+    feed.fetch_options = options.to_owned();
+    db.channel_subscribed(channel, feed)?;
+    // 已经有别的频道订阅过了, 只覆盖这次带上的选项
+    if !options.is_empty() {
+        let mut merged = db
+            .feed_by_hash(&utils::hash(&subscribe_url))?
+            .map(|f| f.fetch_options)
+            .unwrap_or_default();
+        merged.merge(&options);
+        db.update_feed_fetch_options(&subscribe_url, merged)?;
+    }
+
+    Ok(fetch::Discovered::Feed(subscribe_url, rss))
+}
+
+/// 下载 OPML 文件, 订阅里面的每一个订阅源
+///
+/// Returns:
+///
+/// 导入报告: 成功和失败的数量, 以及每个失败的原因
+async fn import_opml(db: &Database, channel: &str, url: &str) -> Result<String, KsbotError> {
+    let body = fetch::download(url, &FetchOptions::default()).await?;
+    let outlines = opml::parse(&body)?;
+
+    let mut succeeded = 0;
+    let mut failures = vec![];
+    for outline in outlines {
+        let result = match parse_feed_url(&outline.xml_url) {
+            Some(u) => subscribe_feed(db, channel, &u, FetchOptions::default()).await,
+            None => Err(KsbotError::NotUrl(outline.xml_url.to_owned())),
+        };
+        match result {
+            Ok(fetch::Discovered::Feed(..)) => succeeded += 1,
+            Ok(fetch::Discovered::Candidates(_)) => {
+                failures.push((outline, KsbotError::MultipleFeeds))
+            }
+            Err(e) => failures.push((outline, e)),
+        }
+    }
+
+    let mut report = format!(
+        "导入完成: 成功 {} 个, 失败 {} 个",
+        succeeded,
+        failures.len()
+    );
+    for (outline, e) in failures {
+        match outline.title {
+            Some(title) => report.push_str(&format!("\n- [{}] {}: {}", title, outline.xml_url, e)),
+            None => report.push_str(&format!("\n- {}: {}", outline.xml_url, e)),
+        }
+    }
+    Ok(report)
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// 等待拉取的订阅源, 按拉取时间先后出队
 #[derive(Default)]
 struct FetchQueue {
-    feeds: HashMap<String, SubscribeFeed>,
+    queued: HashSet<String>,
     notifies: DelayQueue<String>,
     wakeup: Notify,
+    // 正在拉取的订阅源, 拉取结束之前不会再排队
+    fetching: Arc<Mutex<HashSet<String>>>,
 }
 
 impl FetchQueue {
@@ -306,21 +885,33 @@ impl FetchQueue {
         Self::default()
     }
 
-    fn enqueue(&mut self, feed: SubscribeFeed, delay: Duration) -> bool {
-        let exists = self.feeds.contains_key(&feed.subscribe_url);
+    fn enqueue(&mut self, subscribe_url: &str, delay: Duration) -> bool {
+        let exists = self.queued.contains(subscribe_url)
+            || self.fetching.lock().unwrap().contains(subscribe_url);
         if !exists {
-            self.notifies.insert(feed.subscribe_url.clone(), delay);
-            self.feeds.insert(feed.subscribe_url.clone(), feed);
+            self.notifies.insert(subscribe_url.to_owned(), delay);
+            self.queued.insert(subscribe_url.to_owned());
             self.wakeup.notify_waiters();
         }
         !exists
     }
 
-    async fn next(&mut self) -> Result<SubscribeFeed, tokio::time::error::Error> {
+    /// 等到下一个订阅源到点
+    ///
+    /// Returns:
+    ///
+    /// 订阅地址, 以及拉取结束之前要拿着的 [`Fetching`]
+    async fn next(&mut self) -> (String, Fetching) {
         loop {
-            if let Some(feed_id) = self.notifies.next().await {
-                let feed = self.feeds.remove(feed_id.get_ref()).unwrap();
-                break Ok(feed);
+            if let Some(expired) = self.notifies.next().await {
+                let url = expired.into_inner();
+                self.queued.remove(&url);
+                self.fetching.lock().unwrap().insert(url.to_owned());
+                let fetching = Fetching {
+                    url: url.to_owned(),
+                    fetching: self.fetching.clone(),
+                };
+                break (url, fetching);
             } else {
                 self.wakeup.notified().await;
             }
@@ -328,6 +919,18 @@ impl FetchQueue {
     }
 }
 
+/// 订阅源正在拉取, 丢掉之后才能重新排队
+struct Fetching {
+    url: String,
+    fetching: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Drop for Fetching {
+    fn drop(&mut self) {
+        self.fetching.lock().unwrap().remove(&self.url);
+    }
+}
+
 fn is_valid_message(msg: &KookEventMessage) -> bool {
     match msg.msg_timestamp {
         Some(timestamp) => {
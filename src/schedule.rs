@@ -0,0 +1,356 @@
+use chrono::{Datelike, TimeZone, Timelike, Utc, Weekday};
+use tracing::info;
+
+use crate::conf::ScheduleConfig;
+use crate::data::SubscribeFeed;
+use crate::fetch::feed::Feed;
+use crate::utils::ExponentRegress;
+
+// 新样本在发文间隔的指数加权平均里占的比重
+const EWMA_WEIGHT: f64 = 0.3;
+// 不知道发文间隔的时候当成多久发一篇
+const UNKNOWN_POST_INTERVAL: u64 = 60 * 60 * 2;
+// 跳过 skipHours / skipDays 最多往后找多少个小时
+const MAX_SKIP_HOURS: u64 = 24 * 7;
+
+// 失败退避的指数最多到几, 再往后就一直是拉取间隔的上限了
+const MAX_BACKOFF_EXPONENT: u32 = 16;
+
+const HOUR: u64 = 60 * 60;
+
+/// `sy:updatePeriod` 和 `sy:updateFrequency` 算出来的更新间隔, 秒.
+/// 两个都没写就是 None, 只写了一个的话另一个按规范取默认值 (daily, 1).
+pub fn syndication_period(period: Option<&str>, frequency: Option<u32>) -> Option<u64> {
+    if period.is_none() && frequency.is_none() {
+        return None;
+    }
+
+    let period = match period.map(|p| p.trim().to_ascii_lowercase()).as_deref() {
+        Some("hourly") => HOUR,
+        Some("weekly") => HOUR * 24 * 7,
+        Some("monthly") => HOUR * 24 * 30,
+        Some("yearly") => HOUR * 24 * 365,
+        _ => HOUR * 24,
+    };
+    Some(period / frequency.unwrap_or(1).max(1) as u64)
+}
+
+/// 订阅源里的 `<skipHours>`, 不合法的小时会被丢掉
+pub fn skip_hours(feed: &Feed) -> Vec<u32> {
+    feed.skip_hours
+        .iter()
+        .filter_map(|h| h.trim().parse::<u32>().ok())
+        .filter(|h| *h < 24)
+        .collect()
+}
+
+/// 订阅源里的 `<skipDays>`, 0 是星期一
+pub fn skip_days(feed: &Feed) -> Vec<u32> {
+    feed.skip_days
+        .iter()
+        .filter_map(|d| d.trim().parse::<Weekday>().ok())
+        .map(|d| d.num_days_from_monday())
+        .collect()
+}
+
+/// 按订阅源里文章的发布时间估计发文间隔, 刚订阅的时候用
+///
+/// Returns:
+///
+/// 平均发文间隔和最新一篇的发布时间, 带日期的文章少于两篇就估计不了
+pub fn estimate_post_interval(feed: &Feed) -> Option<(u64, u64)> {
+    let mut dates = feed
+        .posts
+        .iter()
+        .filter_map(|p| p.date_time())
+        .map(|d| d.timestamp().max(0) as u64)
+        .collect::<Vec<u64>>();
+    if dates.len() < 2 {
+        return None;
+    }
+
+    dates.sort_unstable();
+    let newest = dates[dates.len() - 1];
+    let interval = (newest - dates[0]) / (dates.len() as u64 - 1);
+    Some((interval, newest))
+}
+
+/// 拉取之后更新发文间隔, 并算出下一次拉取的时间
+///
+/// Arguments:
+///
+/// * `feed`: 数据库里的订阅源
+/// * `new_posts`: 这次拉取发现了几篇新文章
+/// * `conf`: 拉取间隔的上下限
+/// * `now`: 现在的时间戳
+pub fn reschedule(feed: &mut SubscribeFeed, new_posts: usize, conf: &ScheduleConfig, now: u64) {
+    if new_posts > 0 {
+        if let Some(last) = feed.last_post_time {
+            let sample = now.saturating_sub(last) / new_posts as u64;
+            feed.post_interval = Some(match feed.post_interval {
+                Some(old) => {
+                    (old as f64 * (1.0 - EWMA_WEIGHT) + sample as f64 * EWMA_WEIGHT).round() as u64
+                }
+                None => sample,
+            });
+        }
+        feed.last_post_time = Some(now);
+    }
+
+    feed.next_fetch = next_fetch(feed, conf, now);
+    info!(
+        "下次拉取 {}: {} 秒后",
+        feed.subscribe_url,
+        feed.next_fetch.saturating_sub(now)
+    );
+}
+
+/// 拉取失败之后记下原因, 按连续失败的次数指数退避. 太久没有拉取成功的话暂停拉取.
+///
+/// Arguments:
+///
+/// * `feed`: 数据库里的订阅源
+/// * `error`: 失败的原因
+/// * `conf`: 拉取间隔的上下限和暂停的时间
+/// * `now`: 现在的时间戳
+pub fn record_failure(feed: &mut SubscribeFeed, error: String, conf: &ScheduleConfig, now: u64) {
+    feed.failure_count += 1;
+    feed.last_error = Some(error);
+    if let Some(after) = conf.suspend_after {
+        if feed.suspended_at.is_none() && now.saturating_sub(feed.down_time) >= after {
+            feed.suspended_at = Some(now);
+        }
+    }
+
+    // 第一次失败等两倍的最短间隔, 之后每次翻倍
+    let eg = ExponentRegress::from_base(2);
+    eg.forward((feed.failure_count.min(MAX_BACKOFF_EXPONENT) - 1) as usize);
+    let backoff = conf
+        .min_interval
+        .saturating_mul(eg.get() as u64)
+        .max(fetch_interval(feed, conf, now))
+        .min(conf.max_interval);
+    feed.next_fetch = now + backoff;
+    info!(
+        "{} 连续失败 {} 次, {} 秒后重试",
+        feed.subscribe_url, feed.failure_count, backoff
+    );
+}
+
+/// 拉取间隔: 发文间隔的一半, 很久没有新文章的话按距离上一篇的时间慢慢放宽.
+/// 订阅源声明的 `ttl` 和 `sy:updatePeriod` 是下限, 最后再限制在配置的上下限之间.
+fn fetch_interval(feed: &SubscribeFeed, conf: &ScheduleConfig, now: u64) -> u64 {
+    let idle = feed.last_post_time.map(|last| now.saturating_sub(last));
+    let estimate = match (feed.post_interval, idle) {
+        (Some(interval), Some(idle)) => interval.max(idle),
+        (Some(interval), None) => interval,
+        (None, Some(idle)) => idle,
+        (None, None) => UNKNOWN_POST_INTERVAL,
+    };
+
+    let ttl = feed.ttl.map(|ttl| ttl as u64 * 60).unwrap_or_default();
+    let publisher = ttl.max(feed.update_period.unwrap_or_default());
+    (estimate / 2)
+        .max(publisher)
+        .clamp(conf.min_interval, conf.max_interval)
+}
+
+/// 下一次拉取的时间, 落在 `<skipHours>` / `<skipDays>` 里的话顺延到允许的整点
+fn next_fetch(feed: &SubscribeFeed, conf: &ScheduleConfig, now: u64) -> u64 {
+    let next = now + fetch_interval(feed, conf, now);
+    if feed.skip_hours.is_empty() && feed.skip_days.is_empty() {
+        return next;
+    }
+
+    let mut at = next;
+    for _ in 0..MAX_SKIP_HOURS {
+        let time = match Utc.timestamp_opt(at as i64, 0).single() {
+            Some(t) => t,
+            None => return next,
+        };
+        let skipped = feed.skip_hours.contains(&time.hour())
+            || feed
+                .skip_days
+                .contains(&time.weekday().num_days_from_monday());
+        if !skipped {
+            return at;
+        }
+        at = (at / HOUR + 1) * HOUR;
+    }
+    // 每个小时都跳过的话就不管了
+    next
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::fetch::FromXmlWithBufRead;
+
+    // 2023-04-03 00:00:00 UTC, 星期一
+    const MONDAY: u64 = 1680480000;
+
+    fn conf() -> ScheduleConfig {
+        ScheduleConfig {
+            min_interval: 60 * 3,
+            max_interval: HOUR * 6,
+            failure_notify: 5,
+            suspend_after: Some(HOUR * 24 * 7),
+        }
+    }
+
+    #[test]
+    fn test_syndication_period() {
+        assert_eq!(syndication_period(None, None), None);
+        assert_eq!(syndication_period(Some("hourly"), Some(2)), Some(HOUR / 2));
+        assert_eq!(syndication_period(Some("daily"), None), Some(HOUR * 24));
+        assert_eq!(syndication_period(None, Some(4)), Some(HOUR * 6));
+        assert_eq!(
+            syndication_period(Some("weekly"), Some(0)),
+            Some(HOUR * 24 * 7)
+        );
+    }
+
+    #[test]
+    fn test_feed_hints() {
+        let s: &[u8] = include_bytes!("../test/data/rss_2.0.xml");
+        let rss = Feed::from_xml_with_buf(Cursor::new(s)).unwrap();
+        // 只有 22 点可以拉取
+        assert_eq!(skip_hours(&rss), (0..22).chain([23]).collect::<Vec<u32>>());
+
+        let rss = Feed {
+            skip_hours: vec!["1".to_owned(), "24".to_owned(), "x".to_owned()],
+            skip_days: vec!["Saturday".to_owned(), " sunday ".to_owned(), "x".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(skip_hours(&rss), vec![1]);
+        assert_eq!(skip_days(&rss), vec![5, 6]);
+    }
+
+    #[test]
+    fn test_interval() {
+        let conf = conf();
+        let mut feed = SubscribeFeed::default();
+
+        // 什么都不知道
+        assert_eq!(fetch_interval(&feed, &conf, MONDAY), HOUR);
+
+        // 十分钟一篇, 五分钟拉一次
+        feed.post_interval = Some(600);
+        feed.last_post_time = Some(MONDAY);
+        assert_eq!(fetch_interval(&feed, &conf, MONDAY), 300);
+
+        // 一天没有新文章就放宽到半天, 但不超过上限
+        assert_eq!(fetch_interval(&feed, &conf, MONDAY + HOUR * 24), HOUR * 6);
+        assert_eq!(fetch_interval(&feed, &conf, MONDAY + HOUR * 4), HOUR * 2);
+
+        // 订阅源声明的 ttl 是下限
+        feed.ttl = Some(60);
+        assert_eq!(fetch_interval(&feed, &conf, MONDAY), HOUR);
+        feed.ttl = None;
+        feed.update_period = Some(HOUR / 2);
+        assert_eq!(fetch_interval(&feed, &conf, MONDAY), HOUR / 2);
+
+        // 很勤快的订阅源也不低于下限
+        feed.update_period = None;
+        feed.post_interval = Some(10);
+        feed.last_post_time = Some(MONDAY);
+        assert_eq!(fetch_interval(&feed, &conf, MONDAY), 60 * 3);
+    }
+
+    #[test]
+    fn test_reschedule() {
+        let conf = conf();
+        let mut feed = SubscribeFeed {
+            post_interval: Some(HOUR),
+            last_post_time: Some(MONDAY - HOUR),
+            ..Default::default()
+        };
+
+        // 一个小时里出了四篇
+        reschedule(&mut feed, 4, &conf, MONDAY);
+        assert_eq!(feed.last_post_time, Some(MONDAY));
+        assert_eq!(feed.post_interval, Some(2790));
+        assert_eq!(feed.next_fetch, MONDAY + 1395);
+
+        // 没有新文章不改发文间隔
+        reschedule(&mut feed, 0, &conf, MONDAY + 600);
+        assert_eq!(feed.post_interval, Some(2790));
+        assert_eq!(feed.last_post_time, Some(MONDAY));
+    }
+
+    #[test]
+    fn test_record_failure() {
+        let conf = conf();
+        let mut feed = SubscribeFeed {
+            post_interval: Some(600),
+            last_post_time: Some(MONDAY),
+            down_time: MONDAY,
+            ..Default::default()
+        };
+
+        record_failure(&mut feed, "timeout".to_owned(), &conf, MONDAY);
+        assert_eq!(feed.failure_count, 1);
+        assert_eq!(feed.last_error.as_deref(), Some("timeout"));
+        assert_eq!(feed.next_fetch, MONDAY + 60 * 6);
+
+        record_failure(&mut feed, "timeout".to_owned(), &conf, MONDAY);
+        assert_eq!(feed.next_fetch, MONDAY + 60 * 12);
+
+        // 退避不超过上限
+        for _ in 0..40 {
+            record_failure(&mut feed, "timeout".to_owned(), &conf, MONDAY);
+        }
+        assert_eq!(feed.next_fetch, MONDAY + HOUR * 6);
+        assert_eq!(feed.suspended_at, None);
+
+        // 一周没有成功就暂停
+        let later = MONDAY + HOUR * 24 * 7;
+        record_failure(&mut feed, "timeout".to_owned(), &conf, later);
+        assert_eq!(feed.suspended_at, Some(later));
+        record_failure(&mut feed, "timeout".to_owned(), &conf, later + 1);
+        assert_eq!(feed.suspended_at, Some(later));
+    }
+
+    #[test]
+    fn test_skip() {
+        let conf = conf();
+        let mut feed = SubscribeFeed {
+            post_interval: Some(600),
+            last_post_time: Some(MONDAY),
+            skip_hours: vec![0, 1],
+            ..Default::default()
+        };
+        assert_eq!(next_fetch(&feed, &conf, MONDAY), MONDAY + HOUR * 2);
+        assert_eq!(
+            next_fetch(&feed, &conf, MONDAY + HOUR * 3),
+            MONDAY + HOUR * 4 + 1800
+        );
+
+        // 星期六和星期天都不拉, 星期五晚上之后就等到星期一
+        feed.skip_hours = vec![];
+        feed.skip_days = vec![5, 6];
+        let friday_night = MONDAY + HOUR * (24 * 4 + 23) + 3500;
+        feed.last_post_time = Some(friday_night);
+        assert_eq!(
+            next_fetch(&feed, &conf, friday_night),
+            MONDAY + HOUR * 24 * 7
+        );
+
+        // 全都跳过就不管了
+        feed.skip_days = (0..7).collect();
+        assert_eq!(next_fetch(&feed, &conf, MONDAY), MONDAY + 300);
+    }
+
+    #[test]
+    fn test_estimate_post_interval() {
+        let s: &[u8] = include_bytes!("../test/data/rss_1.0.xml");
+        let rss = Feed::from_xml_with_buf(Cursor::new(s)).unwrap();
+        assert!(estimate_post_interval(&rss).is_some());
+
+        let s: &[u8] = include_bytes!("../test/data/no_link.xml");
+        let rss = Feed::from_xml_with_buf(Cursor::new(s)).unwrap();
+        assert_eq!(estimate_post_interval(&rss), None);
+    }
+}
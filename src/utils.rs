@@ -3,12 +3,15 @@ use regex::Regex;
 use sled::IVec;
 use std::cell::Cell;
 use std::hash::{Hash, Hasher};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
-use std::time::Duration;
 
-static REGEX_HTTP_URL: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r"http(s?)://[\w\./:\-$&#]*").unwrap());
+// KMarkdown 里的链接: [文字](链接)
+static REGEX_KMARKDOWN_LINK: Lazy<Regex> = Lazy::new(|| Regex::new(r"^\[(.*)\]\((.*)\)$").unwrap());
+
+// KMarkdown 转义过的字符
+static REGEX_KMARKDOWN_ESCAPE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\\([\\*_~`>\-\[\]()|])").unwrap());
+
+static REGEX_HTML_TAG: Lazy<Regex> = Lazy::new(|| Regex::new(r"<[^>]*>").unwrap());
 
 #[inline]
 pub fn ivec_to_str(vec: IVec) -> String {
@@ -36,62 +39,110 @@ pub fn ivec_to_str(vec: IVec) -> String {
 //    vec.join(&*pat.to_string())
 //}
 
-#[inline]
-pub fn find_http_url(url: &str) -> Option<&str> {
-    let m = REGEX_HTTP_URL.find(url)?;
-    Some(m.as_str())
+/// 解析用户发来的订阅地址, 只接受 http 和 https.
+///
+/// KOOK 会把消息里的链接转成 `[url](url)`, 还会转义一些字符, 这里都会先去掉.
+///
+/// Returns:
+///
+/// 规范化之后的 URL, 不带 `#` 后面的部分
+pub fn parse_http_url(s: &str) -> Option<String> {
+    let s = strip_kmarkdown(s);
+
+    let mut url = url::Url::parse(&s).ok()?;
+    if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+        return None;
+    }
+    url.set_fragment(None);
+    Some(url.to_string())
 }
 
-#[inline]
-pub fn hash(k: impl Hash) -> String {
-    let mut buffer = itoa::Buffer::new();
-    let mut hasher = std::collections::hash_map::DefaultHasher::default();
-    k.hash(&mut hasher);
-    buffer.format(hasher.finish()).to_owned()
+/// 解析用户发来的订阅地址, 除了 http 和 https 还接受 RSSHub 路由: `rsshub:/3dm/news`,
+/// 本地订阅源: `file:///srv/feeds/build.xml`, `exec:build-status`,
+/// 以及被监控的网页: `watch:https://example.com/news#css=ul.news+li`
+pub fn parse_feed_url(s: &str) -> Option<String> {
+    let stripped = strip_kmarkdown(s);
+    crate::fetch::parse_route(&stripped)
+        .or_else(|| crate::fetch::parse_local_source(&stripped))
+        .or_else(|| crate::fetch::PageWatch::from_url(&stripped).map(|w| w.to_url()))
+        .or_else(|| parse_http_url(s))
 }
 
-/** 节流器 */
-pub struct Throttle {
-    pieces: usize,
-    counter: Arc<AtomicUsize>,
-    unit: Duration,
+/// 去掉 KMarkdown 的链接格式和转义
+pub fn strip_kmarkdown(s: &str) -> String {
+    let s = s.trim();
+    let s = match REGEX_KMARKDOWN_LINK.captures(s) {
+        Some(c) => c.get(2).unwrap().as_str(),
+        None => s,
+    };
+    REGEX_KMARKDOWN_ESCAPE.replace_all(s, "$1").into_owned()
 }
 
-impl Throttle {
-    pub fn new(pieces: usize, unit: Option<Duration>) -> Self {
-        Throttle {
-            pieces,
-            unit: unit.unwrap_or_else(|| Duration::from_secs(1)),
-            counter: Arc::new(AtomicUsize::new(0)),
-        }
-    }
-
-    pub fn acquire(&self) -> Opportunity {
-        Opportunity {
-            n: self.counter.fetch_add(1, Ordering::AcqRel) % self.pieces,
-            u: self.unit,
-            counter: self.counter.clone(),
-        }
+/// 去掉 HTML 标签, 合并空白, 最多保留 `max_chars` 个字符
+pub fn html_to_plain_text(html: &str, max_chars: usize) -> String {
+    let text = REGEX_HTML_TAG.replace_all(html, " ");
+    let text = text
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+    let text = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+
+    match text.char_indices().nth(max_chars) {
+        Some((idx, _)) => format!("{}…", &text[..idx]),
+        None => text,
     }
 }
 
-#[must_use = "Don't lose your opportunity"]
-pub struct Opportunity {
-    n: usize,
-    u: Duration,
-    counter: Arc<AtomicUsize>,
+#[inline]
+pub fn hash(k: impl Hash) -> String {
+    let mut buffer = itoa::Buffer::new();
+    let mut hasher = std::collections::hash_map::DefaultHasher::default();
+    k.hash(&mut hasher);
+    buffer.format(hasher.finish()).to_owned()
 }
 
-impl Opportunity {
-    pub async fn wait(&self) {
-        tokio::time::sleep(self.u * self.n as u32).await
+/// `to` 是不是 `from` 的另一种写法: 只允许 http 升级到 https, 去掉 `www.`, 默认端口和末尾的 `/`.
+/// 订阅源自己声明的 `self` 地址只有在这种情况下才会被当成订阅源的规范地址.
+pub fn is_url_variant(from: &str, to: &str) -> bool {
+    let (from, to) = match (url::Url::parse(from), url::Url::parse(to)) {
+        (Ok(f), Ok(t)) => (f, t),
+        _ => return false,
+    };
+    if from == to {
+        return false;
     }
+
+    let scheme_ok =
+        from.scheme() == to.scheme() || (from.scheme(), to.scheme()) == ("http", "https");
+    let host = |u: &url::Url| {
+        u.host_str()
+            .map(|h| h.trim_start_matches("www.").to_owned())
+    };
+    // 端口不是默认的就要一样
+    let port_ok = from.port() == to.port();
+    let path = |u: &url::Url| u.path().trim_end_matches('/').to_owned();
+
+    scheme_ok
+        && port_ok
+        && host(&from).is_some()
+        && host(&from) == host(&to)
+        && path(&from) == path(&to)
+        && from.query() == to.query()
 }
 
-impl Drop for Opportunity {
-    fn drop(&mut self) {
-        self.counter.fetch_sub(1, Ordering::SeqCst);
-    }
+/// 两个订阅地址是不是同一个站点: 协议, 主机和端口都一样. 被监控的网页比较的是网页的地址.
+pub fn is_same_origin(a: &str, b: &str) -> bool {
+    let origin = |s: &str| {
+        let page = crate::fetch::PageWatch::from_url(s).map(|w| w.url);
+        url::Url::parse(page.as_deref().unwrap_or(s))
+            .ok()
+            .map(|u| u.origin())
+            .filter(|o| o.is_tuple())
+    };
+    matches!((origin(a), origin(b)), (Some(a), Some(b)) if a == b)
 }
 
 // 0 = Bottom, ,1 = Exponential
@@ -163,27 +214,118 @@ mod test {
     }
 
     #[test]
-    fn test_find_url() {
-        let r = REGEX_HTTP_URL
-            .find("[http://175.24.205.140:12000/3dm/news](http://175.24.205.140:12000/3dm/news)")
-            .unwrap()
-            .as_str();
-
-        assert_eq!("http://175.24.205.140:12000/3dm/news", r);
+    fn test_parse_http_url() {
+        let r = parse_http_url(
+            "[http://175.24.205.140:12000/3dm/news](http://175.24.205.140:12000/3dm/news)",
+        );
+        assert_eq!(Some("http://175.24.205.140:12000/3dm/news"), r.as_deref());
+
+        let r1 = parse_http_url("http://175.24.205.140:12000/3dm/news");
+        assert_eq!(Some("http://175.24.205.140:12000/3dm/news"), r1.as_deref());
+
+        let r1 = parse_http_url("http://175.24.205.140:12000/nga/forum/-61285727");
+        assert_eq!(
+            Some("http://175.24.205.140:12000/nga/forum/-61285727"),
+            r1.as_deref()
+        );
+
+        // 带查询参数和百分号编码
+        let r2 = parse_http_url(
+            "[https://example.com/feed?tag=a%20b&page=1](https://example.com/feed?tag=a%20b&page=1)",
+        );
+        assert_eq!(
+            Some("https://example.com/feed?tag=a%20b&page=1"),
+            r2.as_deref()
+        );
+
+        // KMarkdown 转义, 大写的域名, 默认端口, 锚点
+        let r3 = parse_http_url("HTTPS://Example.COM:443/a\\_b/feed.xml#top");
+        assert_eq!(Some("https://example.com/a_b/feed.xml"), r3.as_deref());
+
+        assert_eq!(None, parse_http_url("ftp://example.com/feed.xml"));
+        assert_eq!(None, parse_http_url("file:///etc/passwd"));
+        assert_eq!(None, parse_http_url("example.com/feed.xml"));
+        assert_eq!(None, parse_http_url("http://"));
+        assert_eq!(None, parse_http_url("rsshub:/3dm/news"));
+    }
 
-        let r1 = REGEX_HTTP_URL
-            .find("http://175.24.205.140:12000/3dm/news")
-            .unwrap()
-            .as_str();
+    #[test]
+    fn test_parse_feed_url() {
+        assert_eq!(
+            parse_feed_url("rsshub:/3dm/news").as_deref(),
+            Some("rsshub:/3dm/news")
+        );
+        assert_eq!(
+            parse_feed_url("rsshub:/bilibili/user/video\\_list/2267573").as_deref(),
+            Some("rsshub:/bilibili/user/video_list/2267573")
+        );
+        assert_eq!(
+            parse_feed_url("http://example.com/feed.xml").as_deref(),
+            Some("http://example.com/feed.xml")
+        );
+        assert_eq!(
+            parse_feed_url("exec:build\\_status").as_deref(),
+            Some("exec:build_status")
+        );
+        assert_eq!(
+            parse_feed_url("file:///srv/feeds/build.xml").as_deref(),
+            Some("file:///srv/feeds/build.xml")
+        );
+        assert_eq!(None, parse_feed_url("rsshub:"));
+        assert_eq!(None, parse_feed_url("exec:ls -la"));
+        assert_eq!(
+            parse_feed_url("watch:https://example.com/news#css=ul.news+li").as_deref(),
+            Some("watch:https://example.com/news#css=ul.news+li")
+        );
+        assert_eq!(None, parse_feed_url("watch:https://example.com/news"));
+    }
 
-        assert_eq!("http://175.24.205.140:12000/3dm/news", r1);
+    #[test]
+    fn test_is_url_variant() {
+        let from = "http://example.com/feed/";
+        assert!(is_url_variant(from, "https://example.com/feed"));
+        assert!(is_url_variant(from, "http://www.example.com/feed"));
+        assert!(is_url_variant(from, "https://example.com:443/feed/"));
+        assert!(is_url_variant(
+            "https://www.example.com/feed",
+            "https://example.com/feed/"
+        ));
+
+        assert!(!is_url_variant(from, from));
+        assert!(!is_url_variant(
+            "https://example.com/feed",
+            "http://example.com/feed"
+        ));
+        assert!(!is_url_variant(from, "https://example.org/feed"));
+        assert!(!is_url_variant(from, "https://example.com/atom.xml"));
+        assert!(!is_url_variant(from, "https://example.com:8443/feed"));
+        assert!(!is_url_variant(from, "https://example.com/feed?page=2"));
+        assert!(!is_url_variant(from, "/feed"));
+    }
 
-        let r1 = REGEX_HTTP_URL
-            .find("http://175.24.205.140:12000/nga/forum/-61285727")
-            .unwrap()
-            .as_str();
+    #[test]
+    fn test_is_same_origin() {
+        let from = "https://example.com/feed";
+        assert!(is_same_origin(from, "https://example.com:443/atom.xml"));
+        assert!(is_same_origin(
+            "watch:https://example.com/news#css=li",
+            "watch:https://example.com/blog#css=li"
+        ));
+
+        assert!(!is_same_origin(from, "http://example.com/feed"));
+        assert!(!is_same_origin(from, "https://www.example.com/feed"));
+        assert!(!is_same_origin(from, "https://example.com:8443/feed"));
+        assert!(!is_same_origin("exec:build", "exec:build"));
+    }
 
-        assert_eq!("http://175.24.205.140:12000/nga/forum/-61285727", r1);
+    #[test]
+    fn test_html_to_plain_text() {
+        assert_eq!(
+            "a & b c",
+            html_to_plain_text("<p>a &amp; b</p>\n<p>c</p>", 100)
+        );
+        assert_eq!("你好…", html_to_plain_text("<b>你好</b>世界", 2));
+        assert_eq!("", html_to_plain_text("<img src=\"x\"/>", 2));
     }
 
     #[test]
@@ -0,0 +1,794 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use sha1::Sha1;
+use sha2::Sha256;
+use thiserror::Error;
+use tracing::*;
+
+use crate::{
+    conf::{RuntimeConfig, WebSubConfig},
+    data::{SubscribeFeed, WebSubLease},
+    db::{Database, StoreError},
+    fetch, push, utils,
+};
+
+// 回调地址: {callback}/websub/{feed_hash}/{token}
+const CALLBACK_PATH_PREFIX: &str = "/websub/";
+
+// 向 hub 申请的订阅时长, 10 天
+const LEASE_SECONDS: u64 = 60 * 60 * 24 * 10;
+
+// 到期前一天续订
+const RENEW_BEFORE_SECS: u64 = 60 * 60 * 24;
+
+// hub 给的订阅时长最多按这么长算, 免得订阅源太久不轮询, 30 天
+const MAX_LEASE_SECONDS: u64 = 60 * 60 * 24 * 30;
+
+// 发起订阅之后 hub 一小时都没来验证就重新订阅, 超过这个时间的验证请求也不再接受
+const PENDING_TIMEOUT_SECS: u64 = 60 * 60;
+
+// hub 推送内容的大小限制
+const MAX_CONTENT_SIZE: u64 = 1024 * 1024 * 4; // 4MB
+
+const SECRET_LEN: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum WebSubError {
+    #[error("hub 请求失败: {0}")]
+    Hub(#[from] fetch::FeedError),
+    #[error("数据库错误: {0}")]
+    Database(#[from] StoreError),
+}
+
+#[inline]
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// 订阅源的回调地址, 带上订阅的随机令牌
+pub fn callback_url(conf: &WebSubConfig, subscribe_url: &str, lease: &WebSubLease) -> String {
+    format!(
+        "{}{}{}/{}",
+        conf.callback,
+        CALLBACK_PATH_PREFIX,
+        utils::hash(subscribe_url),
+        lease.token
+    )
+}
+
+/// 订阅源在 hub 那边的 topic, 优先用订阅源声明的自身地址
+fn topic(feed: &SubscribeFeed) -> String {
+    feed.self_link
+        .to_owned()
+        .unwrap_or_else(|| feed.subscribe_url.to_owned())
+}
+
+/// 是否需要向 hub 发起 (或者重新发起) 订阅
+pub fn needs_subscribe(feed: &SubscribeFeed, now: u64) -> bool {
+    let hub = match feed.hub {
+        Some(ref hub) => hub,
+        None => return false,
+    };
+
+    match feed.websub {
+        None => true,
+        // 订阅源换了 hub, 或者是回调地址里还没有令牌的旧订阅
+        Some(ref lease) if &lease.hub != hub || lease.token.is_empty() => true,
+        // hub 还没来验证
+        Some(ref lease) if lease.expires_at == 0 => lease.requested_at + PENDING_TIMEOUT_SECS < now,
+        Some(ref lease) => lease.expires_at < now + RENEW_BEFORE_SECS,
+    }
+}
+
+/// 订阅有效期间 hub 会推送更新, 不需要轮询
+pub fn is_pushed_by_hub(feed: &SubscribeFeed, now: u64) -> bool {
+    matches!(feed.websub, Some(ref lease) if lease.is_active(now))
+}
+
+/// 向订阅源声明的 hub 发起订阅, 续订的时候沿用原来的密钥, 令牌和到期时间.
+/// 订阅要等 hub 回调验证之后才生效.
+///
+/// Arguments:
+///
+/// * `db`: 数据库
+/// * `conf`: WebSub 配置
+/// * `feed`: 声明了 hub 的订阅源
+pub async fn subscribe(
+    db: &Database,
+    conf: &WebSubConfig,
+    feed: &SubscribeFeed,
+) -> Result<(), WebSubError> {
+    let hub = match feed.hub {
+        Some(ref hub) => hub.to_owned(),
+        None => return Ok(()),
+    };
+
+    let lease = match feed.websub {
+        Some(ref old) if old.hub == hub && !old.token.is_empty() => WebSubLease {
+            topic: topic(feed),
+            requested_at: now_secs(),
+            ..old.to_owned()
+        },
+        _ => WebSubLease {
+            hub,
+            topic: topic(feed),
+            secret: random_secret(),
+            token: random_secret(),
+            requested_at: now_secs(),
+            expires_at: 0,
+        },
+    };
+
+    // 先保存, hub 有可能在请求返回之前就来验证了
+    db.update_feed_websub(&feed.subscribe_url, Some(lease.to_owned()))?;
+
+    info!("WebSub 订阅: {} => {}", lease.topic, lease.hub);
+    let callback = callback_url(conf, &feed.subscribe_url, &lease);
+    let lease_seconds = LEASE_SECONDS.to_string();
+    // hub 地址是订阅源自己声明的, 和拉取订阅源一样不能指向内网
+    fetch::post_form(
+        &lease.hub,
+        &[
+            ("hub.mode", "subscribe"),
+            ("hub.topic", &lease.topic),
+            ("hub.callback", &callback),
+            ("hub.lease_seconds", &lease_seconds),
+            ("hub.secret", &lease.secret),
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// 退订, 订阅源被删除之后调用
+pub async fn unsubscribe(
+    conf: &WebSubConfig,
+    subscribe_url: &str,
+    lease: &WebSubLease,
+) -> Result<(), WebSubError> {
+    info!("WebSub 退订: {} => {}", lease.topic, lease.hub);
+    let callback = callback_url(conf, subscribe_url, lease);
+    fetch::post_form(
+        &lease.hub,
+        &[
+            ("hub.mode", "unsubscribe"),
+            ("hub.topic", &lease.topic),
+            ("hub.callback", &callback),
+        ],
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// 启动 WebSub 回调服务, 处理 hub 的订阅验证和内容推送
+pub async fn serve(db: Arc<Database>, conf: Arc<RuntimeConfig>) -> Result<(), hyper::Error> {
+    let addr = match conf.websub {
+        Some(ref websub) => websub.listen,
+        None => return Ok(()),
+    };
+
+    let make_svc = make_service_fn(move |_| {
+        let db = db.clone();
+        let conf = conf.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(req, db.clone(), conf.clone()))) }
+    });
+
+    info!("WebSub 回调服务启动: {}", addr);
+    Server::bind(&addr).serve(make_svc).await
+}
+
+async fn handle(
+    req: Request<Body>,
+    db: Arc<Database>,
+    conf: Arc<RuntimeConfig>,
+) -> Result<Response<Body>, Infallible> {
+    let (feed_hash, token) = match req
+        .uri()
+        .path()
+        .strip_prefix(CALLBACK_PATH_PREFIX)
+        .and_then(|p| p.split_once('/'))
+    {
+        Some((hash, token)) if !hash.is_empty() && !token.is_empty() => {
+            (hash.to_owned(), token.to_owned())
+        }
+        _ => return Ok(status(StatusCode::NOT_FOUND)),
+    };
+
+    let feed = match db.feed_by_hash(&feed_hash) {
+        Ok(feed) => feed,
+        Err(e) => {
+            error!("WebSub 回调查询订阅源失败: {}", e);
+            return Ok(status(StatusCode::INTERNAL_SERVER_ERROR));
+        }
+    };
+
+    // 令牌对不上的请求不是冲着现在这个订阅来的, 当成没有订阅处理
+    let feed = feed.filter(|f| matches!(f.websub, Some(ref lease) if lease.token == token));
+
+    let resp = match *req.method() {
+        Method::GET => verify_intent(&db, feed, req.uri().query().unwrap_or_default()),
+        Method::POST => receive_content(db, conf, feed, req).await,
+        _ => status(StatusCode::METHOD_NOT_ALLOWED),
+    };
+
+    Ok(resp)
+}
+
+/// hub 的意图验证: 确认订阅 (或者退订) 确实是我们发起的, 然后原样返回 `hub.challenge`.
+/// 订阅的验证只在发起订阅之后的 `PENDING_TIMEOUT_SECS` 之内接受.
+fn verify_intent(db: &Database, feed: Option<SubscribeFeed>, query: &str) -> Response<Body> {
+    let params = url::form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect::<HashMap<String, String>>();
+    let param = |name: &str| params.get(name).map(|s| s.as_str()).unwrap_or_default();
+
+    let (mode, topic, challenge) = (
+        param("hub.mode"),
+        param("hub.topic"),
+        param("hub.challenge"),
+    );
+
+    let lease = feed
+        .as_ref()
+        .and_then(|f| f.websub.as_ref())
+        .filter(|lease| lease.topic == topic);
+
+    let now = now_secs();
+    let pending = lease.is_some_and(|lease| {
+        lease.requested_at <= now && now <= lease.requested_at + PENDING_TIMEOUT_SECS
+    });
+
+    match (mode, lease) {
+        ("subscribe", Some(lease)) if pending && !challenge.is_empty() => {
+            let feed = feed.as_ref().unwrap();
+            let lease_seconds = param("hub.lease_seconds")
+                .parse::<u64>()
+                .unwrap_or(LEASE_SECONDS)
+                .min(MAX_LEASE_SECONDS);
+            let lease = WebSubLease {
+                expires_at: now.saturating_add(lease_seconds),
+                ..lease.to_owned()
+            };
+            if let Err(e) = db.update_feed_websub(&feed.subscribe_url, Some(lease)) {
+                error!("WebSub 订阅状态保存失败: {}", e);
+                return status(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+            info!("WebSub 订阅生效: {} ({}s)", topic, lease_seconds);
+            Response::new(Body::from(challenge.to_owned()))
+        }
+
+        // 订阅源已经删掉了, 才是我们发起的退订
+        ("unsubscribe", None) if !challenge.is_empty() => {
+            info!("WebSub 退订生效: {}", topic);
+            Response::new(Body::from(challenge.to_owned()))
+        }
+
+        ("denied", Some(_)) => {
+            let feed = feed.as_ref().unwrap();
+            warn!("WebSub 订阅被 hub 拒绝: {} {}", topic, param("hub.reason"));
+            if let Err(e) = db.update_feed_websub(&feed.subscribe_url, None) {
+                error!("WebSub 订阅状态保存失败: {}", e);
+            }
+            status(StatusCode::OK)
+        }
+
+        _ => {
+            warn!("未知的 WebSub 验证请求: {}", query);
+            status(StatusCode::NOT_FOUND)
+        }
+    }
+}
+
+/// hub 推送的新内容. 签名不对的内容按规范也要返回 2xx, 只是不处理
+async fn receive_content(
+    db: Arc<Database>,
+    conf: Arc<RuntimeConfig>,
+    feed: Option<SubscribeFeed>,
+    req: Request<Body>,
+) -> Response<Body> {
+    // 410 告诉 hub 不要再推送了
+    let (feed, lease) = match feed {
+        Some(SubscribeFeed {
+            websub: Some(ref lease),
+            ..
+        }) => (feed.to_owned().unwrap(), lease.to_owned()),
+        _ => return status(StatusCode::GONE),
+    };
+
+    let content_length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or_default();
+    if content_length > MAX_CONTENT_SIZE {
+        return status(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_owned())
+    };
+    let signature = header("X-Hub-Signature");
+    let content_type = header(header::CONTENT_TYPE.as_str());
+
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) if body.len() as u64 <= MAX_CONTENT_SIZE => body,
+        Ok(_) => return status(StatusCode::PAYLOAD_TOO_LARGE),
+        Err(e) => {
+            warn!("WebSub 内容读取失败: {}", e);
+            return status(StatusCode::BAD_REQUEST);
+        }
+    };
+
+    if !verify_signature(&lease.secret, signature.as_deref(), &body) {
+        warn!("WebSub 内容签名不正确, 忽略: {}", feed.subscribe_url);
+        return status(StatusCode::ACCEPTED);
+    }
+
+    let rss = match fetch::parse_feed(content_type.as_deref(), body.to_vec()) {
+        Ok(rss) => rss,
+        Err(e) => {
+            warn!("WebSub 内容解析失败: {} {}", feed.subscribe_url, e);
+            return status(StatusCode::ACCEPTED);
+        }
+    };
+
+    info!("WebSub 收到推送: {}", feed.subscribe_url);
+    tokio::spawn(async move {
+        let validator = feed.cache_validator();
+        if let Err(e) = push::push_new_posts(db, conf, &feed, &rss, validator).await {
+            error!("{}", e);
+        }
+    });
+
+    status(StatusCode::ACCEPTED)
+}
+
+/// 校验 `X-Hub-Signature: sha1=...` / `sha256=...`
+fn verify_signature(secret: &str, signature: Option<&str>, body: &[u8]) -> bool {
+    let (method, hex) = match signature.and_then(|s| s.trim().split_once('=')) {
+        Some(sig) => sig,
+        None => return false,
+    };
+    let expected = match decode_hex(hex) {
+        Some(bytes) => bytes,
+        None => return false,
+    };
+
+    match method {
+        "sha1" => verify_hmac::<Hmac<Sha1>>(secret, body, &expected),
+        "sha256" => verify_hmac::<Hmac<Sha256>>(secret, body, &expected),
+        _ => false,
+    }
+}
+
+fn verify_hmac<M: Mac + hmac::digest::KeyInit>(secret: &str, body: &[u8], expected: &[u8]) -> bool {
+    let mut mac = match <M as Mac>::new_from_slice(secret.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.update(body);
+    // 常量时间比较
+    mac.verify_slice(expected).is_ok()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() & 1 == 1 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn random_secret() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SECRET_LEN)
+        .map(char::from)
+        .collect()
+}
+
+#[inline]
+fn status(code: StatusCode) -> Response<Body> {
+    let mut resp = Response::new(Body::empty());
+    *resp.status_mut() = code;
+    resp
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn sign<M: Mac + hmac::digest::KeyInit>(secret: &str, body: &[u8]) -> String {
+        let mut mac = <M as Mac>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let body = b"<feed></feed>";
+        let sha1 = format!("sha1={}", sign::<Hmac<Sha1>>("secret", body));
+        let sha256 = format!("sha256={}", sign::<Hmac<Sha256>>("secret", body));
+
+        assert!(verify_signature("secret", Some(&sha1), body));
+        assert!(verify_signature("secret", Some(&sha256), body));
+        assert!(!verify_signature("other", Some(&sha1), body));
+        assert!(!verify_signature("secret", Some(&sha256), b"<feed/>"));
+        assert!(!verify_signature("secret", None, body));
+        assert!(!verify_signature("secret", Some("sha1=zz"), body));
+        assert!(!verify_signature("secret", Some("md5=00"), body));
+    }
+
+    #[test]
+    fn test_needs_subscribe() {
+        let mut feed = SubscribeFeed {
+            subscribe_url: "http://example.com/feed.xml".to_owned(),
+            ..Default::default()
+        };
+        assert!(!needs_subscribe(&feed, 1000));
+
+        feed.hub = Some("http://hub.example.com/".to_owned());
+        assert!(needs_subscribe(&feed, 1000));
+
+        // 等 hub 验证
+        let lease = WebSubLease {
+            hub: "http://hub.example.com/".to_owned(),
+            token: "token".to_owned(),
+            requested_at: 1000,
+            ..Default::default()
+        };
+        feed.websub = Some(lease.to_owned());
+        assert!(!needs_subscribe(&feed, 1000 + 60));
+        assert!(!is_pushed_by_hub(&feed, 1000 + 60));
+        assert!(needs_subscribe(&feed, 1000 + PENDING_TIMEOUT_SECS + 1));
+
+        // 订阅有效, 快到期了续订
+        let expires_at = 1000 + LEASE_SECONDS;
+        feed.websub = Some(WebSubLease {
+            expires_at,
+            ..lease.to_owned()
+        });
+        assert!(!needs_subscribe(&feed, 2000));
+        assert!(is_pushed_by_hub(&feed, 2000));
+        assert!(needs_subscribe(&feed, expires_at - 60));
+        assert!(!is_pushed_by_hub(&feed, expires_at + 1));
+
+        // 回调地址里没有令牌的旧订阅要重新订阅
+        feed.websub = Some(WebSubLease {
+            token: "".to_owned(),
+            ..feed.websub.unwrap()
+        });
+        assert!(needs_subscribe(&feed, 2000));
+
+        // 换了 hub
+        feed.hub = Some("http://hub2.example.com/".to_owned());
+        assert!(needs_subscribe(&feed, 2000));
+    }
+
+    // 一个很简陋的 hub, 收到一次订阅请求就把请求体发回来
+    async fn stand_in_hub() -> (String, tokio::sync::oneshot::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut req = vec![0; 4096];
+            let mut n = 0;
+            // 读到请求体为止
+            while !String::from_utf8_lossy(&req[..n]).contains("hub.secret") {
+                n += socket.read(&mut req[n..]).await.unwrap();
+            }
+            socket
+                .write_all(b"HTTP/1.1 202 Accepted\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            let req = String::from_utf8_lossy(&req[..n]).into_owned();
+            let body = req.split("\r\n\r\n").nth(1).unwrap_or_default();
+            tx.send(body.to_owned()).unwrap();
+        });
+        (format!("http://{}/hub", addr), rx)
+    }
+
+    fn runtime_conf() -> Arc<RuntimeConfig> {
+        Arc::new(RuntimeConfig {
+            websub: Some(WebSubConfig {
+                callback: "https://bot.example.com".to_owned(),
+                listen: "127.0.0.1:0".parse::<SocketAddr>().unwrap(),
+            }),
+            ..Default::default()
+        })
+    }
+
+    // 等后台的推送任务把消息放进推送队列
+    async fn wait_outbox(db: &Database, len: usize) -> usize {
+        for _ in 0..100 {
+            if db.outbox_list().unwrap().len() >= len {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        db.outbox_list().unwrap().len()
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_blocked_hub() {
+        fetch::init_test_client();
+        let db = Database::temporary();
+        let conf = runtime_conf();
+        let feed = SubscribeFeed {
+            subscribe_url: "http://example.com/feed.xml".to_owned(),
+            hub: Some("http://169.254.169.254/hub".to_owned()),
+            ..Default::default()
+        };
+        db.update_or_create_feed(&feed).unwrap();
+
+        // 订阅源声明的 hub 在内网, 不能向它发请求
+        let r = subscribe(&db, conf.websub.as_ref().unwrap(), &feed).await;
+        assert!(matches!(
+            r,
+            Err(WebSubError::Hub(fetch::FeedError::Blocked(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_flow() {
+        fetch::init_test_client();
+        let db = Arc::new(Database::temporary());
+        let conf = runtime_conf();
+        let (hub, hub_rx) = stand_in_hub().await;
+
+        let subscribe_url = "http://example.com/feed.xml";
+        let feed = SubscribeFeed {
+            subscribe_url: subscribe_url.to_owned(),
+            title: "feed".to_owned(),
+            self_link: Some("http://example.com/self.xml".to_owned()),
+            hub: Some(hub.to_owned()),
+            ..Default::default()
+        };
+        db.channel_subscribed("chan", feed.to_owned()).unwrap();
+        // 订阅的时候已经拉取过一次了, 不然推送来的文章都会当成旧文章
+        db.mark_posts_seen(subscribe_url, [utils::hash("old")].iter(), 0)
+            .unwrap();
+
+        // 向 hub 发起订阅
+        subscribe(&db, conf.websub.as_ref().unwrap(), &feed)
+            .await
+            .unwrap();
+        let form = hub_rx.await.unwrap();
+        let params = url::form_urlencoded::parse(form.as_bytes())
+            .into_owned()
+            .collect::<HashMap<String, String>>();
+        assert_eq!(params["hub.mode"], "subscribe");
+        assert_eq!(params["hub.topic"], "http://example.com/self.xml");
+
+        let feed = db
+            .feed_by_hash(&utils::hash(subscribe_url))
+            .unwrap()
+            .unwrap();
+        let lease = feed.websub.to_owned().unwrap();
+        assert_eq!(lease.secret, params["hub.secret"]);
+        assert_eq!(lease.expires_at, 0);
+        assert_eq!(lease.token.len(), SECRET_LEN);
+        assert_eq!(
+            params["hub.callback"],
+            format!(
+                "https://bot.example.com/websub/{}/{}",
+                utils::hash(subscribe_url),
+                lease.token
+            )
+        );
+
+        let callback = format!("/websub/{}/{}", utils::hash(subscribe_url), lease.token);
+        let call = |req: Request<Body>| handle(req, db.clone(), conf.clone());
+
+        // 别人伪造的验证请求
+        let req = Request::get(format!(
+            "{}?hub.mode=subscribe&hub.topic=http%3A%2F%2Fevil&hub.challenge=abc",
+            callback
+        ))
+        .body(Body::empty())
+        .unwrap();
+        assert_eq!(call(req).await.unwrap().status(), StatusCode::NOT_FOUND);
+
+        // 猜不到令牌的话, 订阅源的 topic 对了也没用
+        let forged = "?hub.mode=subscribe&hub.topic=http%3A%2F%2Fexample.com%2Fself.xml&hub.challenge=abc&hub.lease_seconds=99999999999999999999";
+        for path in [
+            format!("/websub/{}{}", utils::hash(subscribe_url), forged),
+            format!("/websub/{}/guess{}", utils::hash(subscribe_url), forged),
+        ] {
+            let req = Request::get(path).body(Body::empty()).unwrap();
+            assert_eq!(call(req).await.unwrap().status(), StatusCode::NOT_FOUND);
+        }
+
+        // hub 的意图验证
+        let req = Request::get(format!(
+            "{}?hub.mode=subscribe&hub.topic=http%3A%2F%2Fexample.com%2Fself.xml&hub.challenge=abc&hub.lease_seconds=3600",
+            callback
+        ))
+        .body(Body::empty())
+        .unwrap();
+        let resp = call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"abc");
+
+        let feed = db
+            .feed_by_hash(&utils::hash(subscribe_url))
+            .unwrap()
+            .unwrap();
+        assert!(is_pushed_by_hub(&feed, now_secs()));
+        assert!(!is_pushed_by_hub(&feed, now_secs() + 3601));
+
+        // hub 给的订阅时长太长的话只按上限算
+        let req = Request::get(format!(
+            "{}?hub.mode=subscribe&hub.topic=http%3A%2F%2Fexample.com%2Fself.xml&hub.challenge=abc&hub.lease_seconds={}",
+            callback,
+            u64::MAX
+        ))
+        .body(Body::empty())
+        .unwrap();
+        assert_eq!(call(req).await.unwrap().status(), StatusCode::OK);
+        let feed = db
+            .feed_by_hash(&utils::hash(subscribe_url))
+            .unwrap()
+            .unwrap();
+        assert!(!is_pushed_by_hub(&feed, now_secs() + MAX_LEASE_SECONDS + 1));
+
+        // 发起订阅太久之后的验证请求不接受
+        db.update_feed_websub(
+            subscribe_url,
+            Some(WebSubLease {
+                requested_at: now_secs() - PENDING_TIMEOUT_SECS - 1,
+                ..feed.websub.to_owned().unwrap()
+            }),
+        )
+        .unwrap();
+        let req = Request::get(format!(
+            "{}?hub.mode=subscribe&hub.topic=http%3A%2F%2Fexample.com%2Fself.xml&hub.challenge=abc",
+            callback
+        ))
+        .body(Body::empty())
+        .unwrap();
+        assert_eq!(call(req).await.unwrap().status(), StatusCode::NOT_FOUND);
+
+        // 没有发起过退订
+        let req = Request::get(format!(
+            "{}?hub.mode=unsubscribe&hub.topic=http%3A%2F%2Fexample.com%2Fself.xml&hub.challenge=abc",
+            callback
+        ))
+        .body(Body::empty())
+        .unwrap();
+        assert_eq!(call(req).await.unwrap().status(), StatusCode::NOT_FOUND);
+
+        // 推送内容
+        let content: &[u8] = include_bytes!("../test/data/atom_1.0.xml");
+        let req = Request::post(&callback)
+            .header(header::CONTENT_TYPE, "application/atom+xml")
+            .header(
+                "X-Hub-Signature",
+                format!("sha256={}", sign::<Hmac<Sha256>>(&lease.secret, content)),
+            )
+            .body(Body::from(content))
+            .unwrap();
+        assert_eq!(call(req).await.unwrap().status(), StatusCode::ACCEPTED);
+        // 两篇文章都进了推送队列
+        assert_eq!(2, wait_outbox(&db, 2).await);
+        let outbox = db.outbox_list().unwrap();
+        assert!(outbox
+            .iter()
+            .all(|(_, m)| m.channel_id == "chan" && m.subscribe_url == subscribe_url));
+
+        // 同样的内容再推送一次, 不会重复推送
+        let req = Request::post(&callback)
+            .header(header::CONTENT_TYPE, "application/atom+xml")
+            .header(
+                "X-Hub-Signature",
+                format!("sha256={}", sign::<Hmac<Sha256>>(&lease.secret, content)),
+            )
+            .body(Body::from(content))
+            .unwrap();
+        assert_eq!(call(req).await.unwrap().status(), StatusCode::ACCEPTED);
+        assert_eq!(2, wait_outbox(&db, 3).await);
+
+        // 不认识的订阅源, 或者令牌不对
+        let wrong_token = format!("/websub/{}/guess", utils::hash(subscribe_url));
+        for path in ["/websub/unknown/token", &wrong_token] {
+            let req = Request::post(path).body(Body::from(content)).unwrap();
+            assert_eq!(call(req).await.unwrap().status(), StatusCode::GONE);
+        }
+
+        // 删掉之后的退订验证
+        db.update_feed_websub(subscribe_url, None).unwrap();
+        let req = Request::get(format!(
+            "{}?hub.mode=unsubscribe&hub.topic=http%3A%2F%2Fexample.com%2Fself.xml&hub.challenge=xyz",
+            callback
+        ))
+        .body(Body::empty())
+        .unwrap();
+        assert_eq!(call(req).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_push() {
+        let db = Arc::new(Database::temporary());
+        let conf = runtime_conf();
+
+        let subscribe_url = "http://example.com/feed.xml";
+        let feed = SubscribeFeed {
+            subscribe_url: subscribe_url.to_owned(),
+            websub: Some(WebSubLease {
+                secret: "secret".to_owned(),
+                token: "token".to_owned(),
+                expires_at: now_secs() + 3600,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        db.channel_subscribed("chan", feed.to_owned()).unwrap();
+        db.mark_posts_seen(subscribe_url, [utils::hash("old")].iter(), 0)
+            .unwrap();
+
+        // hub 同时推送了好几次, 轮询也同时拉到了同样的内容
+        let content: &[u8] = include_bytes!("../test/data/atom_1.0.xml");
+        let callback = format!("/websub/{}/token", utils::hash(subscribe_url));
+        let pushes = (0..4).map(|_| {
+            let req = Request::post(&callback)
+                .header(header::CONTENT_TYPE, "application/atom+xml")
+                .header(
+                    "X-Hub-Signature",
+                    format!("sha256={}", sign::<Hmac<Sha256>>("secret", content)),
+                )
+                .body(Body::from(content))
+                .unwrap();
+            handle(req, db.clone(), conf.clone())
+        });
+        let rss = fetch::parse_feed(Some("application/atom+xml"), content.to_vec()).unwrap();
+        let polls = (0..4).map(|_| {
+            let (db, conf, feed, rss) = (db.clone(), conf.clone(), feed.to_owned(), rss.clone());
+            tokio::spawn(async move {
+                push::push_new_posts(db, conf, &feed, &rss, Default::default()).await
+            })
+        });
+        let (pushes, polls) = tokio::join!(
+            futures_util::future::join_all(pushes),
+            futures_util::future::join_all(polls)
+        );
+        assert!(pushes
+            .into_iter()
+            .all(|r| r.unwrap().status() == StatusCode::ACCEPTED));
+        assert!(polls.into_iter().all(|r| r.unwrap().is_ok()));
+
+        // 每篇文章只推送一次
+        assert_eq!(2, wait_outbox(&db, 3).await);
+    }
+}